@@ -0,0 +1,62 @@
+// Compares handing one broadcast to every client in a room as a shared
+// Arc<str>, as the broker does, with cloning the String once per recipient,
+// as it did before. Each iteration sends the line down every client's queue
+// and drains them, so both sides pay the same channel costs.
+//
+//   cargo bench -p chat
+
+use chat::broker::{CLIENT_QUEUE, ClientMessage};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+const ROOM_SIZES: [usize; 3] = [10, 100, 1_000];
+const LINE_LEN: usize = 1_000;
+
+fn room<T>(clients: usize) -> Vec<(Sender<T>, Receiver<T>)> {
+    (0..clients).map(|_| mpsc::channel(CLIENT_QUEUE)).collect()
+}
+
+fn fan_out(c: &mut Criterion) {
+    let line = format!("[alice] {}", "x".repeat(LINE_LEN));
+    let mut group = c.benchmark_group("fan_out");
+
+    for clients in ROOM_SIZES {
+        group.throughput(Throughput::Elements(clients as u64));
+
+        let mut shared = room::<ClientMessage>(clients);
+        let text: Arc<str> = line.as_str().into();
+        group.bench_with_input(BenchmarkId::new("arc_str", clients), &text, |b, text| {
+            b.iter(|| {
+                for (sender, _) in &shared {
+                    let _ = sender.try_send(ClientMessage::Text(text.clone()));
+                }
+                for (_, receiver) in &mut shared {
+                    black_box(receiver.try_recv().ok());
+                }
+            })
+        });
+
+        let mut cloned = room::<String>(clients);
+        group.bench_with_input(
+            BenchmarkId::new("string_clone", clients),
+            &line,
+            |b, line| {
+                b.iter(|| {
+                    for (sender, _) in &cloned {
+                        let _ = sender.try_send(line.clone());
+                    }
+                    for (_, receiver) in &mut cloned {
+                        black_box(receiver.try_recv().ok());
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
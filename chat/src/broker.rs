@@ -0,0 +1,1006 @@
+use crate::log;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+
+// Broadcasts kept for bot clients to re-fetch with ack_from.
+const HISTORY_LEN: usize = 1024;
+// Messages queued for one client before it's considered to have stopped
+// reading and is disconnected.
+pub const CLIENT_QUEUE: usize = 1024;
+// Where every client starts. Without the rooms extension it's the only one.
+const DEFAULT_ROOM: &str = "main";
+
+// Operator-facing strings. `{name}` is replaced with the client's name and
+// `{members}` with the comma-separated member list (or `empty_room` when
+// nobody else is present).
+#[derive(Debug, Clone)]
+pub struct Templates {
+    pub invite: String,
+    pub members: String,
+    pub empty_room: String,
+    pub joined: String,
+    pub left: String,
+    pub name_taken: String,
+    pub room_full: String,
+    pub shutdown: String,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            invite: "Welcome to budgetchat! What shall I call you?".to_string(),
+            members: "* The room contains: {members} *".to_string(),
+            empty_room: "...just you it seems...".to_string(),
+            joined: "* {name} has entered the room".to_string(),
+            left: "* {name} has left the room".to_string(),
+            name_taken: "* The name {name} is already taken".to_string(),
+            room_full: "* The room is full, try again later".to_string(),
+            shutdown: "* server shutting down".to_string(),
+        }
+    }
+}
+
+impl Templates {
+    // The template a key names, as in the `--<key>` flags and template files.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut String> {
+        match key {
+            "invite" => Some(&mut self.invite),
+            "members" => Some(&mut self.members),
+            "empty-room" => Some(&mut self.empty_room),
+            "joined" => Some(&mut self.joined),
+            "left" => Some(&mut self.left),
+            "name-taken" => Some(&mut self.name_taken),
+            "room-full" => Some(&mut self.room_full),
+            "shutdown" => Some(&mut self.shutdown),
+            _ => None,
+        }
+    }
+
+    // Overrides templates from `key = value` lines, e.g.
+    //
+    //   invite = Hello! Who are you?
+    //   joined = * {name} is here
+    //
+    // Blank lines and lines starting with `#` are skipped, and anything not
+    // mentioned keeps its current value.
+    pub fn load(&mut self, text: &str) -> Result<(), String> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected key = value", number + 1))?;
+            let field = self
+                .get_mut(key.trim())
+                .ok_or_else(|| format!("Line {}: unknown template '{}'", number + 1, key.trim()))?;
+            *field = value.trim().to_string();
+        }
+
+        Ok(())
+    }
+}
+
+pub fn render(template: &str, name: &str, members: &str) -> String {
+    template
+        .replace("{name}", name)
+        .replace("{members}", members)
+}
+
+// Names and rooms are ASCII letters and digits, which is what the budgetchat
+// checker expects, unless `unicode` lets in any letters and digits.
+pub fn is_alphanumeric(text: &str, unicode: bool) -> bool {
+    if unicode {
+        text.chars().all(char::is_alphanumeric)
+    } else {
+        text.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+}
+
+pub enum ClientMessage {
+    Welcome { id: usize, members: String },
+    // The client can't join, e.g. because its name is in use; it's sent the
+    // reason and disconnected.
+    Rejected(String),
+    // Shared between every client a broadcast goes to, rather than copied.
+    Text(Arc<str>),
+}
+
+#[derive(Debug)]
+pub struct ChatMessage {
+    pub client_id: usize,
+    pub content: String,
+}
+
+// Lines a client can send with the bot extension enabled. `bot` switches the connection to
+// JSON output where every broadcast carries its id; `ack_from` asks for
+// everything broadcast after `id` again, e.g. after reconnecting.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BotCommand {
+    Bot,
+    AckFrom { id: u64 },
+}
+
+pub enum Event {
+    Join {
+        name: String,
+        sender: Sender<ClientMessage>,
+    },
+    Message(ChatMessage),
+    Bot(BotCommand, usize),
+    // `/join <room>`, with the rooms extension enabled.
+    JoinRoom {
+        id: usize,
+        room: String,
+    },
+    // `/msg <name> <text>`, with the msg extension enabled.
+    Private {
+        id: usize,
+        to: String,
+        text: String,
+    },
+    // `/nick <name>`, with the nick extension enabled.
+    Rename {
+        id: usize,
+        name: String,
+    },
+    Leave {
+        id: usize,
+    },
+    // From the admin listener.
+    Who(oneshot::Sender<Vec<Member>>),
+    Kick {
+        name: String,
+        reply: oneshot::Sender<bool>,
+    },
+    // Answered once every client has been told and dropped.
+    Shutdown(oneshot::Sender<()>),
+}
+
+// A connected client, as the admin listener reports it.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub id: usize,
+    pub name: String,
+    pub room: String,
+    pub joined_at: Instant,
+}
+
+#[derive(Debug)]
+struct Broadcast {
+    id: u64,
+    origin: usize,
+    room: String,
+    text: Arc<str>,
+}
+
+struct Client {
+    name: String,
+    room: String,
+    sender: Sender<ClientMessage>,
+    bot: bool,
+    joined_at: Instant,
+}
+
+impl Client {
+    // Sends a line to this client alone. Returns false if its queue is full.
+    fn tell(&self, text: Arc<str>) -> bool {
+        !matches!(
+            self.sender.try_send(ClientMessage::Text(text)),
+            Err(TrySendError::Full(_))
+        )
+    }
+
+    // Returns false if the client's queue is full.
+    fn deliver(&self, broadcast: &Broadcast) -> bool {
+        let text = if self.bot {
+            let json = serde_json::json!({ "id": broadcast.id, "text": &*broadcast.text });
+            json.to_string().into()
+        } else {
+            broadcast.text.clone()
+        };
+
+        self.tell(text)
+    }
+}
+
+// Where a client id stands. A joining client has no id until its Join is
+// handled, and ids are never reused, so every other event names a client
+// that's in the room, one that has left it, or one that never existed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifecycle {
+    Active,
+    Gone,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BrokerConfig {
+    pub templates: Templates,
+    // Keep broadcasts for bot clients to re-fetch.
+    pub bot_mode: bool,
+    // How many recent messages a joiner is shown; 0 shows none.
+    pub replay_len: usize,
+    // Joiners beyond this many are turned away with `room_full`.
+    pub max_clients: Option<usize>,
+    // The longest name `/nick` accepts, in characters.
+    pub max_name_len: Option<usize>,
+    // Accept Unicode letters and digits in names and rooms, not just ASCII.
+    pub unicode_names: bool,
+}
+
+// Everything the room knows, driven one event at a time. A client whose
+// queue fills up has evidently stopped reading and is dropped as if it had
+// left; its writer stops once its queue is written or a write times out.
+// Everyone else is told it left, which may in turn find more clients that
+// can't keep up. Events for clients that aren't Active are ignored, since
+// a client's reader and writer can each report it leaving, and a reader can
+// still be sending after the client was evicted.
+pub struct Broker {
+    config: BrokerConfig,
+    clients: HashMap<usize, Client>,
+    next_id: usize,
+    history: VecDeque<Broadcast>,
+    next_broadcast_id: u64,
+    // The last `replay_len` messages, by room.
+    recent: VecDeque<(String, String)>,
+    // Set once the server is shutting down, after which joiners are turned
+    // away.
+    closed: bool,
+}
+
+impl Broker {
+    pub fn new(config: BrokerConfig) -> Self {
+        Broker {
+            config,
+            clients: HashMap::new(),
+            next_id: 0,
+            history: VecDeque::new(),
+            next_broadcast_id: 0,
+            recent: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    pub fn lifecycle(&self, id: usize) -> Lifecycle {
+        if self.clients.contains_key(&id) {
+            Lifecycle::Active
+        } else if id < self.next_id {
+            Lifecycle::Gone
+        } else {
+            Lifecycle::Unknown
+        }
+    }
+
+    pub fn handle(&mut self, event: Event) {
+        match event {
+            Event::Join { name, sender } => self.join(name, sender),
+            Event::Message(message) => self.message(message.client_id, message.content),
+            Event::Bot(command, id) => self.bot(command, id),
+            Event::JoinRoom { id, room } => self.join_room(id, room),
+            Event::Private { id, to, text } => self.private(id, &to, &text),
+            Event::Rename { id, name } => self.rename(id, name),
+            Event::Leave { id } => self.leave(id),
+            Event::Who(reply) => {
+                let _ = reply.send(self.who());
+            }
+            Event::Kick { name, reply } => {
+                let _ = reply.send(self.kick(&name));
+            }
+            Event::Shutdown(done) => {
+                self.shutdown();
+                let _ = done.send(());
+            }
+        }
+    }
+
+    // Listing the members, registering the client and announcing it happen
+    // in this one step, so every broadcast lands either before the Welcome
+    // on the client's channel or after it, and the announcement goes out
+    // before the client's reader can send anything.
+    pub fn join(&mut self, name: String, sender: Sender<ClientMessage>) {
+        let templates = &self.config.templates;
+        let rejection = if self.closed {
+            Some(&templates.shutdown)
+        } else if self.clients.values().any(|c| c.name == name) {
+            Some(&templates.name_taken)
+        } else if (self.config.max_clients).is_some_and(|max| self.clients.len() >= max) {
+            Some(&templates.room_full)
+        } else {
+            None
+        };
+        if let Some(template) = rejection {
+            let reason = render(template, &name, "");
+            log::event("reject", json!({ "name": name, "reason": reason }));
+            let _ = sender.try_send(ClientMessage::Rejected(reason));
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        log::event("join", json!({ "id": id, "name": name }));
+
+        let members = self.members(DEFAULT_ROOM, id);
+        let _ = sender.try_send(ClientMessage::Welcome { id, members });
+        let client = Client {
+            name: name.clone(),
+            room: DEFAULT_ROOM.to_string(),
+            sender,
+            bot: false,
+            joined_at: Instant::now(),
+        };
+
+        // Marked so they can't be mistaken for new messages, and written
+        // right after the member list.
+        let kept_up = self
+            .recent
+            .iter()
+            .filter(|(room, _)| room == DEFAULT_ROOM)
+            .all(|(_, text)| client.tell(format!("* earlier: {}", text).into()));
+        self.clients.insert(id, client);
+
+        let announcement = render(&self.config.templates.joined, &name, "");
+        let mut leaving = self.broadcast(id, DEFAULT_ROOM, announcement);
+        if !kept_up {
+            leaving.push(id);
+        }
+        self.depart(leaving);
+    }
+
+    pub fn message(&mut self, client_id: usize, content: String) {
+        let Some(client) = self.clients.get(&client_id) else {
+            return;
+        };
+        let room = client.room.clone();
+        let formatted_msg = format!("[{}] {}", client.name, content);
+        log::event(
+            "message",
+            json!({
+                "id": client_id,
+                "name": client.name,
+                "room": room,
+                "len": content.chars().count(),
+            }),
+        );
+
+        if self.config.replay_len > 0 {
+            if self.recent.len() == self.config.replay_len {
+                self.recent.pop_front();
+            }
+            self.recent.push_back((room.clone(), formatted_msg.clone()));
+        }
+
+        let leaving = self.broadcast(client_id, &room, formatted_msg);
+        self.depart(leaving);
+    }
+
+    pub fn bot(&mut self, command: BotCommand, id: usize) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+
+        match command {
+            BotCommand::Bot => client.bot = true,
+            BotCommand::AckFrom { id: from } => {
+                let kept_up = self
+                    .history
+                    .iter()
+                    .filter(|b| b.id > from && b.origin != id && b.room == client.room)
+                    .all(|b| client.deliver(b));
+                if !kept_up {
+                    self.depart(vec![id]);
+                }
+            }
+        }
+    }
+
+    // The old room hears where the client went, the new one hears it
+    // arrive, and the client gets the new room's member list.
+    pub fn join_room(&mut self, id: usize, room: String) {
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+
+        if room.is_empty() || !is_alphanumeric(&room, self.config.unicode_names) {
+            self.tell(id, "* Room names must be alphanumeric".to_string());
+            return;
+        }
+        if room == client.room {
+            self.tell(id, format!("* You are already in {}", room));
+            return;
+        }
+
+        let name = client.name.clone();
+        let old_room = std::mem::replace(&mut client.room, room.clone());
+        log::event(
+            "move",
+            json!({ "id": id, "name": name, "from": old_room, "to": room }),
+        );
+
+        let moved = format!("* {} has moved to {}", name, room);
+        let mut leaving = self.broadcast(id, &old_room, moved);
+        let joined = render(&self.config.templates.joined, &name, "");
+        leaving.extend(self.broadcast(id, &room, joined));
+        self.depart(leaving);
+
+        let members = self.members(&room, id);
+        let members = render(&self.config.templates.members, &name, &members);
+        self.tell(id, members);
+    }
+
+    // Held to the same rules as a name given on joining. The client's room
+    // hears about it, and the client is told it worked.
+    pub fn rename(&mut self, id: usize, name: String) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+
+        let too_long = (self.config.max_name_len).is_some_and(|max| name.chars().count() > max);
+        if name.is_empty() || !is_alphanumeric(&name, self.config.unicode_names) || too_long {
+            self.tell(id, "* Names must be alphanumeric".to_string());
+            return;
+        }
+        if name == client.name {
+            self.tell(id, format!("* You are already known as {}", name));
+            return;
+        }
+        if self.clients.values().any(|c| c.name == name) {
+            let taken = render(&self.config.templates.name_taken, &name, "");
+            self.tell(id, taken);
+            return;
+        }
+
+        let Some(client) = self.clients.get_mut(&id) else {
+            return;
+        };
+        let old_name = std::mem::replace(&mut client.name, name.clone());
+        let room = client.room.clone();
+        log::event("rename", json!({ "id": id, "from": old_name, "to": name }));
+
+        let announcement = format!("* {} is now known as {}", old_name, name);
+        let leaving = self.broadcast(id, &room, announcement);
+        self.depart(leaving);
+        self.tell(id, format!("* You are now known as {}", name));
+    }
+
+    // Goes to the named client wherever it is, and never into the history.
+    pub fn private(&mut self, id: usize, to: &str, text: &str) {
+        let Some(client) = self.clients.get(&id) else {
+            return;
+        };
+
+        if text.is_empty() {
+            self.tell(id, "* Usage: /msg <name> <text>".to_string());
+            return;
+        }
+
+        let line = format!("[{} -> {}] {}", client.name, to, text);
+        let target = self.clients.iter().find(|(_, c)| c.name == to);
+        log::event(
+            "private",
+            json!({
+                "id": id,
+                "name": client.name,
+                "to": to,
+                "len": text.chars().count(),
+                "delivered": target.is_some(),
+            }),
+        );
+        match target {
+            Some((&target_id, _)) => self.tell(target_id, line),
+            None => self.tell(id, format!("* There is nobody called {}", to)),
+        }
+    }
+
+    pub fn leave(&mut self, id: usize) {
+        match self.lifecycle(id) {
+            Lifecycle::Active => self.depart(vec![id]),
+            Lifecycle::Gone => {}
+            Lifecycle::Unknown => log::error("unknown_leave", json!({ "id": id })),
+        }
+    }
+
+    // Everyone connected, in the order they joined.
+    pub fn who(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .clients
+            .iter()
+            .map(|(&id, client)| Member {
+                id,
+                name: client.name.clone(),
+                room: client.room.clone(),
+                joined_at: client.joined_at,
+            })
+            .collect();
+        members.sort_by_key(|member| member.id);
+        members
+    }
+
+    // Disconnects the named client as if it had left, so its room hears the
+    // usual announcement. Returns false if nobody has that name.
+    pub fn kick(&mut self, name: &str) -> bool {
+        let Some((&id, _)) = self.clients.iter().find(|(_, c)| c.name == name) else {
+            return false;
+        };
+
+        log::event("kick", json!({ "id": id, "name": name }));
+        self.tell(
+            id,
+            "* You have been disconnected by an operator".to_string(),
+        );
+        self.depart(vec![id]);
+        true
+    }
+
+    // Tells everyone the server is going away and drops them all at once,
+    // without the usual leave announcements. Each client's writer stops
+    // once it has written what's already queued.
+    pub fn shutdown(&mut self) {
+        self.closed = true;
+        log::event("shutdown", json!({ "connected": self.clients.len() }));
+        let notice: Arc<str> = render(&self.config.templates.shutdown, "", "").into();
+        for client in self.clients.values() {
+            client.tell(notice.clone());
+        }
+        self.clients.clear();
+    }
+
+    // Everyone in `room` but `except`, as the members template expects them.
+    fn members(&self, room: &str, except: usize) -> String {
+        let names: Vec<&str> = self
+            .clients
+            .iter()
+            .filter(|&(&client_id, client)| client_id != except && client.room == room)
+            .map(|(_, client)| client.name.as_str())
+            .collect();
+
+        if names.is_empty() {
+            self.config.templates.empty_room.clone()
+        } else {
+            names.join(", ")
+        }
+    }
+
+    fn tell(&mut self, id: usize, text: String) {
+        let text = text.into();
+        if let Some(client) = self.clients.get(&id)
+            && !client.tell(text)
+        {
+            self.depart(vec![id]);
+        }
+    }
+
+    // Sends to everyone in `room` but the originating client, recording the
+    // broadcast for bot clients when bot mode is on. Returns the clients that
+    // couldn't keep up.
+    fn broadcast(&mut self, origin: usize, room: &str, text: String) -> Vec<usize> {
+        let broadcast = Broadcast {
+            id: self.next_broadcast_id,
+            origin,
+            room: room.to_string(),
+            text: text.into(),
+        };
+        self.next_broadcast_id += 1;
+
+        let laggards = self
+            .clients
+            .iter()
+            .filter(|&(&client_id, client)| {
+                client_id != origin && client.room == room && !client.deliver(&broadcast)
+            })
+            .map(|(&client_id, _)| client_id)
+            .collect();
+
+        if self.config.bot_mode {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(broadcast);
+        }
+
+        laggards
+    }
+
+    fn depart(&mut self, mut leaving: Vec<usize>) {
+        while let Some(id) = leaving.pop() {
+            let Some(client) = self.clients.remove(&id) else {
+                continue;
+            };
+            if client.sender.capacity() == 0 {
+                log::error(
+                    "evict",
+                    json!({ "id": id, "name": client.name, "reason": "not reading" }),
+                );
+            }
+            log::event(
+                "leave",
+                json!({ "id": id, "name": client.name, "room": client.room }),
+            );
+
+            let announcement = render(&self.config.templates.left, &client.name, "");
+            leaving.extend(self.broadcast(id, &client.room, announcement));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{self, Receiver};
+
+    fn broker() -> Broker {
+        Broker::new(BrokerConfig {
+            replay_len: 2,
+            ..BrokerConfig::default()
+        })
+    }
+
+    fn join(broker: &mut Broker, name: &str, queue: usize) -> (usize, Receiver<ClientMessage>) {
+        let (sender, mut receiver) = mpsc::channel(queue);
+        broker.join(name.to_string(), sender);
+
+        match receiver.try_recv() {
+            Ok(ClientMessage::Welcome { id, .. }) => (id, receiver),
+            _ => panic!("Expected a welcome for {}", name),
+        }
+    }
+
+    fn shared_text(receiver: &mut Receiver<ClientMessage>) -> Arc<str> {
+        match receiver.try_recv() {
+            Ok(ClientMessage::Text(text)) => text,
+            _ => panic!("Expected a line of text"),
+        }
+    }
+
+    fn text(receiver: &mut Receiver<ClientMessage>) -> String {
+        match receiver.try_recv() {
+            Ok(ClientMessage::Text(text)) => text.to_string(),
+            _ => panic!("Expected a line of text"),
+        }
+    }
+
+    #[test]
+    fn templates_load_from_key_value_lines() {
+        let mut templates = Templates::default();
+        templates
+            .load("# A friendlier room\n\ninvite = Hi! Name?\nempty-room = nobody = here\n")
+            .expect("Templates should load");
+
+        assert_eq!(templates.invite, "Hi! Name?");
+        assert_eq!(templates.empty_room, "nobody = here");
+        assert_eq!(templates.joined, Templates::default().joined);
+
+        assert!(templates.load("greeting = hello").is_err());
+        assert!(templates.load("invite").is_err());
+    }
+
+    #[test]
+    fn names_are_ascii_unless_unicode_is_allowed() {
+        for unicode in [false, true] {
+            assert!(is_alphanumeric("Alice42", unicode));
+            assert!(!is_alphanumeric("alice smith", unicode));
+            assert!(!is_alphanumeric("alice!", unicode));
+        }
+
+        for name in ["café", "Zoë", "名前", "٣"] {
+            assert!(!is_alphanumeric(name, false), "{} should be rejected", name);
+            assert!(is_alphanumeric(name, true), "{} should be accepted", name);
+        }
+    }
+
+    #[test]
+    fn renames_follow_the_same_name_rules() {
+        let mut ascii = broker();
+        let (alice, mut alice_rx) = join(&mut ascii, "alice", CLIENT_QUEUE);
+        ascii.rename(alice, "zoë".to_string());
+        assert_eq!(text(&mut alice_rx), "* Names must be alphanumeric");
+
+        let mut unicode = Broker::new(BrokerConfig {
+            unicode_names: true,
+            ..BrokerConfig::default()
+        });
+        let (alice, mut alice_rx) = join(&mut unicode, "alice", CLIENT_QUEUE);
+        unicode.rename(alice, "zoë".to_string());
+        assert_eq!(text(&mut alice_rx), "* You are now known as zoë");
+    }
+
+    #[test]
+    fn a_joiner_sees_everyone_else_and_is_announced() {
+        let mut broker = broker();
+        let (_, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (sender, mut bob_rx) = mpsc::channel(CLIENT_QUEUE);
+        broker.join("bob".to_string(), sender);
+
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ClientMessage::Welcome { members, .. }) if members == "alice"
+        ));
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_message_reaches_everyone_but_its_sender() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (_, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        let (_, mut carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+        text(&mut alice_rx);
+        text(&mut alice_rx);
+        text(&mut bob_rx);
+
+        broker.message(alice, "hi".to_string());
+
+        assert_eq!(text(&mut bob_rx), "[alice] hi");
+        assert_eq!(text(&mut carol_rx), "[alice] hi");
+        assert!(alice_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_taken_name_is_rejected() {
+        let mut broker = broker();
+        let (_, _alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (sender, mut second_rx) = mpsc::channel(CLIENT_QUEUE);
+        broker.join("alice".to_string(), sender);
+
+        assert!(matches!(
+            second_rx.try_recv(),
+            Ok(ClientMessage::Rejected(reason)) if reason == "* The name alice is already taken"
+        ));
+    }
+
+    #[test]
+    fn a_stalled_reader_is_evicted() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (_, mut stalled_rx) = join(&mut broker, "bob", 1);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+
+        broker.message(alice, "one".to_string());
+        broker.message(alice, "two".to_string());
+
+        assert_eq!(text(&mut alice_rx), "* bob has left the room");
+        assert_eq!(text(&mut stalled_rx), "[alice] one");
+        assert!(stalled_rx.try_recv().is_err(), "bob should be disconnected");
+        assert!(stalled_rx.is_closed());
+    }
+
+    #[test]
+    fn a_second_leave_is_ignored() {
+        let mut broker = broker();
+        let (_, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        drop(bob_rx);
+
+        broker.leave(bob);
+        broker.leave(bob);
+        let (_, _carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert_eq!(text(&mut alice_rx), "* bob has left the room");
+        assert_eq!(text(&mut alice_rx), "* carol has entered the room");
+    }
+
+    #[test]
+    fn events_for_absent_clients_are_ignored() {
+        let mut broker = broker();
+        let (_, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, _bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        broker.leave(bob);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert_eq!(text(&mut alice_rx), "* bob has left the room");
+
+        let never_joined = 100;
+        assert_eq!(broker.lifecycle(bob), Lifecycle::Gone);
+        assert_eq!(broker.lifecycle(never_joined), Lifecycle::Unknown);
+
+        for id in [bob, never_joined] {
+            let events = [
+                Event::Message(ChatMessage {
+                    client_id: id,
+                    content: "hi".to_string(),
+                }),
+                Event::Bot(BotCommand::Bot, id),
+                Event::Bot(BotCommand::AckFrom { id: 0 }, id),
+                Event::JoinRoom {
+                    id,
+                    room: "garden".to_string(),
+                },
+                Event::Private {
+                    id,
+                    to: "alice".to_string(),
+                    text: "psst".to_string(),
+                },
+                Event::Leave { id },
+            ];
+            for event in events {
+                broker.handle(event);
+            }
+        }
+
+        assert!(alice_rx.try_recv().is_err());
+        let (_, mut carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+        assert!(carol_rx.try_recv().is_err());
+        assert_eq!(text(&mut alice_rx), "* carol has entered the room");
+    }
+
+    #[test]
+    fn who_lists_everyone_in_join_order() {
+        let mut broker = broker();
+        let (_, _alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, _bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        let (_, _carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+        broker.join_room(bob, "garden".to_string());
+
+        let members: Vec<(String, String)> = broker
+            .who()
+            .into_iter()
+            .map(|member| (member.name, member.room))
+            .collect();
+
+        assert_eq!(
+            members,
+            [
+                ("alice".to_string(), "main".to_string()),
+                ("bob".to_string(), "garden".to_string()),
+                ("carol".to_string(), "main".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_kicked_client_is_announced_as_leaving() {
+        let mut broker = broker();
+        let (_, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+
+        assert!(broker.kick("bob"));
+        assert!(!broker.kick("bob"));
+
+        assert_eq!(text(&mut alice_rx), "* bob has left the room");
+        assert_eq!(
+            text(&mut bob_rx),
+            "* You have been disconnected by an operator"
+        );
+        assert!(bob_rx.try_recv().is_err());
+        assert!(bob_rx.is_closed());
+        assert_eq!(broker.lifecycle(bob), Lifecycle::Gone);
+    }
+
+    #[test]
+    fn shutdown_tells_everyone_and_turns_joiners_away() {
+        let mut broker = broker();
+        let (_, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+
+        broker.shutdown();
+        broker.leave(bob);
+
+        for receiver in [&mut alice_rx, &mut bob_rx] {
+            assert_eq!(text(receiver), "* server shutting down");
+            assert!(receiver.try_recv().is_err());
+            assert!(receiver.is_closed());
+        }
+
+        let (sender, mut carol_rx) = mpsc::channel(CLIENT_QUEUE);
+        broker.join("carol".to_string(), sender);
+        assert!(matches!(
+            carol_rx.try_recv(),
+            Ok(ClientMessage::Rejected(reason)) if reason == "* server shutting down"
+        ));
+    }
+
+    #[test]
+    fn a_rename_is_announced_and_frees_the_old_name() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (_, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+
+        broker.rename(alice, "bob".to_string());
+        broker.rename(alice, "al ice".to_string());
+        broker.rename(alice, "ally".to_string());
+        let (_, _second_alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+
+        assert_eq!(text(&mut alice_rx), "* The name bob is already taken");
+        assert_eq!(text(&mut alice_rx), "* Names must be alphanumeric");
+        assert_eq!(text(&mut alice_rx), "* You are now known as ally");
+        assert_eq!(text(&mut alice_rx), "* alice has entered the room");
+        assert_eq!(text(&mut bob_rx), "* alice is now known as ally");
+        assert_eq!(text(&mut bob_rx), "* alice has entered the room");
+
+        broker.message(alice, "hi".to_string());
+        assert_eq!(text(&mut bob_rx), "[ally] hi");
+    }
+
+    #[test]
+    fn broadcasts_stay_in_their_room() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (bob, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        let (_, mut carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert_eq!(text(&mut alice_rx), "* carol has entered the room");
+        assert_eq!(text(&mut bob_rx), "* carol has entered the room");
+
+        broker.join_room(alice, "garden".to_string());
+        broker.join_room(bob, "garden".to_string());
+        broker.message(alice, "hi".to_string());
+
+        assert_eq!(
+            text(&mut alice_rx),
+            "* The room contains: ...just you it seems... *"
+        );
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert_eq!(text(&mut bob_rx), "* alice has moved to garden");
+        assert_eq!(text(&mut bob_rx), "* The room contains: alice *");
+        assert_eq!(text(&mut bob_rx), "[alice] hi");
+        assert_eq!(text(&mut carol_rx), "* alice has moved to garden");
+        assert_eq!(text(&mut carol_rx), "* bob has moved to garden");
+        assert!(carol_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_private_message_reaches_only_its_target() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let (_, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+        let (_, mut carol_rx) = join(&mut broker, "carol", CLIENT_QUEUE);
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+        assert_eq!(text(&mut alice_rx), "* carol has entered the room");
+        assert_eq!(text(&mut bob_rx), "* carol has entered the room");
+
+        broker.private(alice, "bob", "psst");
+        broker.private(alice, "dave", "psst");
+
+        assert_eq!(text(&mut bob_rx), "[alice -> bob] psst");
+        assert_eq!(text(&mut alice_rx), "* There is nobody called dave");
+        assert!(carol_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn joiners_are_shown_recent_messages() {
+        let mut broker = broker();
+        let (alice, mut alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        for content in ["one", "two", "three"] {
+            broker.message(alice, content.to_string());
+        }
+
+        let (_, mut bob_rx) = join(&mut broker, "bob", CLIENT_QUEUE);
+
+        assert_eq!(text(&mut bob_rx), "* earlier: [alice] two");
+        assert_eq!(text(&mut bob_rx), "* earlier: [alice] three");
+        assert_eq!(text(&mut alice_rx), "* bob has entered the room");
+    }
+
+    #[test]
+    fn a_broadcast_is_shared_rather_than_copied_per_client() {
+        let mut broker = broker();
+        let (alice, _alice_rx) = join(&mut broker, "alice", CLIENT_QUEUE);
+        let mut receivers: Vec<_> = (0..1000)
+            .map(|i| join(&mut broker, &format!("client{}", i), CLIENT_QUEUE).1)
+            .collect();
+        for receiver in &mut receivers {
+            while receiver.try_recv().is_ok() {}
+        }
+
+        broker.message(alice, "x".repeat(1000));
+
+        let first = shared_text(&mut receivers[0]);
+        assert_eq!(first.len(), 1000 + "[alice] ".len());
+        for receiver in &mut receivers[1..] {
+            assert!(Arc::ptr_eq(&first, &shared_text(receiver)));
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use crate::broker::is_alphanumeric;
+
+// The one channel IRC clients see; it stands for the budgetchat room.
+pub const CHANNEL: &str = "#budgetchat";
+// Used as the prefix of everything the server itself says.
+pub const SERVER: &str = "budgetchat";
+
+// The subset of IRC the chat server understands. Anything else a client
+// sends is parsed as Other and ignored.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Cap,
+    Nick(String),
+    Join(String),
+    Privmsg { target: String, text: String },
+    Ping(String),
+    Quit,
+    Other,
+}
+
+// Parses `[:prefix] COMMAND params [:trailing]`, where the trailing
+// parameter may contain spaces. Returns None for blank lines.
+pub fn parse(line: &str) -> Option<Command> {
+    let mut line = line.trim();
+    if line.starts_with(':') {
+        line = line
+            .split_once(' ')
+            .map_or("", |(_, rest)| rest)
+            .trim_start();
+    }
+
+    let (line, trailing) = match line.split_once(" :") {
+        Some((line, trailing)) => (line, Some(trailing)),
+        None => (line, None),
+    };
+    let mut words = line.split_whitespace();
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<&str> = words.collect();
+    params.extend(trailing);
+    let param = |i: usize| params.get(i).map(|p| p.to_string());
+
+    Some(match command.as_str() {
+        "CAP" => Command::Cap,
+        "NICK" => Command::Nick(param(0).unwrap_or_default()),
+        "JOIN" => Command::Join(param(0).unwrap_or_default()),
+        "PRIVMSG" => match (param(0), param(1)) {
+            (Some(target), Some(text)) => Command::Privmsg { target, text },
+            _ => Command::Other,
+        },
+        "PING" => Command::Ping(param(0).unwrap_or_default()),
+        "QUIT" => Command::Quit,
+        _ => Command::Other,
+    })
+}
+
+// A numeric reply from the server to `nick`.
+pub fn reply(code: &str, nick: &str, text: &str) -> String {
+    format!(":{} {} {} :{}", SERVER, code, nick, text)
+}
+
+pub fn joined(nick: &str) -> String {
+    format!(":{}!{}@{} JOIN {}", nick, nick, SERVER, CHANNEL)
+}
+
+pub fn notice(text: &str) -> String {
+    format!(":{} NOTICE {} :{}", SERVER, CHANNEL, text)
+}
+
+// Turns a line the broker sent into IRC. `[name] text` is a message to the
+// channel and `[from -> to] text` a private one; everything else, like the
+// join and leave announcements, comes from the server as a notice.
+pub fn translate(line: &str) -> String {
+    let message = line
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "));
+
+    if let Some((sender, text)) = message {
+        let (from, to) = sender.split_once(" -> ").unwrap_or((sender, CHANNEL));
+        // Whatever names the broker let in, so check permissively.
+        if !from.is_empty() && is_alphanumeric(from, true) {
+            return format!(":{}!{}@{} PRIVMSG {} :{}", from, from, SERVER, to, text);
+        }
+    }
+
+    notice(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed_with_their_trailing_parameter() {
+        assert_eq!(
+            parse("NICK alice"),
+            Some(Command::Nick("alice".to_string()))
+        );
+        assert_eq!(
+            parse(":alice PRIVMSG #budgetchat :hello there\r\n"),
+            Some(Command::Privmsg {
+                target: "#budgetchat".to_string(),
+                text: "hello there".to_string(),
+            })
+        );
+        assert_eq!(
+            parse("join #budgetchat"),
+            Some(Command::Join(CHANNEL.to_string()))
+        );
+        assert_eq!(
+            parse("PING :12345"),
+            Some(Command::Ping("12345".to_string()))
+        );
+        assert_eq!(parse("CAP LS 302"), Some(Command::Cap));
+        assert_eq!(parse("USER alice 0 * :Alice"), Some(Command::Other));
+        assert_eq!(parse("PRIVMSG #budgetchat"), Some(Command::Other));
+        assert_eq!(parse("   "), None);
+    }
+
+    #[test]
+    fn broker_lines_become_messages_or_notices() {
+        assert_eq!(
+            translate("[bob] hi :)"),
+            ":bob!bob@budgetchat PRIVMSG #budgetchat :hi :)"
+        );
+        assert_eq!(
+            translate("[bob -> alice] psst"),
+            ":bob!bob@budgetchat PRIVMSG alice :psst"
+        );
+        assert_eq!(
+            translate("* bob has entered the room"),
+            ":budgetchat NOTICE #budgetchat :* bob has entered the room"
+        );
+        assert_eq!(
+            translate("[not a name] hi"),
+            ":budgetchat NOTICE #budgetchat :[not a name] hi"
+        );
+    }
+}
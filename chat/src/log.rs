@@ -0,0 +1,48 @@
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Logs are one JSON object per line, so a session can be pulled back out of
+// a failed checker run by filtering on its id or peer, e.g.
+//
+//   {"event":"message","id":3,"len":12,"name":"alice","room":"main","ts_ms":1760000000123}
+//
+// Events go to stdout and errors to stderr.
+pub fn event(event: &str, fields: Value) {
+    println!("{}", line(event, fields));
+}
+
+pub fn error(event: &str, fields: Value) {
+    eprintln!("{}", line(event, fields));
+}
+
+fn line(event: &str, fields: Value) -> String {
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+
+    let mut line = Map::new();
+    line.insert("ts_ms".to_string(), ts_ms.into());
+    line.insert("event".to_string(), event.into());
+    if let Value::Object(fields) = fields {
+        line.extend(fields);
+    }
+
+    Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn lines_carry_a_timestamp_the_event_and_its_fields() {
+        let line = line("join", json!({ "id": 3, "name": "alice" }));
+        let parsed: Value = serde_json::from_str(&line).expect("Log lines should be JSON");
+
+        assert!(parsed["ts_ms"].as_u64().is_some_and(|ts| ts > 0));
+        assert_eq!(parsed["event"], "join");
+        assert_eq!(parsed["id"], 3);
+        assert_eq!(parsed["name"], "alice");
+    }
+}
@@ -1,224 +1,1107 @@
-use crossbeam_channel::{Sender, unbounded};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
+use chat::broker::{
+    BotCommand, Broker, BrokerConfig, CLIENT_QUEUE, ChatMessage, ClientMessage, Event, Templates,
+    is_alphanumeric, render,
+};
+use chat::irc::{self, Command};
+use chat::log;
+use common::protocol::{self, Capabilities};
+use common::quota::Quotas;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::Message;
 
-enum ClientMessage {
-    Welcome { id: usize, members: String },
-    Text(String),
+const LOCAL_ADDR: &str = "0.0.0.0:8080";
+// How long a write to a client may block before the client is given up on.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long shutdown waits for clients to be sent what's queued for them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+// Bytes buffered each way between a WebSocket and its chat session.
+const BRIDGE_BUFFER: usize = 64 * 1024;
+#[derive(Debug, Clone)]
+struct Config {
+    addr: String,
+    templates: Templates,
+    capabilities: Capabilities,
+    // Names and messages longer than these, in characters, get the client
+    // disconnected rather than buffered without bound. The spec asks for at
+    // least 16 and 1000.
+    max_name_len: usize,
+    max_message_len: usize,
+    // Accept Unicode letters and digits in names, not just ASCII.
+    unicode_names: bool,
+    // Joiners beyond this many are turned away with `room_full`.
+    max_clients: Option<usize>,
+    // How many recent messages a joiner is shown with the replay extension.
+    replay_len: usize,
+    // How long a client has to give its name before it's disconnected.
+    name_timeout: Duration,
+    // Where operators can connect to run /who and /kick, if anywhere.
+    admin: Option<String>,
+    // Where WebSocket clients can join the same room, if anywhere.
+    websocket: Option<String>,
+    // Where IRC clients can join the same room, if anywhere.
+    irc: Option<String>,
 }
 
-#[derive(Debug)]
-struct ChatMessage {
-    client_id: usize,
-    content: String,
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: LOCAL_ADDR.to_string(),
+            templates: Templates::default(),
+            capabilities: Capabilities::strict(protocol::CHAT),
+            max_name_len: 32,
+            max_message_len: 1000,
+            unicode_names: false,
+            max_clients: None,
+            replay_len: 20,
+            name_timeout: Duration::from_secs(30),
+            admin: None,
+            websocket: None,
+            irc: None,
+        }
+    }
 }
 
-enum Event {
-    Join {
-        name: String,
-        sender: Sender<ClientMessage>,
-    },
-    Message(ChatMessage),
-    Leave {
-        id: usize,
-    },
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        if let Ok(path) = std::env::var("CHAT_TEMPLATES") {
+            load_templates(&mut config.templates, &path)?;
+        }
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if config.capabilities.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Expected a value after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--bot-mode" => config.capabilities.enable("bot")?,
+                "--max-name-len" => config.max_name_len = parse_len(&arg, value()?)?,
+                "--max-message-len" => config.max_message_len = parse_len(&arg, value()?)?,
+                "--unicode-names" => config.unicode_names = true,
+                "--max-clients" => config.max_clients = Some(parse_len(&arg, value()?)?),
+                "--replay" => {
+                    config.replay_len = parse_len(&arg, value()?)?;
+                    config.capabilities.enable("replay")?;
+                }
+                "--name-timeout" => {
+                    let secs = parse_len(&arg, value()?)?;
+                    config.name_timeout = Duration::from_secs(secs as u64);
+                }
+                "--bind" => config.addr = value()?,
+                "--admin" => config.admin = Some(value()?),
+                "--websocket" => config.websocket = Some(value()?),
+                "--irc" => config.irc = Some(value()?),
+                "--templates" => load_templates(&mut config.templates, &value()?)?,
+                // Any other flag naming a template sets it, e.g. `--joined`.
+                other => {
+                    let Some(field) = other
+                        .strip_prefix("--")
+                        .and_then(|key| config.templates.get_mut(key))
+                    else {
+                        return Err(format!("Unknown argument '{}'", other));
+                    };
+                    *field = value()?;
+                }
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Templates come from the file named by CHAT_TEMPLATES, then from
+// `--templates` and the single-template flags in the order they're given.
+fn load_templates(templates: &mut Templates, path: &str) -> Result<(), String> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+    templates
+        .load(&text)
+        .map_err(|e| format!("Invalid templates in {}: {}", path, e))
 }
 
-struct Client {
-    name: String,
-    sender: Sender<ClientMessage>,
+fn parse_len(arg: &str, value: String) -> Result<usize, String> {
+    value.parse().map_err(|e| format!("Invalid {}: {}", arg, e))
+}
+
+// Reads one line of at most `max_len` characters, not counting its line
+// ending. Never buffers more than a line that long could take in UTF-8, so a
+// longer one is an error instead of growing without bound. Invalid UTF-8 is
+// replaced rather than ending the session, since the line is still a line.
+async fn read_line_limited<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+    max_len: usize,
+) -> std::io::Result<usize> {
+    let mut bytes = Vec::new();
+    let bytes_read = reader
+        .take(max_len as u64 * 4 + 2)
+        .read_until(b'\n', &mut bytes)
+        .await?;
+    let text = String::from_utf8_lossy(&bytes);
+
+    if text.trim_end_matches(['\r', '\n']).chars().count() > max_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Line is longer than {} characters", max_len),
+        ));
+    }
+
+    // A client that disconnected mid-line never finished saying it, so the
+    // fragment is dropped and read as the end of the stream.
+    if !bytes.ends_with(b"\n") {
+        return Ok(0);
+    }
+
+    line.push_str(&text);
+    Ok(bytes_read)
+}
+
+// Strips the line ending, `\n` or `\r\n`. Any other control character is
+// an error: a stray `\r` or escape sequence could only garble what everyone
+// else sees.
+fn strip_line_ending(line: &str) -> std::io::Result<&str> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    if line.chars().any(char::is_control) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Line contains control characters",
+        ));
+    }
+
+    Ok(line)
 }
 
-fn is_alphanumeric(text: &str) -> bool {
-    text.chars().all(|t| char::is_alphanumeric(t))
+// Charged against the outbound quota, and given up on after WRITE_TIMEOUT.
+async fn write_line<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    text: &str,
+    quotas: &Quotas,
+) -> std::io::Result<()> {
+    let line = format!("{}\n", text);
+    quotas.throttle_out_async(line.len()).await;
+
+    tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(line.as_bytes()))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "Write timed out"))?
 }
 
-fn handle_invite(
-    reader: &mut BufReader<TcpStream>,
-    writer: &mut BufWriter<TcpStream>,
+async fn handle_invite<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    quotas: &Quotas,
 ) -> Result<String, std::io::Error> {
-    let invite_message = "Welcome to budgetchat! What shall I call you?\n";
-    writer.write_all(invite_message.as_bytes())?;
-    writer.flush()?;
+    write_line(writer, &render(&config.templates.invite, "", ""), quotas).await?;
 
     let mut client_name = String::new();
-    loop {
-        client_name.clear();
-        match reader.read_line(&mut client_name) {
-            Ok(_) => {
-                let formatted_name = client_name.trim().to_string();
-                if formatted_name.is_empty() || !is_alphanumeric(&formatted_name) {
-                    return Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        "Name cannot be empty, and must be alphanumeric",
-                    ));
-                } else {
-                    return Ok(client_name.trim().to_string());
-                }
-            }
-            Err(e) => return Err(e),
-        }
+    let bytes_read = read_line_limited(reader, &mut client_name, config.max_name_len).await?;
+    quotas.throttle_in_async(bytes_read).await;
+
+    let formatted_name = strip_line_ending(&client_name)?.trim().to_string();
+    if formatted_name.is_empty() || !is_alphanumeric(&formatted_name, config.unicode_names) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Name cannot be empty, and must be alphanumeric",
+        ));
     }
+
+    Ok(formatted_name)
 }
 
-fn handle_client(stream: TcpStream, broker_tx: Sender<Event>) {
-    let write_stream = stream
-        .try_clone()
-        .expect("Couldn't clone stream for writing");
+// Serves one chat session over any byte stream, so TCP and WebSocket
+// clients get exactly the same handling.
+async fn handle_client<R, W>(
+    reader: R,
+    mut writer: W,
+    peer: String,
+    broker_tx: UnboundedSender<Event>,
+    config: Arc<Config>,
+    quotas: Arc<Quotas>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let templates = &config.templates;
+    let mut reader = BufReader::new(reader);
 
-    let mut reader = BufReader::new(stream);
-    let mut writer = BufWriter::new(write_stream);
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            log::error("reject", json!({ "peer": peer, "reason": e.to_string() }));
+            let _ = write_line(&mut writer, &format!("* {}, try again later", e), &quotas).await;
+            return;
+        }
+    };
 
-    let client_name = match handle_invite(&mut reader, &mut writer) {
+    // A client that never answers would otherwise hold its session forever.
+    let invite = handle_invite(&mut reader, &mut writer, &config, &quotas);
+    let client_name = match tokio::time::timeout(config.name_timeout, invite)
+        .await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "No name given in time")))
+    {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Couldn't set client name: {}", e);
+            log::error("name", json!({ "peer": peer, "error": e.to_string() }));
             return;
         }
     };
 
-    let (client_tx, client_rx) = unbounded::<ClientMessage>();
+    // Created before joining and handed to the broker with the Join, so
+    // there's no window where a broadcast could skip this client.
+    let (client_tx, mut client_rx) = mpsc::channel::<ClientMessage>(CLIENT_QUEUE);
 
-    broker_tx
-        .send(Event::Join {
-            name: client_name.clone(),
-            sender: client_tx,
-        })
-        .unwrap();
+    let join = Event::Join {
+        name: client_name.clone(),
+        sender: client_tx,
+    };
+    if broker_tx.send(join).is_err() {
+        log::error(
+            "broker_stopped",
+            json!({ "peer": peer, "name": client_name }),
+        );
+        return;
+    }
 
-    let client_id = match client_rx.recv().unwrap() {
-        ClientMessage::Welcome { id, members } => {
-            println!("User '{}' assigned ID {}", client_name, id);
-            let _ = writeln!(writer, "* The room contains: {} *", members);
-            let _ = writer.flush();
+    let client_id = match client_rx.recv().await {
+        Some(ClientMessage::Welcome { id, members }) => {
+            log::event(
+                "connect",
+                json!({ "id": id, "name": client_name, "peer": peer }),
+            );
+            let members = render(&templates.members, &client_name, &members);
+            let _ = write_line(&mut writer, &members, &quotas).await;
             id
         }
+        Some(ClientMessage::Rejected(reason)) => {
+            let _ = write_line(&mut writer, &reason, &quotas).await;
+            return;
+        }
         _ => {
-            eprintln!("Protocol mismatch. No welcome completed yet.");
+            log::error(
+                "broker_stopped",
+                json!({ "peer": peer, "name": client_name }),
+            );
             return;
         }
     };
 
     let broker_tx_clone = broker_tx.clone();
+    let reader_quotas = quotas.clone();
+    let bot_mode = config.capabilities.has("bot");
+    let rooms = config.capabilities.has("rooms");
+    let private_messages = config.capabilities.has("msg");
+    let renames = config.capabilities.has("nick");
+    let max_message_len = config.max_message_len;
 
-    thread::spawn(move || {
+    let reader_task = tokio::spawn(async move {
         let mut buffer = String::new();
         loop {
             buffer.clear();
-            match reader.read_line(&mut buffer) {
-                Ok(0) => break,
-                Ok(_) => {
-                    let content = buffer.trim().to_string();
-                    if !content.is_empty() {
-                        broker_tx_clone
-                            .send(Event::Message(ChatMessage { client_id, content }))
-                            .unwrap();
+            let line = read_line_limited(&mut reader, &mut buffer, max_message_len)
+                .await
+                .and_then(|bytes_read| Ok((bytes_read, strip_line_ending(&buffer)?)));
+            match line {
+                Ok((0, _)) => break,
+                Ok((bytes_read, line)) => {
+                    reader_quotas.throttle_in_async(bytes_read).await;
+                    let content = line.trim().to_string();
+                    let event = if bot_mode
+                        && let Ok(command) = serde_json::from_str::<BotCommand>(&content)
+                    {
+                        Event::Bot(command, client_id)
+                    } else if rooms && let Some(room) = content.strip_prefix("/join ") {
+                        Event::JoinRoom {
+                            id: client_id,
+                            room: room.trim().to_string(),
+                        }
+                    } else if private_messages && let Some(rest) = content.strip_prefix("/msg ") {
+                        let (to, text) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                        Event::Private {
+                            id: client_id,
+                            to: to.to_string(),
+                            text: text.trim().to_string(),
+                        }
+                    } else if renames && let Some(name) = content.strip_prefix("/nick ") {
+                        Event::Rename {
+                            id: client_id,
+                            name: name.trim().to_string(),
+                        }
+                    } else if !content.is_empty() {
+                        Event::Message(ChatMessage { client_id, content })
+                    } else {
+                        continue;
+                    };
+
+                    // Only fails once the broker has stopped, when there's
+                    // nobody left to tell.
+                    if broker_tx_clone.send(event).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    if e.kind() == ErrorKind::InvalidData {
+                        log::error("read", json!({ "id": client_id, "error": e.to_string() }));
                     }
+                    break;
                 }
-                Err(_) => break,
             }
         }
-        broker_tx_clone
-            .send(Event::Leave { id: client_id })
-            .unwrap();
+        let _ = broker_tx_clone.send(Event::Leave { id: client_id });
     });
 
-    for msg in client_rx {
-        match msg {
-            ClientMessage::Text(text) => {
-                let _ = writeln!(writer, "{}", text);
-                let _ = writer.flush();
+    // Ends when the broker drops this client, or once a write fails or times
+    // out. Either way the reader is stopped and the broker told the client
+    // left straight away, rather than when the reader notices; if the reader
+    // got there first, that Leave finds nobody to remove.
+    while let Some(msg) = client_rx.recv().await {
+        if let ClientMessage::Text(text) = msg
+            && let Err(e) = write_line(&mut writer, &text, &quotas).await
+        {
+            log::error("write", json!({ "id": client_id, "error": e.to_string() }));
+            break;
+        }
+    }
+    reader_task.abort();
+    let _ = broker_tx.send(Event::Leave { id: client_id });
+    let _ = writer.shutdown().await;
+}
+
+// Bridges a WebSocket client onto an in-memory stream served like any other
+// client: each text frame becomes a line, and each line a text frame.
+async fn handle_websocket(
+    stream: TcpStream,
+    peer: String,
+    broker_tx: UnboundedSender<Event>,
+    config: Arc<Config>,
+    quotas: Arc<Quotas>,
+) {
+    let websocket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(websocket) => websocket,
+        Err(e) => {
+            log::error(
+                "websocket_handshake",
+                json!({ "peer": peer, "error": e.to_string() }),
+            );
+            return;
+        }
+    };
+    let (mut frames_out, mut frames_in) = websocket.split();
+
+    let (session, bridge) = tokio::io::duplex(BRIDGE_BUFFER);
+    let (session_reader, session_writer) = tokio::io::split(session);
+    let (bridge_reader, mut bridge_writer) = tokio::io::split(bridge);
+    let session = handle_client(
+        session_reader,
+        session_writer,
+        peer,
+        broker_tx,
+        config,
+        quotas,
+    );
+    let session = tokio::spawn(session);
+
+    let inbound = tokio::spawn(async move {
+        while let Some(Ok(frame)) = frames_in.next().await {
+            let line = match frame {
+                Message::Text(text) => format!("{}\n", text.as_str()),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if bridge_writer.write_all(line.as_bytes()).await.is_err() {
+                break;
             }
-            _ => {}
+        }
+        // The session reads this as the client disconnecting.
+        let _ = bridge_writer.shutdown().await;
+    });
+
+    let mut lines = BufReader::new(bridge_reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if frames_out.send(Message::text(line)).await.is_err() {
+            break;
         }
     }
+    let _ = frames_out.close().await;
+    inbound.abort();
+    let _ = session.await;
 }
 
-fn main() -> std::io::Result<()> {
-    let (broker_tx, broker_rx) = unbounded::<Event>();
+// Reads IRC lines until the client picks a nickname that would be accepted
+// as a chat name, answering what it has to along the way.
+async fn irc_register<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    quotas: &Quotas,
+) -> std::io::Result<String> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let bytes_read = read_line_limited(reader, &mut line, config.max_message_len).await?;
+        if bytes_read == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Disconnected before choosing a nickname",
+            ));
+        }
+        quotas.throttle_in_async(bytes_read).await;
 
-    let broker_handle = thread::spawn(move || {
-        let mut clients: HashMap<usize, Client> = HashMap::new();
-        let mut id_counter: usize = 0;
+        let reply = match irc::parse(&line) {
+            Some(Command::Cap) => format!(":{} CAP * LS :", irc::SERVER),
+            Some(Command::Ping(token)) => {
+                format!(":{} PONG {} :{}", irc::SERVER, irc::SERVER, token)
+            }
+            Some(Command::Nick(nick))
+                if !nick.is_empty()
+                    && is_alphanumeric(&nick, config.unicode_names)
+                    && nick.chars().count() <= config.max_name_len =>
+            {
+                return Ok(nick);
+            }
+            Some(Command::Nick(nick)) => {
+                let target = format!("* {}", nick);
+                irc::reply("432", &target, "Nicknames must be alphanumeric")
+            }
+            Some(Command::Quit) => {
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "Quit before choosing a nickname",
+                ));
+            }
+            _ => continue,
+        };
+        write_line(writer, &reply, quotas).await?;
+    }
+}
 
-        for event in broker_rx {
-            match event {
-                Event::Join { name, sender } => {
-                    let id = id_counter;
-                    id_counter += 1;
+// Serves a stock IRC client. Its nickname is its chat name, #budgetchat is
+// the room, and a PRIVMSG to a nickname is a /msg.
+async fn handle_irc(
+    stream: TcpStream,
+    peer: String,
+    broker_tx: UnboundedSender<Event>,
+    config: Arc<Config>,
+    quotas: Arc<Quotas>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
 
-                    let names: Vec<&str> = clients.values().map(|c| c.name.as_str()).collect();
-                    let members = if names.is_empty() {
-                        "...just you it seems...".to_string()
-                    } else {
-                        names.join(", ")
-                    };
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            log::error("reject", json!({ "peer": peer, "reason": e.to_string() }));
+            let _ = write_line(
+                &mut writer,
+                &format!("ERROR :{}, try again later", e),
+                &quotas,
+            )
+            .await;
+            return;
+        }
+    };
 
-                    sender.send(ClientMessage::Welcome { id, members }).unwrap();
-                    clients.insert(
-                        id,
-                        Client {
-                            name: name.clone(),
-                            sender,
-                        },
-                    );
-
-                    let announcement = format!("* {} has entered the room", name);
-                    for (client_id, client) in &clients {
-                        if *client_id != id {
-                            let _ = client
-                                .sender
-                                .send(ClientMessage::Text(announcement.clone()));
-                        }
+    let register = irc_register(&mut reader, &mut writer, &config, &quotas);
+    let nick = match tokio::time::timeout(config.name_timeout, register)
+        .await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "No nickname given in time")))
+    {
+        Ok(nick) => nick,
+        Err(e) => {
+            log::error("name", json!({ "peer": peer, "error": e.to_string() }));
+            return;
+        }
+    };
+
+    let (client_tx, mut client_rx) = mpsc::channel::<ClientMessage>(CLIENT_QUEUE);
+    let join = Event::Join {
+        name: nick.clone(),
+        sender: client_tx,
+    };
+    if broker_tx.send(join).is_err() {
+        log::error("broker_stopped", json!({ "peer": peer, "name": nick }));
+        return;
+    }
+
+    let client_id = match client_rx.recv().await {
+        Some(ClientMessage::Welcome { id, members }) => {
+            log::event(
+                "connect",
+                json!({ "id": id, "name": nick, "peer": peer, "transport": "irc" }),
+            );
+            let welcome = format!("Welcome to {}, {}", irc::SERVER, nick);
+            let members = render(&config.templates.members, &nick, &members);
+            for line in [
+                irc::reply("001", &nick, &welcome),
+                irc::joined(&nick),
+                irc::notice(&members),
+            ] {
+                let _ = write_line(&mut writer, &line, &quotas).await;
+            }
+            id
+        }
+        Some(ClientMessage::Rejected(reason)) => {
+            let _ = write_line(&mut writer, &format!("ERROR :{}", reason), &quotas).await;
+            return;
+        }
+        _ => {
+            log::error("broker_stopped", json!({ "peer": peer, "name": nick }));
+            return;
+        }
+    };
+
+    // Answers the reader gives itself, like PONGs, go out through the writer
+    // loop along with everything from the broker.
+    let (replies_tx, mut replies_rx) = mpsc::unbounded_channel::<String>();
+    let reader_broker_tx = broker_tx.clone();
+    let reader_quotas = quotas.clone();
+    let reader_nick = nick.clone();
+    let private_messages = config.capabilities.has("msg");
+    let max_message_len = config.max_message_len;
+
+    let reader_task = tokio::spawn(async move {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let bytes_read = match read_line_limited(&mut reader, &mut line, max_message_len).await
+            {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(e) => {
+                    if e.kind() == ErrorKind::InvalidData {
+                        log::error("read", json!({ "id": client_id, "error": e.to_string() }));
                     }
+                    break;
                 }
-                Event::Message(message) => {
-                    if let Some(client_info) = clients.get(&message.client_id) {
-                        let formatted_msg = format!("[{}] {}", client_info.name, message.content);
-                        for (client_id, client) in &clients {
-                            if *client_id != message.client_id {
-                                let _ = client
-                                    .sender
-                                    .send(ClientMessage::Text(formatted_msg.clone()));
-                            }
-                        }
-                    }
+            };
+            reader_quotas.throttle_in_async(bytes_read).await;
+
+            // Held to the same rules as lines from TCP clients, so a stray
+            // `\r` or escape sequence can't reach the room this way either.
+            let line = match strip_line_ending(&line) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::error("read", json!({ "id": client_id, "error": e.to_string() }));
+                    break;
                 }
-                Event::Leave { id } => {
-                    println!("Client {} left", id);
-                    let name = clients.get(&id).unwrap().name.clone();
-                    clients.remove(&id);
+            };
 
-                    let announcement = format!("* {} has left the room", name);
-                    for (_, client) in &clients {
-                        let _ = client
-                            .sender
-                            .send(ClientMessage::Text(announcement.clone()));
-                    }
+            let event = match irc::parse(line) {
+                Some(Command::Privmsg { text, .. }) if text.trim().is_empty() => continue,
+                Some(Command::Privmsg { target, text })
+                    if target.eq_ignore_ascii_case(irc::CHANNEL) =>
+                {
+                    Event::Message(ChatMessage {
+                        client_id,
+                        content: text.trim().to_string(),
+                    })
                 }
+                Some(Command::Privmsg { target, text }) if private_messages => Event::Private {
+                    id: client_id,
+                    to: target,
+                    text: text.trim().to_string(),
+                },
+                Some(Command::Privmsg { target, .. }) => {
+                    let target = format!("{} {}", reader_nick, target);
+                    let reply = irc::reply("401", &target, "Private messages aren't enabled");
+                    let _ = replies_tx.send(reply);
+                    continue;
+                }
+                Some(Command::Join(channel)) if channel.eq_ignore_ascii_case(irc::CHANNEL) => {
+                    let _ = replies_tx.send(irc::joined(&reader_nick));
+                    continue;
+                }
+                Some(Command::Join(channel)) => {
+                    let target = format!("{} {}", reader_nick, channel);
+                    let reply = irc::reply("403", &target, "Only #budgetchat exists");
+                    let _ = replies_tx.send(reply);
+                    continue;
+                }
+                Some(Command::Ping(token)) => {
+                    let pong = format!(":{} PONG {} :{}", irc::SERVER, irc::SERVER, token);
+                    let _ = replies_tx.send(pong);
+                    continue;
+                }
+                Some(Command::Quit) => break,
+                _ => continue,
+            };
+
+            if reader_broker_tx.send(event).is_err() {
+                return;
             }
         }
+        let _ = reader_broker_tx.send(Event::Leave { id: client_id });
     });
 
-    let listener = TcpListener::bind("0.0.0.0:8080")?;
+    loop {
+        let line = tokio::select! {
+            message = client_rx.recv() => match message {
+                Some(ClientMessage::Text(text)) => irc::translate(&text),
+                Some(_) => continue,
+                None => break,
+            },
+            Some(reply) = replies_rx.recv() => reply,
+        };
+
+        if let Err(e) = write_line(&mut writer, &line, &quotas).await {
+            log::error("write", json!({ "id": client_id, "error": e.to_string() }));
+            break;
+        }
+    }
+    reader_task.abort();
+    let _ = broker_tx.send(Event::Leave { id: client_id });
+    let _ = writer.shutdown().await;
+}
+
+// Answers one command per line: `/who` lists everyone connected, and
+// `/kick <name>` disconnects that client.
+async fn handle_admin(stream: TcpStream, broker_tx: UnboundedSender<Event>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let tx = broker_tx.clone();
-                thread::spawn(move || {
-                    handle_client(stream, tx);
-                });
+    while let Ok(Some(line)) = lines.next_line().await {
+        let command = line.trim();
+        let reply = if command == "/who" {
+            let (reply, members) = oneshot::channel();
+            if broker_tx.send(Event::Who(reply)).is_err() {
+                return;
+            }
+            let Ok(members) = members.await else {
+                return;
+            };
+
+            let mut reply = String::new();
+            for member in &members {
+                reply.push_str(&format!(
+                    "* {} in {}, joined {}s ago\n",
+                    member.name,
+                    member.room,
+                    member.joined_at.elapsed().as_secs()
+                ));
+            }
+            reply.push_str(&format!("* {} connected\n", members.len()));
+            reply
+        } else if let Some(name) = command.strip_prefix("/kick ") {
+            let name = name.trim().to_string();
+            let (reply, kicked) = oneshot::channel();
+            let kick = Event::Kick {
+                name: name.clone(),
+                reply,
+            };
+            if broker_tx.send(kick).is_err() {
+                return;
             }
-            Err(e) => {
-                eprintln!("Connection failed: {}", e);
+            match kicked.await {
+                Ok(true) => format!("* Kicked {}\n", name),
+                Ok(false) => format!("* There is nobody called {}\n", name),
+                Err(_) => return,
             }
+        } else {
+            "* Unknown command, try /who or /kick <name>\n".to_string()
+        };
+
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn run_admin(listener: TcpListener, broker_tx: UnboundedSender<Event>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_admin(stream, broker_tx.clone()));
+            }
+            Err(e) => log::error("admin_accept", json!({ "error": e.to_string() })),
+        }
+    }
+}
+
+// Never resolves without a listener, so an optional one can sit in a select.
+async fn accept_if_listening(
+    listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = Arc::new(Config::from_args().map_err(std::io::Error::other)?);
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
+    let (broker_tx, mut broker_rx) = mpsc::unbounded_channel::<Event>();
+
+    let mut broker = Broker::new(BrokerConfig {
+        templates: config.templates.clone(),
+        bot_mode: config.capabilities.has("bot"),
+        replay_len: if config.capabilities.has("replay") {
+            config.replay_len
+        } else {
+            0
+        },
+        max_clients: config.max_clients,
+        max_name_len: Some(config.max_name_len),
+        unicode_names: config.unicode_names,
+    });
+    tokio::spawn(async move {
+        while let Some(event) = broker_rx.recv().await {
+            broker.handle(event);
         }
+    });
+
+    let listener = TcpListener::bind(&config.addr).await?;
+
+    if let Some(addr) = &config.admin {
+        let admin = TcpListener::bind(addr)
+            .await
+            .expect("Couldn't bind admin listener");
+        tokio::spawn(run_admin(admin, broker_tx.clone()));
     }
 
-    drop(broker_handle);
+    let websocket = match &config.websocket {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+
+    let irc = match &config.irc {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+
+    let mut clients = JoinSet::new();
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let (reader, writer) = stream.into_split();
+                    clients.spawn(handle_client(
+                        reader,
+                        writer,
+                        peer.to_string(),
+                        broker_tx.clone(),
+                        config.clone(),
+                        quotas.clone(),
+                    ));
+                }
+                Err(e) => {
+                    log::error("accept", json!({ "error": e.to_string() }));
+                }
+            },
+            accepted = accept_if_listening(&websocket) => match accepted {
+                Ok((stream, peer)) => {
+                    clients.spawn(handle_websocket(
+                        stream,
+                        peer.to_string(),
+                        broker_tx.clone(),
+                        config.clone(),
+                        quotas.clone(),
+                    ));
+                }
+                Err(e) => {
+                    log::error("websocket_accept", json!({ "error": e.to_string() }));
+                }
+            },
+            accepted = accept_if_listening(&irc) => match accepted {
+                Ok((stream, peer)) => {
+                    clients.spawn(handle_irc(
+                        stream,
+                        peer.to_string(),
+                        broker_tx.clone(),
+                        config.clone(),
+                        quotas.clone(),
+                    ));
+                }
+                Err(e) => {
+                    log::error("irc_accept", json!({ "error": e.to_string() }));
+                }
+            },
+            // Reaps finished clients so the set doesn't grow without bound.
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            _ = &mut ctrl_c => break,
+        }
+    }
+
+    // Stop accepting, have the broker tell everyone and let them go, then
+    // give their writers a moment to flush before exiting.
+    drop(listener);
+    drop(websocket);
+    drop(irc);
+    let (done, told) = oneshot::channel();
+    if broker_tx.send(Event::Shutdown(done)).is_ok() {
+        let _ = told.await;
+    }
+
+    let drained = async { while clients.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_GRACE, drained).await.is_err() {
+        log::error("shutdown_timeout", json!({ "connected": clients.len() }));
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::quota::Quota;
+
+    async fn next_frame<S>(websocket: &mut S) -> String
+    where
+        S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        match websocket.next().await {
+            Some(Ok(Message::Text(text))) => text.to_string(),
+            other => panic!("Expected a text frame, got {:?}", other),
+        }
+    }
+
+    fn spawn_broker() -> UnboundedSender<Event> {
+        let (broker_tx, mut broker_rx) = mpsc::unbounded_channel();
+        let mut broker = Broker::new(BrokerConfig::default());
+        tokio::spawn(async move {
+            while let Some(event) = broker_rx.recv().await {
+                broker.handle(event);
+            }
+        });
+        broker_tx
+    }
+
+    // Joins the broker directly, without a connection, to watch the room.
+    async fn observe(
+        broker_tx: &UnboundedSender<Event>,
+        name: &str,
+    ) -> mpsc::Receiver<ClientMessage> {
+        let (sender, mut receiver) = mpsc::channel(CLIENT_QUEUE);
+        let join = Event::Join {
+            name: name.to_string(),
+            sender,
+        };
+        broker_tx.send(join).expect("Couldn't join");
+        assert!(matches!(
+            receiver.recv().await,
+            Some(ClientMessage::Welcome { .. })
+        ));
+        receiver
+    }
+
+    async fn text(receiver: &mut mpsc::Receiver<ClientMessage>) -> String {
+        match tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await {
+            Ok(Some(ClientMessage::Text(text))) => text.to_string(),
+            _ => panic!("Expected a line of text"),
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_clients_share_the_room_with_tcp_clients() {
+        let broker_tx = spawn_broker();
+
+        let config = Arc::new(Config::default());
+        let quotas = Quotas::new(Quota::default());
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind listener");
+        let addr = listener.local_addr().expect("Couldn't read local address");
+        let server_tx = broker_tx.clone();
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.expect("Couldn't accept");
+            handle_websocket(stream, peer.to_string(), server_tx, config, quotas).await;
+        });
+
+        let mut alice_rx = observe(&broker_tx, "alice").await;
+
+        let stream = TcpStream::connect(addr).await.expect("Couldn't connect");
+        let (mut websocket, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .expect("Couldn't complete the handshake");
+
+        assert_eq!(
+            next_frame(&mut websocket).await,
+            "Welcome to budgetchat! What shall I call you?"
+        );
+        websocket
+            .send(Message::text("wendy"))
+            .await
+            .expect("Couldn't send name");
+        assert_eq!(
+            next_frame(&mut websocket).await,
+            "* The room contains: alice *"
+        );
+
+        assert_eq!(text(&mut alice_rx).await, "* wendy has entered the room");
+    }
+
+    #[tokio::test]
+    async fn a_line_cut_off_by_a_disconnect_is_discarded() {
+        let broker_tx = spawn_broker();
+        let mut alice_rx = observe(&broker_tx, "alice").await;
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (reader, writer) = tokio::io::split(server);
+        let session = tokio::spawn(handle_client(
+            reader,
+            writer,
+            "test".to_string(),
+            broker_tx.clone(),
+            Arc::new(Config::default()),
+            Quotas::new(Quota::default()),
+        ));
+
+        let (_from_server, mut to_server) = tokio::io::split(client);
+        to_server
+            .write_all(b"bob\nhello\nhalf a li")
+            .await
+            .expect("Couldn't send lines");
+        to_server.shutdown().await.expect("Couldn't disconnect");
+        session.await.expect("Session panicked");
+
+        assert_eq!(text(&mut alice_rx).await, "* bob has entered the room");
+        assert_eq!(text(&mut alice_rx).await, "[bob] hello");
+        assert_eq!(text(&mut alice_rx).await, "* bob has left the room");
+    }
+
+    #[test]
+    fn line_endings_are_stripped_and_control_characters_rejected() {
+        assert_eq!(strip_line_ending("hello\r\n").ok(), Some("hello"));
+        assert_eq!(strip_line_ending("hello\n").ok(), Some("hello"));
+        assert_eq!(strip_line_ending("hello").ok(), Some("hello"));
+
+        for line in ["hel\rlo\n", "hello\r\r\n", "tab\there\n", "\x1b[2Jhello\n"] {
+            let e = strip_line_ending(line).expect_err("Control characters should be rejected");
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{:?}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn irc_messages_with_control_characters_are_rejected() {
+        let broker_tx = spawn_broker();
+        let mut alice_rx = observe(&broker_tx, "alice").await;
+
+        let config = Arc::new(Config::default());
+        let quotas = Quotas::new(Quota::default());
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind listener");
+        let addr = listener.local_addr().expect("Couldn't read local address");
+        let server_tx = broker_tx.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = listener.accept().await.expect("Couldn't accept");
+                let session = handle_irc(
+                    stream,
+                    peer.to_string(),
+                    server_tx.clone(),
+                    config.clone(),
+                    quotas.clone(),
+                );
+                tokio::spawn(session);
+            }
+        });
+
+        for text_sent in ["hi\rthere", "\x1b[2Jhello"] {
+            let mut stream = TcpStream::connect(addr).await.expect("Couldn't connect");
+            let lines = format!(
+                "NICK ircbob\r\nPRIVMSG #budgetchat :hello\r\nPRIVMSG #budgetchat :{}\r\n",
+                text_sent
+            );
+            stream
+                .write_all(lines.as_bytes())
+                .await
+                .expect("Couldn't send lines");
+
+            assert_eq!(text(&mut alice_rx).await, "* ircbob has entered the room");
+            assert_eq!(text(&mut alice_rx).await, "[ircbob] hello");
+            assert_eq!(
+                text(&mut alice_rx).await,
+                "* ircbob has left the room",
+                "{:?}",
+                text_sent
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_name_ending_in_crlf_is_accepted() {
+        let quotas = Quotas::new(Quota::default());
+        let config = Config::default();
+        let mut prompt = Vec::new();
+
+        let mut reader = &b"alice\r\n"[..];
+        let name = handle_invite(&mut reader, &mut prompt, &config, &quotas).await;
+        assert_eq!(name.ok().as_deref(), Some("alice"));
+
+        let mut reader = &b"al\rice\r\n"[..];
+        let name = handle_invite(&mut reader, &mut prompt, &config, &quotas).await;
+        assert!(name.is_err());
+    }
+
+    #[tokio::test]
+    async fn an_unterminated_last_line_reads_as_the_end() {
+        let mut input = &b"whole\npartial"[..];
+        let mut line = String::new();
+
+        let bytes_read = read_line_limited(&mut input, &mut line, 1000)
+            .await
+            .expect("A whole line should be read");
+        assert_eq!((bytes_read, line.as_str()), (6, "whole\n"));
+
+        line.clear();
+        let bytes_read = read_line_limited(&mut input, &mut line, 1000)
+            .await
+            .expect("A fragment shouldn't be an error");
+        assert_eq!((bytes_read, line.as_str()), (0, ""));
+    }
+
+    #[tokio::test]
+    async fn unicode_names_are_only_accepted_with_unicode_names() {
+        let quotas = Quotas::new(Quota::default());
+        let mut config = Config::default();
+
+        for unicode_names in [false, true] {
+            config.unicode_names = unicode_names;
+            let mut reader = "Zoë\n".as_bytes();
+            let mut prompt = Vec::new();
+            let name = handle_invite(&mut reader, &mut prompt, &config, &quotas).await;
+            assert_eq!(name.ok().as_deref(), unicode_names.then_some("Zoë"));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_is_replaced_rather_than_ending_the_session() {
+        let mut input = &b"caf\xff\nnext\n"[..];
+        let mut line = String::new();
+
+        read_line_limited(&mut input, &mut line, 1000)
+            .await
+            .expect("Invalid UTF-8 shouldn't be an error");
+        assert_eq!(line, "caf\u{fffd}\n");
+
+        line.clear();
+        read_line_limited(&mut input, &mut line, 1000)
+            .await
+            .expect("The next line should still be read");
+        assert_eq!(line, "next\n");
+    }
+}
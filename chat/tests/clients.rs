@@ -0,0 +1,240 @@
+// Runs a real chat server with dozens of clients connecting and talking at
+// once, and checks what each of them sees. The server decides the order
+// concurrent clients join in, so the checks are about each client's view as
+// a whole rather than exact transcripts:
+//
+// - a joiner's member list plus the joins announced to it afterwards name
+//   everyone else exactly once;
+// - every message reaches everyone but its sender, exactly once;
+// - everyone still connected hears each client that leaves.
+//
+// Phases are separated with barriers so one phase's lines can't interleave
+// with the next's. The barriers give up after a while, so a client that
+// panics fails the test instead of leaving the rest waiting forever.
+
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CLIENTS: usize = 40;
+const TIMEOUT: Duration = Duration::from_secs(5);
+const PHASE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// A chat server on a free local port, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Self {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .expect("Couldn't find a free port")
+            .local_addr()
+            .expect("Couldn't read local address")
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_chat"))
+            .args(["--bind", &addr])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Couldn't start chat");
+
+        let started = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "chat never started listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Server { child, addr }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Like std's Barrier, but waits at most PHASE_TIMEOUT for everyone.
+struct Phase {
+    parties: usize,
+    // How many have arrived, and how many times everyone has.
+    state: Mutex<(usize, u64)>,
+    everyone_arrived: Condvar,
+}
+
+impl Phase {
+    fn new(parties: usize) -> Self {
+        Phase {
+            parties,
+            state: Mutex::new((0, 0)),
+            everyone_arrived: Condvar::new(),
+        }
+    }
+
+    fn wait(&self) {
+        let mut state = self.state.lock().expect("Couldn't obtain lock on phase");
+        let (arrived, generation) = *state;
+
+        if arrived + 1 == self.parties {
+            *state = (0, generation + 1);
+            self.everyone_arrived.notify_all();
+            return;
+        }
+        state.0 += 1;
+
+        let (state, waited) = self
+            .everyone_arrived
+            .wait_timeout_while(state, PHASE_TIMEOUT, |state| state.1 == generation)
+            .expect("Couldn't obtain lock on phase");
+        drop(state);
+        assert!(!waited.timed_out(), "Not every client finished the phase");
+    }
+}
+
+struct Client {
+    name: String,
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: &str, name: &str) -> Self {
+        let stream = TcpStream::connect(addr).expect("Couldn't connect to chat");
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .expect("Couldn't set read timeout");
+        let reader = BufReader::new(stream.try_clone().expect("Couldn't clone stream"));
+
+        Client {
+            name: name.to_string(),
+            stream,
+            reader,
+        }
+    }
+
+    fn send(&mut self, line: &str) {
+        writeln!(self.stream, "{}", line).expect("Couldn't send line");
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        let bytes_read = self
+            .reader
+            .read_line(&mut line)
+            .unwrap_or_else(|e| panic!("{} couldn't read a line: {}", self.name, e));
+        assert!(bytes_read > 0, "chat disconnected {}", self.name);
+        line.trim_end().to_string()
+    }
+
+    // Everyone already in the room, from the member list sent on joining.
+    fn join(&mut self) -> HashSet<String> {
+        self.line();
+        let name = self.name.clone();
+        self.send(&name);
+
+        let members = self.line();
+        let members = members
+            .strip_prefix("* The room contains: ")
+            .and_then(|m| m.strip_suffix(" *"))
+            .unwrap_or_else(|| panic!("{} got no member list: {}", self.name, members));
+
+        if members == "...just you it seems..." {
+            HashSet::new()
+        } else {
+            members.split(", ").map(str::to_string).collect()
+        }
+    }
+
+    // Reads `count` lines, each of which must be `prefix` + a name + `suffix`,
+    // and returns the names.
+    fn names(&mut self, count: usize, prefix: &str, suffix: &str) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let line = self.line();
+                line.strip_prefix(prefix)
+                    .and_then(|rest| rest.strip_suffix(suffix))
+                    .unwrap_or_else(|| panic!("{} got an unexpected line: {}", self.name, line))
+                    .to_string()
+            })
+            .collect()
+    }
+}
+
+fn everyone_but(name: &str) -> HashSet<String> {
+    (0..CLIENTS)
+        .map(|i| format!("client{}", i))
+        .filter(|other| other != name)
+        .collect()
+}
+
+fn distinct(names: &[String]) -> HashSet<String> {
+    let set: HashSet<String> = names.iter().cloned().collect();
+    assert_eq!(set.len(), names.len(), "Duplicate lines: {:?}", names);
+    set
+}
+
+#[test]
+fn many_clients_see_a_consistent_room() {
+    let server = Server::start();
+    let everyone = Arc::new(Phase::new(CLIENTS));
+    let stayers = Arc::new(Phase::new(CLIENTS / 2));
+
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|i| {
+            let addr = server.addr.clone();
+            let everyone = everyone.clone();
+            let stayers = stayers.clone();
+
+            thread::spawn(move || {
+                let name = format!("client{}", i);
+                let mut client = Client::connect(&addr, &name);
+                let others = everyone_but(&name);
+
+                // Whoever wasn't in the member list must be announced, once.
+                let members = client.join();
+                let announced =
+                    client.names(others.len() - members.len(), "* ", " has entered the room");
+                let announced = distinct(&announced);
+                assert!(members.is_disjoint(&announced));
+                assert_eq!(&members | &announced, others);
+                everyone.wait();
+
+                client.send("hello");
+                let senders = client.names(others.len(), "[", "] hello");
+                assert_eq!(distinct(&senders), others);
+                everyone.wait();
+
+                // The odd clients leave; the even ones hear each of them go.
+                let leavers: HashSet<String> = (0..CLIENTS)
+                    .filter(|i| i % 2 == 1)
+                    .map(|i| format!("client{}", i))
+                    .collect();
+                if leavers.contains(&name) {
+                    return;
+                }
+                let left = client.names(leavers.len(), "* ", " has left the room");
+                assert_eq!(distinct(&left), leavers);
+                stayers.wait();
+
+                // Lines from one sender arrive in order, so if this is next
+                // nothing else was queued.
+                if i == 0 {
+                    client.send("last");
+                } else {
+                    assert_eq!(client.line(), "[client0] last");
+                }
+            })
+        })
+        .collect();
+
+    for client in clients {
+        client.join().expect("A client's checks failed");
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+// Servers never call Instant::now() or thread::sleep() directly for protocol
+// timing (heartbeats, retransmission, expiry). They go through a Clock so
+// tests can swap in a VirtualClock and fast-forward hours of protocol time
+// without actually waiting for it. The tokio servers get the same from
+// tokio's own timer instead, which their tests pause and fast-forward.
+//
+// `wall_time` is for anything that outlives the process, like expiry times
+// written to disk, where an Instant means nothing after a restart.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn wall_time(&self) -> SystemTime;
+
+    fn sleep_until(&self, deadline: Instant);
+
+    fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration);
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+    }
+}
+
+// Time only moves when advance() is called. Threads blocked in sleep_until()
+// are woken on every advance and go back to sleep until their deadline has
+// been reached, so a test controls exactly which timers fire and in what order.
+#[derive(Debug)]
+pub struct VirtualClock {
+    origin: Instant,
+    wall_origin: SystemTime,
+    elapsed: Mutex<Duration>,
+    ticked: Condvar,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        VirtualClock {
+            origin: Instant::now(),
+            wall_origin: SystemTime::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            ticked: Condvar::new(),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut elapsed = self.elapsed.lock().expect("Couldn't obtain lock on clock");
+        *elapsed += duration;
+        self.ticked.notify_all();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().expect("Couldn't obtain lock on clock")
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.wall_origin + self.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let target = deadline.saturating_duration_since(self.origin);
+        let mut elapsed = self.elapsed.lock().expect("Couldn't obtain lock on clock");
+        while *elapsed < target {
+            elapsed = self
+                .ticked
+                .wait(elapsed)
+                .expect("Couldn't obtain lock on clock");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+
+    #[test]
+    fn virtual_time_only_moves_on_advance() {
+        let clock = VirtualClock::new();
+        let (start, wall_start) = (clock.now(), clock.wall_time());
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(clock.now() - start, Duration::from_secs(3600));
+        assert_eq!(
+            clock.wall_time().duration_since(wall_start).unwrap(),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn sleepers_wake_once_their_deadline_is_reached() {
+        let clock = Arc::new(VirtualClock::new());
+        let (woke, wakes) = mpsc::channel();
+        let deadline = clock.now() + Duration::from_secs(10);
+
+        let sleeper = {
+            let clock = clock.clone();
+            std::thread::spawn(move || {
+                clock.sleep_until(deadline);
+                woke.send(clock.elapsed()).unwrap();
+            })
+        };
+
+        clock.advance(Duration::from_secs(9));
+        assert!(wakes.recv_timeout(Duration::from_millis(50)).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            wakes.recv_timeout(Duration::from_secs(5)),
+            Ok(Duration::from_secs(10))
+        );
+        sleeper.join().unwrap();
+    }
+}
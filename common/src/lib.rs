@@ -0,0 +1,4 @@
+pub mod clock;
+pub mod protocol;
+pub mod quota;
+pub mod roundtrip;
@@ -0,0 +1,191 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+// Every server speaks its protocol strictly by default and only turns on the
+// optional extensions it's asked to. The set of extensions, and the version
+// reported for them, lives here so the servers gate them the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub extensions: &'static [&'static str],
+}
+
+pub const CHAT: Protocol = Protocol {
+    name: "chat",
+    version: "1.0.0",
+    extensions: &["bot", "rooms", "msg", "replay", "nick"],
+};
+
+pub const DATABASE: Protocol = Protocol {
+    name: "database",
+    version: "0.0.9",
+    extensions: &["ttl"],
+};
+
+pub const FLOCK: Protocol = Protocol {
+    name: "flock",
+    version: "1.0.0",
+    extensions: &["camera-relocation", "observer"],
+};
+
+pub const PRICES: Protocol = Protocol {
+    name: "prices",
+    version: "1.0.0",
+    extensions: &["token", "resume"],
+};
+
+pub const PRIME: Protocol = Protocol {
+    name: "prime",
+    version: "1.0.0",
+    extensions: &[
+        "factorize",
+        "timeout",
+        "batch",
+        "concatenated",
+        "lenient-trailing",
+        "next-prime",
+    ],
+};
+
+// The extensions enabled for one server. `--lab` enables everything the
+// protocol defines and `--enable a,b` enables just those; anything not
+// enabled is rejected exactly as the strict protocol would.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    protocol: Protocol,
+    enabled: BTreeSet<&'static str>,
+}
+
+impl Capabilities {
+    pub fn strict(protocol: Protocol) -> Self {
+        Capabilities {
+            protocol,
+            enabled: BTreeSet::new(),
+        }
+    }
+
+    pub fn lab(protocol: Protocol) -> Self {
+        Capabilities {
+            protocol,
+            enabled: protocol.extensions.iter().copied().collect(),
+        }
+    }
+
+    pub fn enable(&mut self, extension: &str) -> Result<(), String> {
+        let known = self
+            .protocol
+            .extensions
+            .iter()
+            .find(|&&known| known == extension)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown {} extension '{}', expected one of: {}",
+                    self.protocol.name,
+                    extension,
+                    self.protocol.extensions.join(", ")
+                )
+            })?;
+
+        self.enabled.insert(known);
+        Ok(())
+    }
+
+    pub fn has(&self, extension: &str) -> bool {
+        self.enabled.contains(extension)
+    }
+
+    // Handles the shared `--lab` and `--enable` flags. Returns Ok(false) for
+    // any other argument so the server can parse its own.
+    pub fn parse_arg(
+        &mut self,
+        arg: &str,
+        args: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        match arg {
+            "--lab" => *self = Capabilities::lab(self.protocol),
+            "--enable" => {
+                let extensions = args
+                    .next()
+                    .ok_or_else(|| "Expected a list of extensions after --enable".to_string())?;
+                for extension in extensions.split(',') {
+                    self.enable(extension.trim())?;
+                }
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+// `VERSION` for strict mode, `VERSION+ext+ext` with extensions enabled.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.protocol.version)?;
+        for extension in &self.enabled {
+            write!(f, "+{}", extension)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<(Capabilities, Vec<String>), String> {
+        let mut capabilities = Capabilities::strict(PRIME);
+        let mut args = args.iter().map(|arg| arg.to_string());
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if !capabilities.parse_arg(&arg, &mut args)? {
+                rest.push(arg);
+            }
+        }
+
+        Ok((capabilities, rest))
+    }
+
+    #[test]
+    fn strict_reports_the_bare_version() {
+        let capabilities = Capabilities::strict(PRIME);
+        assert_eq!(capabilities.to_string(), "1.0.0");
+        assert!(!capabilities.has("factorize"));
+    }
+
+    #[test]
+    fn enabled_extensions_are_listed_in_order() {
+        let (capabilities, rest) =
+            parse(&["--enable", "timeout, factorize", "--port", "9000"]).unwrap();
+
+        assert_eq!(capabilities.to_string(), "1.0.0+factorize+timeout");
+        assert!(capabilities.has("factorize"));
+        assert!(!capabilities.has("batch"));
+        assert_eq!(rest, ["--port", "9000"]);
+    }
+
+    #[test]
+    fn lab_enables_every_extension() {
+        let (capabilities, _) = parse(&["--lab"]).unwrap();
+
+        assert_eq!(capabilities, Capabilities::lab(PRIME));
+        assert!(PRIME.extensions.iter().all(|e| capabilities.has(e)));
+    }
+
+    #[test]
+    fn unknown_or_missing_extensions_are_errors() {
+        let err = parse(&["--enable", "factorize,teleport"]).unwrap_err();
+        assert!(
+            err.contains("Unknown prime extension 'teleport'"),
+            "{}",
+            err
+        );
+        assert!(err.contains("next-prime"), "{}", err);
+
+        assert!(parse(&["--enable"]).is_err());
+        assert!(Capabilities::strict(DATABASE).enable("factorize").is_err());
+    }
+}
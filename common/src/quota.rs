@@ -0,0 +1,354 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Per-server resource limits, read from the QUOTA environment variable so
+// each server process on a shared host can be given its own profile, e.g.
+//
+//   QUOTA="sessions=200,memory=64M,in=1M,out=1M"
+//
+// Every limit is optional; anything left out is unbounded. Sizes accept K,
+// M and G suffixes (powers of 1024). `in` and `out` are bytes per second
+// summed over all of the server's connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    pub max_sessions: Option<usize>,
+    pub max_memory: Option<usize>,
+    pub max_bytes_in_per_sec: Option<u64>,
+    pub max_bytes_out_per_sec: Option<u64>,
+}
+
+impl Quota {
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("QUOTA") {
+            Ok(profile) => Quota::parse(&profile),
+            Err(_) => Ok(Quota::default()),
+        }
+    }
+
+    pub fn parse(profile: &str) -> Result<Self, String> {
+        let mut quota = Quota::default();
+
+        for entry in profile.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value in quota, got '{}'", entry))?;
+            let value = parse_size(value.trim())?;
+
+            match key.trim() {
+                "sessions" => quota.max_sessions = Some(value as usize),
+                "memory" => quota.max_memory = Some(value as usize),
+                "in" => quota.max_bytes_in_per_sec = Some(value),
+                "out" => quota.max_bytes_out_per_sec = Some(value),
+                other => return Err(format!("Unknown quota '{}'", other)),
+            }
+        }
+
+        Ok(quota)
+    }
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1 << 10),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1 << 20),
+        Some('G' | 'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid quota size '{}'", value))
+}
+
+// Why a server turned something away. Each protocol decides how to tell the
+// client (an Error message, a malformed response, a close packet, or just
+// dropping the connection).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    Sessions,
+    Memory,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaExceeded::Sessions => write!(f, "Too many sessions"),
+            QuotaExceeded::Memory => write!(f, "Out of memory quota"),
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+// Fixed one-second windows. A caller that overdraws the current window
+// borrows from the ones after it and is told how long to wait before its last
+// byte would have fit; the lock only covers the bookkeeping, so one throttled
+// connection never holds up another's accounting.
+#[derive(Debug)]
+struct RateLimiter {
+    limit: Option<u64>,
+    window: Mutex<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    fn new(limit: Option<u64>) -> Self {
+        RateLimiter {
+            limit,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    // Charges `bytes` at `now`, returning how long to wait before sending
+    // them.
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let Some(limit) = self.limit else {
+            return Duration::ZERO;
+        };
+        let limit = limit.max(1);
+
+        let mut window = self.window.lock().expect("Couldn't obtain lock on rate limiter");
+        let (started, used) = &mut *window;
+
+        let passed = now.saturating_duration_since(*started).as_secs();
+        if passed > 0 {
+            *started += Duration::from_secs(passed);
+            *used = used.saturating_sub(limit.saturating_mul(passed));
+        }
+
+        *used = used.saturating_add(bytes as u64);
+        if *used <= limit {
+            return Duration::ZERO;
+        }
+
+        let windows_ahead = (*used - 1) / limit;
+        (*started + Duration::from_secs(windows_ahead)).saturating_duration_since(now)
+    }
+
+    fn consume(&self, bytes: usize) {
+        let delay = self.reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn consume_async(&self, bytes: usize) {
+        let delay = self.reserve(bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Quotas {
+    quota: Quota,
+    sessions: AtomicUsize,
+    memory: AtomicUsize,
+    bytes_in: RateLimiter,
+    bytes_out: RateLimiter,
+}
+
+impl Quotas {
+    pub fn new(quota: Quota) -> Arc<Self> {
+        Arc::new(Quotas {
+            quota,
+            sessions: AtomicUsize::new(0),
+            memory: AtomicUsize::new(0),
+            bytes_in: RateLimiter::new(quota.max_bytes_in_per_sec),
+            bytes_out: RateLimiter::new(quota.max_bytes_out_per_sec),
+        })
+    }
+
+    pub fn from_env() -> Result<Arc<Self>, String> {
+        Ok(Quotas::new(Quota::from_env()?))
+    }
+
+    // The session counts against the quota until the permit is dropped.
+    pub fn open_session(self: &Arc<Self>) -> Result<SessionPermit, QuotaExceeded> {
+        let open = self.sessions.fetch_add(1, Ordering::SeqCst);
+        if self.quota.max_sessions.is_some_and(|max| open >= max) {
+            self.sessions.fetch_sub(1, Ordering::SeqCst);
+            return Err(QuotaExceeded::Sessions);
+        }
+
+        Ok(SessionPermit(self.clone()))
+    }
+
+    pub fn reserve(&self, bytes: usize) -> Result<(), QuotaExceeded> {
+        let used = self.memory.fetch_add(bytes, Ordering::SeqCst);
+        if self.quota.max_memory.is_some_and(|max| used + bytes > max) {
+            self.memory.fetch_sub(bytes, Ordering::SeqCst);
+            return Err(QuotaExceeded::Memory);
+        }
+
+        Ok(())
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.memory.fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    pub fn throttle_in(&self, bytes: usize) {
+        self.bytes_in.consume(bytes);
+    }
+
+    pub fn throttle_out(&self, bytes: usize) {
+        self.bytes_out.consume(bytes);
+    }
+
+    // The same limits for async servers, which wait on a tokio timer rather
+    // than blocking a runtime thread.
+    #[cfg(feature = "tokio")]
+    pub async fn throttle_in_async(&self, bytes: usize) {
+        self.bytes_in.consume_async(bytes).await;
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn throttle_out_async(&self, bytes: usize) {
+        self.bytes_out.consume_async(bytes).await;
+    }
+
+    pub fn wrap<S>(self: &Arc<Self>, inner: S) -> Throttled<S> {
+        Throttled {
+            inner,
+            quotas: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionPermit(Arc<Quotas>);
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Charges reads against the inbound rate and writes against the outbound rate.
+#[derive(Debug)]
+pub struct Throttled<S> {
+    inner: S,
+    quotas: Arc<Quotas>,
+}
+
+impl<S> Throttled<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.quotas.throttle_in(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.quotas.throttle_out(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_parse_sizes_and_reject_bad_entries() {
+        assert_eq!(
+            Quota::parse("sessions=200, memory=64M,in=1k,out=2G"),
+            Ok(Quota {
+                max_sessions: Some(200),
+                max_memory: Some(64 << 20),
+                max_bytes_in_per_sec: Some(1 << 10),
+                max_bytes_out_per_sec: Some(2 << 30),
+            })
+        );
+        assert_eq!(Quota::parse(""), Ok(Quota::default()));
+
+        assert!(Quota::parse("sessions").is_err());
+        assert!(Quota::parse("threads=4").is_err());
+        assert!(Quota::parse("memory=lots").is_err());
+        assert!(Quota::parse("memory=-1M").is_err());
+        assert!(Quota::parse(&format!("memory={}G", u64::MAX >> 20)).is_err());
+    }
+
+    #[test]
+    fn unlimited_rates_never_wait() {
+        let limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(usize::MAX, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn overdrawn_windows_wait_for_the_window_the_last_byte_fits_in() {
+        let limiter = RateLimiter::new(Some(10));
+        let start = limiter.window.lock().unwrap().0;
+
+        assert_eq!(limiter.reserve(10, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(5, start), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(10, start), Duration::from_secs(2));
+
+        // Half a second later the 25 bytes already charged still run two
+        // windows past the current one.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.reserve(1, later), Duration::from_millis(1500));
+
+        // Once those windows have passed, the budget is back.
+        let idle = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(10, idle), Duration::ZERO);
+        assert_eq!(limiter.reserve(1, idle), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn waiting_callers_do_not_hold_the_limiter() {
+        let quotas = Quotas::new(Quota {
+            max_bytes_out_per_sec: Some(10),
+            ..Quota::default()
+        });
+
+        // The first caller overdraws by a full window and sleeps; the second
+        // still gets its (zero-length) answer straight away.
+        let sleeper = {
+            let quotas = quotas.clone();
+            std::thread::spawn(move || quotas.throttle_out(20))
+        };
+        std::thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert!(quotas.bytes_out.reserve(0, Instant::now()) > Duration::ZERO);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        sleeper.join().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn async_throttling_waits_on_the_runtime_clock() {
+        let quotas = Quotas::new(Quota {
+            max_bytes_in_per_sec: Some(10),
+            ..Quota::default()
+        });
+
+        let started = tokio::time::Instant::now();
+        quotas.throttle_in_async(10).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        quotas.throttle_in_async(10).await;
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+}
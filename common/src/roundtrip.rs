@@ -0,0 +1,62 @@
+// Property tests asserting that a wire codec is symmetric. Each invocation
+// generates a module `name` with two proptests:
+//
+//   encode_decode: decoding encode(v) gives back v, consuming every byte
+//   decode_encode: whenever some bytes decode, re-encoding the value gives
+//                  back exactly the bytes that were consumed
+//
+// decode_encode runs on arbitrary byte strings as well as valid encodings
+// with a byte overwritten or junk appended, so it explores the inputs a
+// decoder might accept without being handed them whole.
+//
+// `encode` is any `Fn(&T) -> Vec<u8>` and `decode` any
+// `Fn(&[u8]) -> Option<(T, usize)>` returning the value and how many bytes it
+// used, both resolved in the invoking module's scope. The calling crate needs
+// `proptest` as a dev-dependency.
+#[macro_export]
+macro_rules! roundtrip_tests {
+    ($name:ident, $strategy:expr, $encode:expr, $decode:expr $(,)?) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+            use ::proptest::prelude::*;
+
+            fn bytes() -> impl Strategy<Value = Vec<u8>> {
+                let junk = || prop::collection::vec(any::<u8>(), 0..64);
+                prop_oneof![
+                    junk(),
+                    ($strategy, junk()).prop_map(|(value, junk)| {
+                        let mut bytes = ($encode)(&value);
+                        bytes.extend(junk);
+                        bytes
+                    }),
+                    ($strategy, any::<prop::sample::Index>(), any::<u8>()).prop_map(
+                        |(value, at, byte)| {
+                            let mut bytes = ($encode)(&value);
+                            if !bytes.is_empty() {
+                                let at = at.index(bytes.len());
+                                bytes[at] = byte;
+                            }
+                            bytes
+                        }
+                    ),
+                ]
+            }
+
+            proptest! {
+                #[test]
+                fn encode_decode(value in $strategy) {
+                    let bytes = ($encode)(&value);
+                    prop_assert_eq!(($decode)(&bytes[..]), Some((value, bytes.len())));
+                }
+
+                #[test]
+                fn decode_encode(bytes in bytes()) {
+                    if let Some((value, consumed)) = ($decode)(&bytes[..]) {
+                        prop_assert_eq!(($encode)(&value), &bytes[..consumed]);
+                    }
+                }
+            }
+        }
+    };
+}
@@ -1,7 +1,207 @@
+use common::clock::{Clock, SystemClock};
+use common::protocol::{self, Capabilities};
+use common::quota::Quotas;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
 use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-type Store = HashMap<String, String>;
+const DEFAULT_ADDR: &str = "0.0.0.0:8080";
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+// Expiry is stored as an absolute wall-clock time rather than a remaining
+// duration, so an entry restored from a snapshot expires at the same moment it
+// would have if the server had never restarted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    value: String,
+    expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+type Store = HashMap<String, Entry>;
+
+#[derive(Debug, Default)]
+struct SocketStats {
+    inserts: AtomicU64,
+    retrieves: AtomicU64,
+    versions: AtomicU64,
+}
+
+impl SocketStats {
+    fn record(&self, req: &Request) -> u64 {
+        let counter = match req {
+            Request::Insert { .. } => &self.inserts,
+            Request::Retrieve { .. } => &self.retrieves,
+            Request::Version => &self.versions,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        self.total()
+    }
+
+    fn total(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+            + self.retrieves.load(Ordering::Relaxed)
+            + self.versions.load(Ordering::Relaxed)
+    }
+}
+
+// `--ttl SECS` gives every insert an expiry; `--snapshot PATH` loads the
+// store from PATH at startup and rewrites it every SNAPSHOT_INTERVAL while
+// inserts keep arriving. `--lab` and `--enable` pick extensions as they do for
+// the other servers.
+#[derive(Debug, Clone)]
+struct Config {
+    capabilities: Capabilities,
+    addrs: Vec<String>,
+    ttl: Option<Duration>,
+    snapshot: Option<PathBuf>,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            capabilities: Capabilities::strict(protocol::DATABASE),
+            addrs: Vec::new(),
+            ttl: None,
+            snapshot: None,
+        };
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if config.capabilities.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Expected a value after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--bind" => config.addrs.push(value()?),
+                "--ttl" => {
+                    let secs = value()?
+                        .parse()
+                        .map_err(|e| format!("Invalid --ttl: {}", e))?;
+                    config.ttl = Some(Duration::from_secs(secs));
+                    config.capabilities.enable("ttl")?;
+                }
+                "--snapshot" => config.snapshot = Some(PathBuf::from(value()?)),
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        if config.addrs.is_empty() {
+            config.addrs.push(DEFAULT_ADDR.to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+fn write_snapshot<W: Write>(writer: W, db: &Store) -> std::io::Result<()> {
+    serde_json::to_writer(writer, db)?;
+    Ok(())
+}
+
+// Entries that expired while the snapshot sat on disk are dropped on load.
+fn read_snapshot<R: Read>(reader: R, now: SystemTime) -> std::io::Result<Store> {
+    let mut db: Store = serde_json::from_reader(reader)?;
+    db.retain(|_, entry| !entry.is_expired(now));
+    Ok(db)
+}
+
+// Written to a temporary file first so a crash mid-write leaves the previous
+// snapshot intact.
+fn save_snapshot(path: &Path, db: &Store) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    write_snapshot(&mut writer, db)?;
+    writer.flush()?;
+    std::fs::rename(tmp, path)
+}
+
+fn load_snapshot(path: &Path, now: SystemTime) -> std::io::Result<Store> {
+    match File::open(path) {
+        Ok(file) => read_snapshot(BufReader::new(file), now),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Store::new()),
+        Err(e) => Err(e),
+    }
+}
+
+// Drops every expired entry, for keys that are never looked up again.
+fn prune_expired(db: &mut Store, now: SystemTime, quotas: &Quotas) {
+    db.retain(|key, entry| {
+        let expired = entry.is_expired(now);
+        if expired {
+            quotas.release(key.len() + entry.value.len());
+        }
+        !expired
+    });
+}
+
+// Rewrites the snapshot if anything was inserted since the last one. The
+// store is only locked long enough to prune and copy it, so the write itself
+// never holds up requests.
+fn write_pending_snapshot(
+    path: &Path,
+    db: &Mutex<Store>,
+    dirty: &AtomicBool,
+    now: SystemTime,
+    quotas: &Quotas,
+) -> std::io::Result<()> {
+    if !dirty.swap(false, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let copy = {
+        let mut db = db.lock().expect("Couldn't obtain lock on store");
+        prune_expired(&mut db, now, quotas);
+        db.clone()
+    };
+
+    save_snapshot(path, &copy).inspect_err(|_| dirty.store(true, Ordering::Relaxed))
+}
+
+fn run_snapshots(
+    path: &Path,
+    db: &Mutex<Store>,
+    dirty: &AtomicBool,
+    clock: &dyn Clock,
+    quotas: &Quotas,
+) {
+    loop {
+        clock.sleep(SNAPSHOT_INTERVAL);
+        if let Err(e) = write_pending_snapshot(path, db, dirty, clock.wall_time(), quotas) {
+            eprintln!("Couldn't write snapshot to {:?}: {}", path, e);
+        }
+    }
+}
+
+// Expired entries are removed lazily, the first time they're looked up.
+fn lookup<'a>(db: &'a mut Store, key: &str, now: SystemTime, quotas: &Quotas) -> Option<&'a Entry> {
+    if db.get(key).is_some_and(|entry| entry.is_expired(now))
+        && let Some(entry) = db.remove(key)
+    {
+        quotas.release(key.len() + entry.value.len());
+    }
+
+    db.get(key)
+}
 
 #[derive(Debug)]
 enum Request {
@@ -29,45 +229,255 @@ impl From<&[u8]> for Request {
     }
 }
 
-fn handle_request(req: Request, socket: &mut UdpSocket, source: SocketAddr, db: &mut Store) {
+// Inserts that would push the store past the memory quota are dropped, the
+// same way the protocol treats any other request it can't serve. Returns the
+// response to send, if any, so the caller can send it (and wait out the
+// outbound rate) after letting go of the store.
+fn handle_request(
+    req: Request,
+    db: &mut Store,
+    config: &Config,
+    clock: &dyn Clock,
+    quotas: &Quotas,
+    dirty: &AtomicBool,
+) -> Option<String> {
     match req {
         Request::Insert { key, value } => {
-            db.insert(key, value);
-        }
-        Request::Retrieve { key } => {
-            if let Some(val) = db.get(&key) {
-                let resp = format!("{}={}", key, val);
-                socket.send_to(resp.as_bytes(), source).unwrap();
-            };
-        }
-        Request::Version => {
-            let resp = "version=0.0.9";
-            socket.send_to(resp.as_bytes(), source).unwrap();
+            let old_size = db.get(&key).map_or(0, |old| key.len() + old.value.len());
+            let new_size = key.len() + value.len();
+
+            if new_size > old_size {
+                if let Err(e) = quotas.reserve(new_size - old_size) {
+                    eprintln!("Dropping insert for {:?}: {}", key, e);
+                    return None;
+                }
+            } else {
+                quotas.release(old_size - new_size);
+            }
+
+            let expires_at = config.ttl.map(|ttl| clock.wall_time() + ttl);
+            db.insert(key, Entry { value, expires_at });
+            dirty.store(true, Ordering::Relaxed);
+            None
         }
+        Request::Retrieve { key } => lookup(db, &key, clock.wall_time(), quotas)
+            .map(|entry| format!("{}={}", key, entry.value)),
+        Request::Version => Some(format!("version={}", config.capabilities)),
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:8080")?;
-    let mut db: HashMap<String, String> = HashMap::new();
+// IPv6 sockets are bound v6-only so that `[::]:PORT` and `0.0.0.0:PORT` can
+// be served side by side instead of the v6 socket claiming both stacks.
+fn bind(addr: &str) -> std::io::Result<UdpSocket> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|e| std::io::Error::other(format!("Invalid address '{}': {}", addr, e)))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+fn serve(
+    socket: UdpSocket,
+    db: Arc<Mutex<Store>>,
+    stats: Arc<SocketStats>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    quotas: Arc<Quotas>,
+    dirty: Arc<AtomicBool>,
+) {
+    let local_addr = socket.local_addr().unwrap();
 
     let mut buf = [0; 999];
-    let mut socket_clone = socket.try_clone().unwrap();
     loop {
         match socket.recv_from(&mut buf) {
             Ok((amt, source)) => {
+                quotas.throttle_in(amt);
                 let packet = &buf[..amt];
                 let req = Request::from(packet);
-                println!("Request: {:?}", req);
+                let count = stats.record(&req);
+                println!("[{}] Request #{}: {:?}", local_addr, count, req);
 
-                handle_request(req, &mut socket_clone, source, &mut db);
+                let resp = {
+                    let mut db = db.lock().expect("Couldn't obtain lock on store");
+                    handle_request(req, &mut db, &config, clock.as_ref(), &quotas, &dirty)
+                };
+
+                if let Some(resp) = resp {
+                    quotas.throttle_out(resp.len());
+                    socket.send_to(resp.as_bytes(), source).unwrap();
+                }
             }
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("[{}] {}", local_addr, e);
                 break;
             }
         };
     }
 
+    println!("[{}] Closed after {:?}", local_addr, stats);
+}
+
+fn main() -> std::io::Result<()> {
+    let config = Arc::new(Config::from_args().map_err(std::io::Error::other)?);
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
+
+    let store = match &config.snapshot {
+        Some(path) => load_snapshot(path, clock.wall_time())?,
+        None => Store::new(),
+    };
+    let loaded: usize = store.iter().map(|(k, e)| k.len() + e.value.len()).sum();
+    quotas.reserve(loaded).map_err(std::io::Error::other)?;
+    let db: Arc<Mutex<Store>> = Arc::new(Mutex::new(store));
+    let dirty = Arc::new(AtomicBool::new(false));
+
+    if let Some(path) = config.snapshot.clone() {
+        let db = db.clone();
+        let dirty = dirty.clone();
+        let clock = clock.clone();
+        let quotas = quotas.clone();
+
+        thread::spawn(move || run_snapshots(&path, &db, &dirty, clock.as_ref(), &quotas));
+    }
+
+    let mut handles = Vec::new();
+    for addr in &config.addrs {
+        let socket = bind(addr)?;
+        let db = db.clone();
+        let stats = Arc::new(SocketStats::default());
+        let config = config.clone();
+        let clock = clock.clone();
+        let quotas = quotas.clone();
+        let dirty = dirty.clone();
+
+        handles.push(thread::spawn(move || {
+            serve(socket, db, stats, config, clock, quotas, dirty)
+        }));
+    }
+
+    for handle in handles {
+        let _ = handle.join();
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::clock::VirtualClock;
+    use common::quota::Quota;
+
+    fn entry(value: &str, expires_at: Option<SystemTime>) -> Entry {
+        Entry {
+            value: value.to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn snapshot_preserves_absolute_expiry() {
+        let clock = VirtualClock::new();
+        let expires_at = clock.wall_time() + Duration::from_secs(60);
+
+        let mut db = Store::new();
+        db.insert("foo".to_string(), entry("bar", Some(expires_at)));
+        db.insert("baz".to_string(), entry("qux", None));
+
+        let mut snapshot = Vec::new();
+        write_snapshot(&mut snapshot, &db).unwrap();
+
+        // A restart half way through the TTL keeps the original deadline
+        // instead of granting the entry another 60 seconds.
+        clock.advance(Duration::from_secs(30));
+        let restored = read_snapshot(snapshot.as_slice(), clock.wall_time()).unwrap();
+        assert_eq!(restored, db);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(restored["foo"].is_expired(clock.wall_time()));
+        assert!(!restored["baz"].is_expired(clock.wall_time()));
+    }
+
+    #[test]
+    fn snapshot_drops_entries_that_expired_on_disk() {
+        let clock = VirtualClock::new();
+
+        let mut db = Store::new();
+        db.insert(
+            "foo".to_string(),
+            entry("bar", Some(clock.wall_time() + Duration::from_secs(10))),
+        );
+        db.insert("baz".to_string(), entry("qux", None));
+
+        let mut snapshot = Vec::new();
+        write_snapshot(&mut snapshot, &db).unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        let restored = read_snapshot(snapshot.as_slice(), clock.wall_time()).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert!(restored.contains_key("baz"));
+    }
+
+    #[test]
+    fn lookup_removes_expired_entries() {
+        let clock = VirtualClock::new();
+        let quotas = Quotas::new(Quota::default());
+
+        let mut db = Store::new();
+        quotas.reserve("foo".len() + "bar".len()).unwrap();
+        db.insert(
+            "foo".to_string(),
+            entry("bar", Some(clock.wall_time() + Duration::from_secs(5))),
+        );
+
+        assert!(lookup(&mut db, "foo", clock.wall_time(), &quotas).is_some());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(lookup(&mut db, "foo", clock.wall_time(), &quotas).is_none());
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_batched_and_skip_expired_entries() {
+        let clock = VirtualClock::new();
+        let quotas = Quotas::new(Quota {
+            max_memory: Some("foo".len() + "bar".len()),
+            ..Quota::default()
+        });
+        let path = std::env::temp_dir().join(format!("database-test-{}.json", std::process::id()));
+        let dirty = AtomicBool::new(false);
+
+        let mut db = Store::new();
+        quotas.reserve("foo".len() + "bar".len()).unwrap();
+        db.insert(
+            "foo".to_string(),
+            entry("bar", Some(clock.wall_time() + Duration::from_secs(5))),
+        );
+        db.insert("baz".to_string(), entry("qux", None));
+        let db = Mutex::new(db);
+
+        // Nothing is written until an insert marks the store dirty.
+        write_pending_snapshot(&path, &db, &dirty, clock.wall_time(), &quotas).unwrap();
+        assert!(!path.exists());
+
+        dirty.store(true, Ordering::Relaxed);
+        clock.advance(Duration::from_secs(5));
+        write_pending_snapshot(&path, &db, &dirty, clock.wall_time(), &quotas).unwrap();
+        let restored = load_snapshot(&path, SystemTime::UNIX_EPOCH).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.len(), 1);
+        assert!(restored.contains_key("baz"));
+        assert_eq!(db.lock().unwrap().len(), 1);
+        assert!(!dirty.load(Ordering::Relaxed));
+
+        // The expired entry's memory went back to the quota.
+        assert!(quotas.reserve("foo".len() + "bar".len()).is_ok());
+    }
+}
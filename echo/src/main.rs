@@ -1,25 +1,185 @@
+use common::quota::Quotas;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
 
-fn handle_client(stream: &mut TcpStream) {
-    let mut buf = vec![];
-    match stream.read_to_end(&mut buf) {
-        Ok(m) => println!("Received: {}", m),
-        Err(e) => eprintln!("Couldn't read stream: {}", e),
+// Copies everything read back to the writer as it arrives, until EOF. The
+// writer is flushed after every read, since a buffered one like stdout
+// would otherwise hold back anything without a newline until the end.
+fn echo<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8192];
+    let mut copied = 0;
+
+    loop {
+        let bytes_read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..bytes_read])?;
+        writer.flush()?;
+        copied += bytes_read as u64;
+    }
+}
+
+// A client over the session quota is told why before being closed, since
+// otherwise it just sees its echo never arrive.
+fn handle_client(mut stream: TcpStream, quotas: &Arc<Quotas>) {
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("Rejecting client: {}", e);
+            let _ = writeln!(stream, "{}, try again later", e);
+            return;
+        }
+    };
+
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => quotas.wrap(reader),
+        Err(e) => {
+            eprintln!("Couldn't clone stream: {}", e);
+            return;
+        }
     };
+    let mut writer = quotas.wrap(stream);
 
-    match stream.write_all(&buf) {
-        Ok(_) => {}
-        Err(e) => eprintln!("Couldn't write to stream: {}", e),
+    match echo(&mut reader, &mut writer) {
+        Ok(m) => println!("Received: {}", m),
+        Err(e) => eprintln!("Couldn't echo stream: {}", e),
     }
 }
 
+// RFC 862 under inetd/systemd: the connection is already our stdin/stdout,
+// so echo it once and exit. Logging goes to stderr to keep stdout clean.
+fn handle_inetd() -> std::io::Result<()> {
+    let m = echo(&mut std::io::stdin().lock(), &mut std::io::stdout().lock())?;
+    eprintln!("Received: {}", m);
+
+    Ok(())
+}
+
 fn main() -> std::io::Result<()> {
+    if std::env::args().skip(1).any(|arg| arg == "--inetd") {
+        return handle_inetd();
+    }
+
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
     let listener = TcpListener::bind("0.0.0.0:7")?;
 
     for stream in listener.incoming() {
-        handle_client(&mut stream?);
+        match stream {
+            Ok(stream) => {
+                let quotas = quotas.clone();
+                thread::spawn(move || handle_client(stream, &quotas));
+            }
+            Err(e) => eprintln!("Failed to accept client: {}", e),
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::quota::Quota;
+    use std::cell::RefCell;
+    use std::io::{BufRead, BufReader, BufWriter};
+    use std::net::Shutdown;
+    use std::rc::Rc;
+
+    // Hands out one chunk per read, checking everything before it has been
+    // flushed through to `flushed` by then.
+    struct Chunks<'a> {
+        chunks: std::slice::Iter<'a, &'a [u8]>,
+        sent: Vec<u8>,
+        flushed: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for Chunks<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            assert_eq!(*self.flushed.borrow(), self.sent, "Echo was held back");
+            let Some(chunk) = self.chunks.next() else {
+                return Ok(0);
+            };
+            buf[..chunk.len()].copy_from_slice(chunk);
+            self.sent.extend_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn each_read_is_flushed_before_the_next() {
+        let flushed = Rc::new(RefCell::new(Vec::new()));
+        let chunks: &[&[u8]] = &[b"no newline", b" yet", b"\n"];
+        let mut reader = Chunks {
+            chunks: chunks.iter(),
+            sent: Vec::new(),
+            flushed: flushed.clone(),
+        };
+        let mut writer = BufWriter::new(Shared(flushed.clone()));
+
+        assert_eq!(echo(&mut reader, &mut writer).expect("Echo"), 15);
+        assert_eq!(*flushed.borrow(), b"no newline yet\n");
+    }
+
+    #[test]
+    fn clients_are_echoed_at_once_up_to_the_session_quota() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind listener");
+        let addr = listener.local_addr().unwrap();
+        let quotas = Quotas::new(Quota {
+            max_sessions: Some(2),
+            ..Quota::default()
+        });
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.expect("Couldn't accept client");
+                let quotas = quotas.clone();
+                thread::spawn(move || handle_client(stream, &quotas));
+            }
+        });
+
+        // Both sessions are served side by side, each still open while the
+        // other is echoed.
+        let connect = || {
+            let stream = TcpStream::connect(addr).expect("Couldn't connect");
+            stream
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            BufReader::new(stream)
+        };
+        let mut first = connect();
+        let mut second = connect();
+        for (client, line) in [(&mut first, "one\n"), (&mut second, "two\n")] {
+            client.get_mut().write_all(line.as_bytes()).unwrap();
+            let mut echoed = String::new();
+            client.read_line(&mut echoed).expect("Couldn't read echo");
+            assert_eq!(echoed, line);
+        }
+
+        let mut third = connect();
+        let mut rejected = String::new();
+        third
+            .read_to_string(&mut rejected)
+            .expect("Couldn't read rejection");
+        assert_eq!(rejected, "Too many sessions, try again later\n");
+
+        first.get_ref().shutdown(Shutdown::Both).unwrap();
+        second.get_ref().shutdown(Shutdown::Both).unwrap();
+    }
+}
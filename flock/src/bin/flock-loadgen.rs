@@ -0,0 +1,319 @@
+// Drives a flock server with simulated cameras and dispatchers and checks
+// that every expected ticket arrives, e.g.
+//
+//   flock-loadgen --cameras 20 --roads 5 --dispatchers 2 --rate 50 --duration 10
+//
+// Cameras are spread evenly over the roads, 10 miles apart with a limit of
+// 60. On every tick each camera reports the next car on its road, and each
+// car passes every camera on the road at 100 mph, so each one is owed
+// exactly one ticket. Dispatchers cover every road. Tickets for plates that
+// were never ticketable, or for a car that's already had one, fail the run.
+
+use flock::codec::{self, Framer, InboundMessage, OutboundMessage};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+// 10 miles at 100 mph.
+const SECONDS_BETWEEN_CAMERAS: u32 = 360;
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+const SETTLE_PERIOD: Duration = Duration::from_millis(500);
+const MAX_RATE: u32 = 1_000_000_000;
+
+#[derive(Debug, Clone)]
+struct Config {
+    addr: String,
+    cameras: u16,
+    roads: u16,
+    dispatchers: u16,
+    rate: u32,
+    duration: Duration,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            addr: "127.0.0.1:8080".to_string(),
+            cameras: 10,
+            roads: 5,
+            dispatchers: 1,
+            rate: 10,
+            duration: Duration::from_secs(10),
+        };
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Expected a value after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--addr" => config.addr = value()?,
+                "--cameras" => config.cameras = parse(&arg, value()?)?,
+                "--roads" => config.roads = parse(&arg, value()?)?,
+                "--dispatchers" => config.dispatchers = parse(&arg, value()?)?,
+                "--rate" => config.rate = parse(&arg, value()?)?,
+                "--duration" => config.duration = Duration::from_secs(parse(&arg, value()?)?),
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        if config.roads == 0 || config.cameras < config.roads * 2 {
+            return Err("Expected at least two cameras per road".to_string());
+        }
+
+        if config.rate == 0 || config.dispatchers == 0 {
+            return Err("Expected a non-zero --rate and --dispatchers".to_string());
+        }
+
+        // Cameras tick every 1/rate seconds, which has to be at least 1ns.
+        if config.rate > MAX_RATE {
+            return Err(format!("Expected a --rate of at most {}", MAX_RATE));
+        }
+
+        Ok(config)
+    }
+
+    fn cars_per_road(&self) -> u64 {
+        self.duration.as_secs() * self.rate as u64
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: String) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| format!("Invalid {}: {}", arg, e))
+}
+
+fn plate(road: u16, car: u64) -> String {
+    format!("R{}C{}", road, car)
+}
+
+// When each car's ticket first became possible: the first time a camera
+// past the first one on its road reported it.
+type Ticketable = Arc<Mutex<HashMap<String, Instant>>>;
+
+async fn run_camera(
+    config: Config,
+    road: u16,
+    position: u16,
+    ticketable: Ticketable,
+    sightings: Arc<AtomicU64>,
+) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(&config.addr).await?;
+    let camera = InboundMessage::IAmCamera {
+        road,
+        mile: position * 10,
+        limit: 60,
+    };
+    stream.write_all(&codec::encode_inbound(&camera)).await?;
+
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / config.rate);
+    for car in 0..config.cars_per_road() {
+        ticks.tick().await;
+
+        // Recorded before sending so the ticket can't beat it.
+        if position > 0 {
+            ticketable
+                .lock()
+                .expect("Couldn't obtain lock on ticketable cars")
+                .entry(plate(road, car))
+                .or_insert_with(Instant::now);
+        }
+
+        let message = InboundMessage::Plate {
+            plate: plate(road, car),
+            timestamp: car as u32 + position as u32 * SECONDS_BETWEEN_CAMERAS,
+        };
+        stream.write_all(&codec::encode_inbound(&message)).await?;
+        sightings.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+// A ticket as a dispatcher saw it: how long after its car became ticketable
+// it arrived, or None if the car never did.
+type Arrival = (String, Option<Duration>);
+
+async fn run_dispatcher(
+    config: Config,
+    ticketable: Ticketable,
+    arrivals: UnboundedSender<Arrival>,
+) -> std::io::Result<()> {
+    let mut stream = TcpStream::connect(&config.addr).await?;
+    let dispatcher = InboundMessage::IAmDispatcher {
+        roads: (0..config.roads).collect(),
+    };
+    stream
+        .write_all(&codec::encode_inbound(&dispatcher))
+        .await?;
+
+    let mut framer = Framer::default();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match framer.next_message(codec::decode_outbound)? {
+            Some(OutboundMessage::Ticket(ticket)) => {
+                let latency = ticketable
+                    .lock()
+                    .expect("Couldn't obtain lock on ticketable cars")
+                    .get(&ticket.plate)
+                    .map(|at| at.elapsed());
+
+                if latency.is_none() {
+                    eprintln!("Unexpected ticket: {:?}", ticket);
+                }
+                let _ = arrivals.send((ticket.plate, latency));
+            }
+            Some(OutboundMessage::Error { msg }) => {
+                return Err(std::io::Error::other(msg));
+            }
+            Some(_) => {}
+            None => {
+                let bytes_read = stream.read(&mut chunk).await?;
+                if bytes_read == 0 {
+                    return Ok(());
+                }
+                framer.push(&chunk[..bytes_read]);
+            }
+        }
+    }
+}
+
+// The first ticket for each ticketable car, and counts of any others.
+#[derive(Debug, Default)]
+struct Tally {
+    received: HashMap<String, Duration>,
+    unexpected: u64,
+    duplicates: u64,
+}
+
+impl Tally {
+    fn record(&mut self, (plate, latency): Arrival) {
+        match latency {
+            None => self.unexpected += 1,
+            Some(_) if self.received.contains_key(&plate) => {
+                eprintln!("Duplicate ticket for {}", plate);
+                self.duplicates += 1;
+            }
+            Some(latency) => {
+                self.received.insert(plate, latency);
+            }
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = Config::from_args().map_err(std::io::Error::other)?;
+    let ticketable: Ticketable = Arc::default();
+    let sightings = Arc::new(AtomicU64::new(0));
+    let (arrivals_tx, mut arrivals_rx) = mpsc::unbounded_channel();
+
+    for _ in 0..config.dispatchers {
+        let config = config.clone();
+        let ticketable = ticketable.clone();
+        let arrivals_tx = arrivals_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_dispatcher(config, ticketable, arrivals_tx).await {
+                eprintln!("Dispatcher failed: {}", e);
+            }
+        });
+    }
+    drop(arrivals_tx);
+
+    let started = Instant::now();
+    let mut cameras = Vec::new();
+    for camera in 0..config.cameras {
+        let road = camera % config.roads;
+        let position = camera / config.roads;
+        let camera = run_camera(
+            config.clone(),
+            road,
+            position,
+            ticketable.clone(),
+            sightings.clone(),
+        );
+        cameras.push(tokio::spawn(camera));
+    }
+
+    for camera in cameras {
+        if let Err(e) = camera.await.expect("Camera task panicked") {
+            eprintln!("Camera failed: {}", e);
+        }
+    }
+    let sending = started.elapsed();
+
+    let expected = config.roads as u64 * config.cars_per_road();
+    let mut tally = Tally::default();
+    let deadline = tokio::time::sleep(GRACE_PERIOD);
+    tokio::pin!(deadline);
+    let mut settling = false;
+
+    // Once every ticket is in, wait a little longer so a duplicate of one of
+    // the last few is still counted.
+    loop {
+        if !settling && tally.received.len() as u64 >= expected {
+            settling = true;
+            deadline
+                .as_mut()
+                .reset(tokio::time::Instant::now() + SETTLE_PERIOD);
+        }
+
+        tokio::select! {
+            arrival = arrivals_rx.recv() => match arrival {
+                Some(arrival) => tally.record(arrival),
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+
+    let sent = sightings.load(Ordering::Relaxed);
+    println!(
+        "Sent {} sightings in {:.2?} ({:.0}/s)",
+        sent,
+        sending,
+        sent as f64 / sending.as_secs_f64()
+    );
+    println!(
+        "Received {}/{} tickets, {} unexpected, {} duplicate",
+        tally.received.len(),
+        expected,
+        tally.unexpected,
+        tally.duplicates
+    );
+
+    let mut latencies: Vec<_> = tally.received.into_values().collect();
+    if !latencies.is_empty() {
+        latencies.sort();
+        println!(
+            "Latency min {:.2?} p50 {:.2?} p99 {:.2?} max {:.2?}",
+            latencies[0],
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1]
+        );
+    }
+
+    if (latencies.len() as u64) < expected {
+        return Err(std::io::Error::other("Some tickets never arrived"));
+    }
+
+    if tally.unexpected > 0 || tally.duplicates > 0 {
+        return Err(std::io::Error::other("Got tickets that weren't owed"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,381 @@
+// The Speed Daemon wire format. Inbound messages are the ones clients send
+// the server, outbound the ones the server sends back. Every decoder takes
+// the front of a buffer and returns the message along with the number of
+// bytes it took up, or None if the message isn't complete yet; malformed or
+// out-of-bounds fields are an InvalidData error.
+
+use serde::Serialize;
+
+pub const MAX_PLATE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    pub speed: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InboundMessage {
+    Plate { plate: String, timestamp: u32 },
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+    // Observer extension.
+    IAmObserver,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutboundMessage {
+    Error { msg: String },
+    Ticket(Ticket),
+    Heartbeat,
+    // Observer extension: a free-form note about a client coming or going.
+    Notice { text: String },
+}
+
+// A decoded message and the number of bytes it took up.
+pub type Decoded<M> = std::io::Result<Option<(M, usize)>>;
+
+pub fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+// Strings longer than their length byte allows are cut short.
+fn push_str(bytes: &mut Vec<u8>, s: &str) {
+    let s = &s.as_bytes()[..s.len().min(255)];
+    bytes.push(s.len() as u8);
+    bytes.extend_from_slice(s);
+}
+
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([buf[i], buf[i + 1]])
+}
+
+fn u32_at(buf: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]])
+}
+
+// The length of a message that's a type byte, a length-prefixed field of
+// `item_size`-byte items and `trailer` more bytes, once the prefix is in.
+fn prefixed_len(buf: &[u8], item_size: usize, trailer: usize) -> Option<usize> {
+    buf.get(1).map(|&n| 2 + n as usize * item_size + trailer)
+}
+
+// Plates are short runs of ASCII letters and digits. Anything else is
+// rejected before it can reach the sightings.
+fn decode_plate(bytes: &[u8]) -> std::io::Result<String> {
+    if bytes.is_empty() || bytes.len() > MAX_PLATE_LEN {
+        return Err(invalid_data("Plate length out of bounds"));
+    }
+
+    if !bytes.iter().all(u8::is_ascii_alphanumeric) {
+        return Err(invalid_data("Plate must be ASCII alphanumeric"));
+    }
+
+    Ok(bytes.iter().map(|&b| b as char).collect())
+}
+
+fn decode_str(bytes: &[u8]) -> std::io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid_data("String must be utf8"))
+}
+
+pub fn encode_inbound(message: &InboundMessage) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    match message {
+        InboundMessage::Plate { plate, timestamp } => {
+            bytes.push(0x20);
+            push_str(&mut bytes, plate);
+            bytes.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        InboundMessage::WantHeartbeat { interval } => {
+            bytes.push(0x40);
+            bytes.extend_from_slice(&interval.to_be_bytes());
+        }
+        InboundMessage::IAmCamera { road, mile, limit } => {
+            bytes.push(0x80);
+            bytes.extend_from_slice(&road.to_be_bytes());
+            bytes.extend_from_slice(&mile.to_be_bytes());
+            bytes.extend_from_slice(&limit.to_be_bytes());
+        }
+        InboundMessage::IAmDispatcher { roads } => {
+            bytes.push(0x81);
+            bytes.push(roads.len() as u8);
+            for road in roads {
+                bytes.extend_from_slice(&road.to_be_bytes());
+            }
+        }
+        InboundMessage::IAmObserver => bytes.push(0x82),
+    }
+
+    bytes
+}
+
+pub fn decode_inbound(buf: &[u8]) -> Decoded<InboundMessage> {
+    let Some(&message_type) = buf.first() else {
+        return Ok(None);
+    };
+
+    let len = match message_type {
+        0x20 => prefixed_len(buf, 1, 4),
+        0x40 => Some(5),
+        0x80 => Some(7),
+        0x81 => prefixed_len(buf, 2, 0),
+        0x82 => Some(1),
+        _ => return Err(invalid_data("Illegal message type")),
+    };
+
+    let Some(len) = len.filter(|&len| buf.len() >= len) else {
+        return Ok(None);
+    };
+
+    let message = match message_type {
+        0x20 => InboundMessage::Plate {
+            plate: decode_plate(&buf[2..len - 4])?,
+            timestamp: u32_at(buf, len - 4),
+        },
+        0x40 => InboundMessage::WantHeartbeat {
+            interval: u32_at(buf, 1),
+        },
+        0x80 if u16_at(buf, 5) == 0 => return Err(invalid_data("Speed limit must be positive")),
+        0x80 => InboundMessage::IAmCamera {
+            road: u16_at(buf, 1),
+            mile: u16_at(buf, 3),
+            limit: u16_at(buf, 5),
+        },
+        0x82 => InboundMessage::IAmObserver,
+        _ if len == 2 => return Err(invalid_data("Dispatcher must cover a road")),
+        _ => InboundMessage::IAmDispatcher {
+            roads: (2..len).step_by(2).map(|i| u16_at(buf, i)).collect(),
+        },
+    };
+
+    Ok(Some((message, len)))
+}
+
+pub fn encode_ticket(ticket: &Ticket) -> Vec<u8> {
+    let mut bytes = vec![0x21];
+    push_str(&mut bytes, &ticket.plate);
+    bytes.extend_from_slice(&ticket.road.to_be_bytes());
+    bytes.extend_from_slice(&ticket.mile1.to_be_bytes());
+    bytes.extend_from_slice(&ticket.timestamp1.to_be_bytes());
+    bytes.extend_from_slice(&ticket.mile2.to_be_bytes());
+    bytes.extend_from_slice(&ticket.timestamp2.to_be_bytes());
+    bytes.extend_from_slice(&ticket.speed.to_be_bytes());
+    bytes
+}
+
+pub fn encode_outbound(message: &OutboundMessage) -> Vec<u8> {
+    match message {
+        OutboundMessage::Error { msg } => {
+            let mut bytes = vec![0x10];
+            push_str(&mut bytes, msg);
+            bytes
+        }
+        OutboundMessage::Ticket(ticket) => encode_ticket(ticket),
+        OutboundMessage::Heartbeat => vec![0x41],
+        OutboundMessage::Notice { text } => {
+            let mut bytes = vec![0x90];
+            push_str(&mut bytes, text);
+            bytes
+        }
+    }
+}
+
+pub fn decode_outbound(buf: &[u8]) -> Decoded<OutboundMessage> {
+    let Some(&message_type) = buf.first() else {
+        return Ok(None);
+    };
+
+    let len = match message_type {
+        0x10 | 0x90 => prefixed_len(buf, 1, 0),
+        0x21 => prefixed_len(buf, 1, 16),
+        0x41 => Some(1),
+        _ => return Err(invalid_data("Illegal message type")),
+    };
+
+    let Some(len) = len.filter(|&len| buf.len() >= len) else {
+        return Ok(None);
+    };
+
+    let message = match message_type {
+        0x10 => OutboundMessage::Error {
+            msg: decode_str(&buf[2..len])?,
+        },
+        0x90 => OutboundMessage::Notice {
+            text: decode_str(&buf[2..len])?,
+        },
+        0x21 => {
+            let rest = &buf[len - 16..len];
+            OutboundMessage::Ticket(Ticket {
+                plate: decode_str(&buf[2..len - 16])?,
+                road: u16_at(rest, 0),
+                mile1: u16_at(rest, 2),
+                timestamp1: u32_at(rest, 4),
+                mile2: u16_at(rest, 8),
+                timestamp2: u32_at(rest, 10),
+                speed: u16_at(rest, 14),
+            })
+        }
+        _ => OutboundMessage::Heartbeat,
+    };
+
+    Ok(Some((message, len)))
+}
+
+// Accumulates whatever the socket hands over, however it's split up or
+// coalesced, and only decodes a message once all of its bytes have arrived.
+// `decode` is `decode_inbound` on the server and `decode_outbound` on a
+// client.
+#[derive(Debug, Default)]
+pub struct Framer {
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn next_message<M>(
+        &mut self,
+        decode: fn(&[u8]) -> Decoded<M>,
+    ) -> std::io::Result<Option<M>> {
+        let Some((message, len)) = decode(&self.buf)? else {
+            return Ok(None);
+        };
+
+        self.buf.drain(..len);
+        Ok(Some(message))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn inbound_message() -> impl Strategy<Value = InboundMessage> {
+        prop_oneof![
+            ("[A-Z0-9]{1,16}", any::<u32>())
+                .prop_map(|(plate, timestamp)| InboundMessage::Plate { plate, timestamp }),
+            any::<u32>().prop_map(|interval| InboundMessage::WantHeartbeat { interval }),
+            (any::<u16>(), any::<u16>(), 1..=u16::MAX)
+                .prop_map(|(road, mile, limit)| InboundMessage::IAmCamera { road, mile, limit }),
+            vec(any::<u16>(), 1..=255).prop_map(|roads| InboundMessage::IAmDispatcher { roads }),
+            Just(InboundMessage::IAmObserver),
+        ]
+    }
+
+    fn ticket() -> impl Strategy<Value = Ticket> {
+        (
+            "[A-Z0-9]{0,255}",
+            any::<u16>(),
+            any::<u16>(),
+            any::<u32>(),
+            any::<u16>(),
+            any::<u32>(),
+            any::<u16>(),
+        )
+            .prop_map(
+                |(plate, road, mile1, timestamp1, mile2, timestamp2, speed)| Ticket {
+                    plate,
+                    road,
+                    mile1,
+                    timestamp1,
+                    mile2,
+                    timestamp2,
+                    speed,
+                },
+            )
+    }
+
+    fn outbound_message() -> impl Strategy<Value = OutboundMessage> {
+        prop_oneof![
+            "[ -~]{0,255}".prop_map(|msg| OutboundMessage::Error { msg }),
+            ticket().prop_map(OutboundMessage::Ticket),
+            Just(OutboundMessage::Heartbeat),
+            "[ -~]{0,255}".prop_map(|text| OutboundMessage::Notice { text }),
+        ]
+    }
+
+    // Only a decode that used up every byte counts as a round trip.
+    fn decoded<M>(decoded: Decoded<M>) -> Option<(M, usize)> {
+        decoded.ok().flatten()
+    }
+
+    proptest! {
+        #[test]
+        fn a_partial_inbound_message_is_incomplete(message in inbound_message()) {
+            let bytes = encode_inbound(&message);
+            for end in 0..bytes.len() {
+                prop_assert!(matches!(decode_inbound(&bytes[..end]), Ok(None)));
+            }
+        }
+
+        #[test]
+        fn a_partial_outbound_message_is_incomplete(message in outbound_message()) {
+            let bytes = encode_outbound(&message);
+            for end in 0..bytes.len() {
+                prop_assert!(matches!(decode_outbound(&bytes[..end]), Ok(None)));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        for message in [
+            InboundMessage::Plate {
+                plate: String::new(),
+                timestamp: 0,
+            },
+            InboundMessage::Plate {
+                plate: "UN-X".to_string(),
+                timestamp: 0,
+            },
+            InboundMessage::Plate {
+                plate: "A".repeat(MAX_PLATE_LEN + 1),
+                timestamp: 0,
+            },
+            InboundMessage::IAmCamera {
+                road: 1,
+                mile: 1,
+                limit: 0,
+            },
+            InboundMessage::IAmDispatcher { roads: Vec::new() },
+        ] {
+            let bytes = encode_inbound(&message);
+            assert!(decode_inbound(&bytes).is_err(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn unknown_message_types_are_rejected() {
+        assert!(decode_inbound(&[0x21]).is_err());
+        assert!(decode_outbound(&[0x20]).is_err());
+    }
+
+    common::roundtrip_tests!(
+        inbound_message_roundtrip,
+        inbound_message(),
+        encode_inbound,
+        |bytes: &[u8]| decoded(decode_inbound(bytes)),
+    );
+
+    common::roundtrip_tests!(
+        outbound_message_roundtrip,
+        outbound_message(),
+        encode_outbound,
+        |bytes: &[u8]| decoded(decode_outbound(bytes)),
+    );
+}
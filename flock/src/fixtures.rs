@@ -0,0 +1,229 @@
+// Worked examples from the Speed Daemon spec, as raw bytes, replayed
+// against a server on a local port. Each camera is the sequence of messages
+// that client sends after connecting, the dispatcher is what a dispatcher
+// for the road sends, and tickets are the exact bytes it should receive.
+
+use super::*;
+use common::quota::Quota;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Fixture {
+    name: &'static str,
+    dispatcher: &'static [u8],
+    cameras: &'static [&'static [&'static [u8]]],
+    tickets: &'static [&'static [u8]],
+}
+
+const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "example session",
+        // IAmDispatcher{roads: [123]}
+        dispatcher: &[0x81, 0x01, 0x00, 0x7b],
+        cameras: &[
+            &[
+                // IAmCamera{road: 123, mile: 8, limit: 60}
+                &[0x80, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x3c],
+                // Plate{"UN1X", 0}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00],
+            ],
+            &[
+                // IAmCamera{road: 123, mile: 9, limit: 60}
+                &[0x80, 0x00, 0x7b, 0x00, 0x09, 0x00, 0x3c],
+                // Plate{"UN1X", 45}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x2d],
+            ],
+        ],
+        tickets: &[
+            // Ticket{plate: "UN1X", road: 123, mile1: 8, timestamp1: 0, mile2: 9, timestamp2: 45, speed: 8000}
+            &[
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x09, 0x00, 0x00, 0x00, 0x2d, 0x1f, 0x40,
+            ],
+        ],
+    },
+    Fixture {
+        name: "ticket example UN1X",
+        // IAmDispatcher{roads: [66]}
+        dispatcher: &[0x81, 0x01, 0x00, 0x42],
+        cameras: &[
+            &[
+                // IAmCamera{road: 66, mile: 100, limit: 60}
+                &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c],
+                // Plate{"UN1X", 123456}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x01, 0xe2, 0x40],
+            ],
+            &[
+                // IAmCamera{road: 66, mile: 110, limit: 60}
+                &[0x80, 0x00, 0x42, 0x00, 0x6e, 0x00, 0x3c],
+                // Plate{"UN1X", 123816}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x01, 0xe3, 0xa8],
+            ],
+        ],
+        tickets: &[
+            // Ticket{plate: "UN1X", road: 66, mile1: 100, timestamp1: 123456, mile2: 110, timestamp2: 123816, speed: 10000}
+            &[
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+            ],
+        ],
+    },
+    Fixture {
+        name: "ticket example RE05BKG",
+        // IAmDispatcher{roads: [368]}
+        dispatcher: &[0x81, 0x01, 0x01, 0x70],
+        cameras: &[
+            &[
+                // IAmCamera{road: 368, mile: 1234, limit: 40}
+                &[0x80, 0x01, 0x70, 0x04, 0xd2, 0x00, 0x28],
+                // Plate{"RE05BKG", 1000000}
+                &[
+                    0x20, 0x07, 0x52, 0x45, 0x30, 0x35, 0x42, 0x4b, 0x47, 0x00, 0x0f, 0x42, 0x40,
+                ],
+            ],
+            &[
+                // IAmCamera{road: 368, mile: 1235, limit: 40}
+                &[0x80, 0x01, 0x70, 0x04, 0xd3, 0x00, 0x28],
+                // Plate{"RE05BKG", 1000060}
+                &[
+                    0x20, 0x07, 0x52, 0x45, 0x30, 0x35, 0x42, 0x4b, 0x47, 0x00, 0x0f, 0x42, 0x7c,
+                ],
+            ],
+        ],
+        tickets: &[
+            // Ticket{plate: "RE05BKG", road: 368, mile1: 1234, timestamp1: 1000000, mile2: 1235, timestamp2: 1000060, speed: 6000}
+            &[
+                0x21, 0x07, 0x52, 0x45, 0x30, 0x35, 0x42, 0x4b, 0x47, 0x01, 0x70, 0x04, 0xd2, 0x00,
+                0x0f, 0x42, 0x40, 0x04, 0xd3, 0x00, 0x0f, 0x42, 0x7c, 0x17, 0x70,
+            ],
+        ],
+    },
+    Fixture {
+        name: "under the limit",
+        // IAmDispatcher{roads: [66]}
+        dispatcher: &[0x81, 0x01, 0x00, 0x42],
+        cameras: &[
+            &[
+                // IAmCamera{road: 66, mile: 100, limit: 60}
+                &[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c],
+                // Plate{"UN1X", 0}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00],
+            ],
+            &[
+                // IAmCamera{road: 66, mile: 101, limit: 60}
+                &[0x80, 0x00, 0x42, 0x00, 0x65, 0x00, 0x3c],
+                // Plate{"UN1X", 60}
+                &[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x3c],
+            ],
+        ],
+        tickets: &[],
+    },
+];
+
+// Accepts clients on a local port the way main does, each with its own
+// handler feeding one state task, and returns the address and the state
+// task's sender.
+async fn serve() -> (std::net::SocketAddr, UnboundedSender<Command>) {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Couldn't bind listener");
+    let addr = listener.local_addr().expect("Couldn't read local address");
+    let (state_tx, state_rx) = mpsc::unbounded_channel();
+    tokio::spawn(run_state(FlockState::default(), state_rx));
+
+    let quotas = Quotas::new(Quota::default());
+    let config = Config {
+        capabilities: Capabilities::strict(protocol::FLOCK),
+        addr: addr.to_string(),
+        horizon: None,
+        admin: None,
+        tickets: None,
+        audit: None,
+        idle_timeout: None,
+    };
+    let state = state_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.expect("Couldn't accept");
+            let permit = quotas.open_session().expect("Within the session quota");
+            tokio::spawn(handle_client(
+                stream,
+                permit,
+                state.clone(),
+                quotas.clone(),
+                config.clone(),
+            ));
+        }
+    });
+
+    (addr, state_tx)
+}
+
+// Returns everything the fixture's dispatcher received.
+async fn run(fixture: &Fixture) -> Vec<u8> {
+    let (addr, state) = serve().await;
+    let mut dispatcher = TcpStream::connect(addr).await.expect("Couldn't connect");
+    dispatcher
+        .write_all(fixture.dispatcher)
+        .await
+        .expect("Couldn't identify the dispatcher");
+
+    for camera in fixture.cameras {
+        let mut stream = TcpStream::connect(addr).await.expect("Couldn't connect");
+        stream
+            .write_all(&camera.concat())
+            .await
+            .expect("Couldn't send the camera's messages");
+        stream.shutdown().await.expect("Couldn't disconnect");
+
+        // The server only hangs up once this camera's sightings are on
+        // their way to the state task, and it has nothing to say to a
+        // camera that behaves.
+        let mut reply = Vec::new();
+        tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut reply))
+            .await
+            .expect("The camera was never disconnected")
+            .expect("Couldn't read from flock");
+        assert!(reply.is_empty(), "{}: camera got {:?}", fixture.name, reply);
+    }
+
+    // Commands are handled in order, so once a dump comes back every
+    // sighting has been checked and its tickets queued.
+    let (reply, dump) = oneshot::channel();
+    state
+        .send(Command::Dump { reply })
+        .expect("State task should be running");
+    dump.await.expect("State task should answer");
+
+    let expected_len = fixture.tickets.iter().map(|ticket| ticket.len()).sum();
+    let mut received = vec![0; expected_len];
+    tokio::time::timeout(TIMEOUT, dispatcher.read_exact(&mut received))
+        .await
+        .expect("Tickets never arrived")
+        .expect("Couldn't read from flock");
+
+    // Anything after the expected tickets would have been written by now.
+    let mut extra = Vec::new();
+    let more =
+        tokio::time::timeout(Duration::from_millis(100), dispatcher.read_buf(&mut extra)).await;
+    assert!(
+        more.is_err(),
+        "{}: dispatcher also got {:?}",
+        fixture.name,
+        extra
+    );
+
+    received
+}
+
+#[tokio::test]
+async fn spec_examples_produce_expected_ticket_bytes() {
+    for fixture in FIXTURES {
+        assert_eq!(
+            run(fixture).await,
+            fixture.tickets.concat(),
+            "fixture: {}",
+            fixture.name
+        );
+    }
+}
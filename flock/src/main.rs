@@ -1,72 +1,139 @@
+use common::protocol::{self, Capabilities};
+use common::quota::{Quotas, SessionPermit};
+use flock::codec::{self, Framer, InboundMessage, OutboundMessage, Ticket};
+use serde::Serialize;
+use serde_json::json;
 use std::collections::{HashMap, HashSet};
-use std::io::{BufReader, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use std::thread;
-use uuid::Uuid;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+#[cfg(test)]
+mod fixtures;
 
 const LOCAL_ADDR: &str = "0.0.0.0:8080";
+const PRUNE_EVERY: usize = 1024;
+// How much can be queued for one client before it's considered slow.
+const OUTBOUND_QUEUE: usize = 1024;
+// How often tickets that found no room are offered to their road again.
+const RETRY_PENDING: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+struct Config {
+    capabilities: Capabilities,
+    addr: String,
+    // How far behind the latest timestamp a sighting is kept, in seconds.
+    // Unset keeps every sighting.
+    horizon: Option<u32>,
+    // Where to serve JSON dumps of the server's state, if anywhere.
+    admin: Option<String>,
+    tickets: Option<PathBuf>,
+    // Where to append a JSON line for every ticket issued or suppressed.
+    audit: Option<PathBuf>,
+    // How long a client may stay silent before identifying itself. Once
+    // identified, dispatchers and cameras can legitimately go quiet.
+    idle_timeout: Option<Duration>,
+}
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-struct Ticket {
-    plate: String,
-    road: u16,
-    mile1: u16,
-    timestamp1: u32,
-    mile2: u16,
-    timestamp2: u32,
-    speed: u16,
-}
-
-impl Ticket {
-    fn write(self, stream: &mut TcpStream) -> std::io::Result<()> {
-        let plate_bytes = self.plate.as_bytes();
-        let plate_len = plate_bytes.len() as u8;
-
-        stream.write_all(&[0x21])?;
-        stream.write_all(&[plate_len])?;
-        stream.write_all(plate_bytes)?;
-        stream.write_all(&self.road.to_be_bytes())?;
-        stream.write_all(&self.mile1.to_be_bytes())?;
-        stream.write_all(&self.timestamp1.to_be_bytes())?;
-        stream.write_all(&self.mile2.to_be_bytes())?;
-        stream.write_all(&self.timestamp2.to_be_bytes())?;
-        stream.write_all(&self.speed.to_be_bytes())?;
-
-        stream.flush()?;
-        Ok(())
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            capabilities: Capabilities::strict(protocol::FLOCK),
+            addr: LOCAL_ADDR.to_string(),
+            horizon: None,
+            admin: None,
+            tickets: None,
+            audit: None,
+            idle_timeout: None,
+        };
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if config.capabilities.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Expected a value after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--bind" => config.addr = value()?,
+                "--horizon" => {
+                    let secs = value()?
+                        .parse()
+                        .map_err(|e| format!("Invalid --horizon: {}", e))?;
+                    config.horizon = Some(secs);
+                }
+                "--admin" => config.admin = Some(value()?),
+                "--tickets" => config.tickets = Some(PathBuf::from(value()?)),
+                "--audit" => config.audit = Some(PathBuf::from(value()?)),
+                "--idle-timeout" => {
+                    let secs = value()?
+                        .parse()
+                        .map_err(|e| format!("Invalid --idle-timeout: {}", e))?;
+                    config.idle_timeout = Some(Duration::from_secs(secs));
+                }
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        Ok(config)
     }
 }
 
+// What a connection has identified itself as. Strict mode only ever has one
+// camera location; with camera-relocation enabled a mobile camera replaces it
+// on each IAmCamera, and its plates are recorded at the latest one.
 #[derive(Debug)]
-enum InboundMessage {
-    Plate { plate: String, timestamp: u32 },
-    WantHeartbeat { interval: u32 },
-    IAmCamera { road: u16, mile: u16, limit: u16 },
-    IAmDispatcher { roads: Vec<u16> },
+enum ClientInfo {
+    CameraInfo { location: CameraLocation },
+    DispatcherInfo,
+    ObserverInfo,
+    Unknown,
 }
 
-#[derive(Debug)]
-enum ClientType {
-    Camera,
-    Dispatcher,
-    Unknown,
+#[derive(Debug, Clone, Copy)]
+struct CameraLocation {
+    road: u16,
+    mile: u16,
+    limit: u16,
 }
 
-#[derive(Debug)]
-enum ClientInfo {
-    CameraInfo { road: u16, mile: u16, limit: u16 },
-    DispatcherInfo { roads: Vec<u16>, stream: TcpStream },
-    Unknown,
+// Handed out in order as clients connect and never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+struct ClientId(u64);
+
+impl ClientId {
+    fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ClientId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
-#[derive(Debug)]
-struct Sighting {
-    client_id: Uuid,
-    plate: String,
-    timestamp: u32,
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
+// Where and under what limit a plate was seen. Resolved from the camera's
+// location when the plate arrives, so moving the camera later doesn't move
+// its past sightings.
+#[derive(Debug, Clone, Copy)]
 struct SightingDetails {
     road: u16,
     mile: u16,
@@ -74,403 +141,1504 @@ struct SightingDetails {
     timestamp: u32,
 }
 
+// What the state task knows about each identified client.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Registration {
+    Camera { road: u16, mile: u16, limit: u16 },
+    Dispatcher { roads: Vec<u16> },
+    Observer,
+}
+
+// Queued for a client's writer task. Tickets are kept as tickets until
+// they're written, so one the writer couldn't deliver can be handed back.
 #[derive(Debug)]
-struct FlockState {
-    client_registry: HashMap<Uuid, (ClientType, ClientInfo)>,
-    traffic_log: Vec<Sighting>,
+enum Outbound {
+    Frame(Vec<u8>),
+    Ticket(Ticket),
 }
 
-impl FlockState {
-    fn new() -> Self {
-        let client_registry = HashMap::new();
-        let traffic_log = Vec::new();
+impl Outbound {
+    fn error(msg: &str) -> Self {
+        Outbound::Frame(codec::encode_outbound(&OutboundMessage::Error {
+            msg: msg.to_string(),
+        }))
+    }
+}
 
-        FlockState {
-            client_registry,
-            traffic_log,
+// Where a client is with WantHeartbeat. It may be sent before the client
+// identifies itself, but only once, and an interval of 0 turns heartbeats
+// off for good.
+#[derive(Debug)]
+enum HeartbeatState {
+    None,
+    Disabled,
+    Active(JoinHandle<()>),
+}
+
+impl HeartbeatState {
+    fn stop(&mut self) {
+        if let HeartbeatState::Active(heartbeat) = self {
+            heartbeat.abort();
         }
     }
 }
 
-fn send_error(stream: &mut TcpStream, msg: &str) -> std::io::Result<()> {
-    stream.write_all(&[0x10])?;
-    stream.write_all(&[msg.len() as u8])?;
-    stream.write_all(msg.as_bytes())?;
-    stream.flush()?;
-    Ok(())
+// Per-connection state that outlives a single message. Everything written to
+// the client goes through `outbound` to its writer task.
+#[derive(Debug)]
+struct Connection {
+    client_id: ClientId,
+    info: ClientInfo,
+    outbound: Sender<Outbound>,
+    heartbeat: HeartbeatState,
 }
 
-fn read_message(reader: &mut BufReader<TcpStream>) -> std::io::Result<Option<InboundMessage>> {
-    let mut message_type = [0u8; 1];
-    let bytes_read = reader.read(&mut message_type)?;
+// Sent to the state task by the connections.
+#[derive(Debug)]
+enum Command {
+    Sighting {
+        plate: String,
+        sighting: SightingDetails,
+    },
+    Dispatcher {
+        client_id: ClientId,
+        roads: Vec<u16>,
+        outbound: Sender<Outbound>,
+    },
+    Camera {
+        client_id: ClientId,
+        location: CameraLocation,
+    },
+    Observer {
+        client_id: ClientId,
+        outbound: Sender<Outbound>,
+    },
+    Disconnect {
+        client_id: ClientId,
+    },
+    // A dispatcher's connection failed with these tickets still unwritten.
+    Undelivered {
+        client_id: ClientId,
+        tickets: Vec<Ticket>,
+    },
+    Dump {
+        reply: oneshot::Sender<serde_json::Value>,
+    },
+}
+
+// Sightings are indexed by (plate, road) and kept sorted by timestamp, so a
+// new sighting only has to be compared with its neighbours.
+type SightingIndex = HashMap<(String, u16), Vec<SightingDetails>>;
+
+// Running totals since startup, to tell whether the server is keeping up.
+#[derive(Debug, Default, Serialize)]
+struct Counters {
+    sightings: u64,
+    tickets_generated: u64,
+    tickets_delivered: u64,
+    // Dropped as duplicates, or because the plate was already ticketed for
+    // one of its days.
+    tickets_suppressed: u64,
+    tickets_requeued: u64,
+    cameras_with_conflicting_limits: u64,
+}
+
+// Owned by the state task alone, so none of it needs a lock.
+#[derive(Debug, Default)]
+struct FlockState {
+    counters: Counters,
+    clients: HashMap<ClientId, Registration>,
+    sightings: SightingIndex,
+    dispatchers: HashMap<u16, RoadDispatchers>,
+    // Tickets wait here until a dispatcher for their road is connected.
+    pending: Vec<Ticket>,
+    // Whether the last dispatch left a ticket behind because every
+    // dispatcher for its road had a full queue. Only then is it worth
+    // retrying before the next command arrives.
+    queues_full: bool,
+    tickets: HashSet<Ticket>,
+    issued_days: IssuedDays,
+    // The limit first reported for each road. A camera that disagrees is
+    // flagged, and its sightings are held to this limit so the road's
+    // tickets stay consistent.
+    limits: HashMap<u16, u16>,
+    // Sent a copy of every delivered ticket, plus a notice as clients
+    // register and disconnect.
+    observers: HashMap<ClientId, Sender<Outbound>>,
+    ticket_log: Option<BufWriter<File>>,
+    audit_log: Option<BufWriter<File>>,
+    horizon: Option<u32>,
+    latest: u32,
+    since_prune: usize,
+}
+
+impl FlockState {
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Sighting { plate, sighting } => {
+                let limit = *self.limits.entry(sighting.road).or_insert(sighting.limit);
+                let sighting = SightingDetails { limit, ..sighting };
+                self.latest = self.latest.max(sighting.timestamp);
+                let tickets = record_sighting(&mut self.sightings, plate, sighting);
+                self.counters.sightings += 1;
+                self.counters.tickets_generated += tickets.len() as u64;
+                self.pending.extend(tickets);
+
+                self.since_prune += 1;
+            }
+            Command::Dispatcher {
+                client_id,
+                roads,
+                outbound,
+            } => {
+                self.notify(&format!("dispatcher {} for roads {:?}", client_id, roads));
+                self.clients.insert(
+                    client_id,
+                    Registration::Dispatcher {
+                        roads: roads.clone(),
+                    },
+                );
+                for road in roads {
+                    self.dispatchers
+                        .entry(road)
+                        .or_default()
+                        .handles
+                        .push(DispatcherHandle {
+                            client_id,
+                            outbound: outbound.clone(),
+                        });
+                }
+            }
+            Command::Camera {
+                client_id,
+                location,
+            } => {
+                self.notify(&format!(
+                    "camera {} on road {} at mile {} limit {}",
+                    client_id, location.road, location.mile, location.limit
+                ));
+
+                let limit = *self.limits.entry(location.road).or_insert(location.limit);
+                if limit != location.limit {
+                    let warning = format!(
+                        "camera {} reports limit {} for road {}, which has limit {}",
+                        client_id, location.limit, location.road, limit
+                    );
+                    eprintln!("Conflicting limit: {}", warning);
+                    self.notify(&warning);
+                    self.counters.cameras_with_conflicting_limits += 1;
+                }
+
+                self.clients.insert(
+                    client_id,
+                    Registration::Camera {
+                        road: location.road,
+                        mile: location.mile,
+                        limit: location.limit,
+                    },
+                );
+                return;
+            }
+            Command::Observer {
+                client_id,
+                outbound,
+            } => {
+                self.clients.insert(client_id, Registration::Observer);
+                self.observers.insert(client_id, outbound);
+                return;
+            }
+            Command::Dump { reply } => {
+                let _ = reply.send(self.dump());
+                return;
+            }
+            Command::Disconnect { client_id } => {
+                self.clients.remove(&client_id);
+                self.evict(client_id);
+                self.observers.remove(&client_id);
+                self.notify(&format!("{} disconnected", client_id));
+                return;
+            }
+            Command::Undelivered { client_id, tickets } => {
+                self.evict(client_id);
+                self.counters.tickets_requeued += tickets.len() as u64;
+                for ticket in tickets {
+                    self.tickets.remove(&ticket);
+                    self.issued_days.forget(&ticket);
+                    self.pending.push(ticket);
+                }
+            }
+        }
+
+        self.dispatch_pending();
+
+        if self.since_prune >= PRUNE_EVERY {
+            self.since_prune = 0;
+            self.prune_sightings();
+        }
+    }
+
+    // Drops sightings that can't produce another ticket: those on a day the
+    // plate has already been ticketed for, since any ticket using them would
+    // span that day, and those older than the horizon. Removing a sighting
+    // from the middle only pairs up its neighbours, and a ticket between them
+    // would span the same day.
+    fn prune_sightings(&mut self) {
+        let cutoff = self
+            .horizon
+            .map_or(0, |horizon| self.latest.saturating_sub(horizon));
+        let issued_days = &self.issued_days;
+
+        self.sightings.retain(|(plate, _), road_sightings| {
+            road_sightings.retain(|s| {
+                s.timestamp >= cutoff && !issued_days.contains(plate, s.timestamp / 86400)
+            });
+            !road_sightings.is_empty()
+        });
+    }
+
+    fn evict(&mut self, client_id: ClientId) {
+        for road_dispatchers in self.dispatchers.values_mut() {
+            road_dispatchers
+                .handles
+                .retain(|handle| handle.client_id != client_id);
+        }
+    }
 
-    if bytes_read == 0 {
-        return Ok(None);
+    fn dump(&self) -> serde_json::Value {
+        let clients: HashMap<String, &Registration> = self
+            .clients
+            .iter()
+            .map(|(client_id, registration)| (client_id.to_string(), registration))
+            .collect();
+        let sightings: Vec<serde_json::Value> = self
+            .sightings
+            .iter()
+            .map(|((plate, road), road_sightings)| {
+                json!({ "plate": plate, "road": road, "count": road_sightings.len() })
+            })
+            .collect();
+
+        let mut connected: HashMap<&str, usize> = HashMap::new();
+        for registration in self.clients.values() {
+            let kind = match registration {
+                Registration::Camera { .. } => "cameras",
+                Registration::Dispatcher { .. } => "dispatchers",
+                Registration::Observer => "observers",
+            };
+            *connected.entry(kind).or_default() += 1;
+        }
+
+        json!({
+            "counters": self.counters,
+            "tickets_queued": self.pending.len(),
+            "connected": connected,
+            "clients": clients,
+            "pending_tickets": self.pending,
+            "issued_days": self.issued_days.days,
+            "sightings": sightings,
+        })
     }
 
-    let message = match message_type[0] {
-        0x20 => {
-            let mut plate_len = [0u8; 1];
-            reader.read_exact(&mut plate_len)?;
+    fn notify(&mut self, notice: &str) {
+        self.broadcast(codec::encode_outbound(&OutboundMessage::Notice {
+            text: notice.to_string(),
+        }));
+    }
 
-            let mut plate = vec![0u8; plate_len[0] as usize];
-            reader.read_exact(&mut plate)?;
+    // An observer that can't keep up misses frames rather than holding up
+    // the state task.
+    fn broadcast(&mut self, frame: Vec<u8>) {
+        self.observers.retain(|_, outbound| {
+            !matches!(
+                outbound.try_send(Outbound::Frame(frame.clone())),
+                Err(TrySendError::Closed(_))
+            )
+        });
+    }
 
-            let mut timestamp = [0u8; 4];
-            reader.read_exact(&mut timestamp)?;
+    // Each plate day is only ticketed once, whichever ticket gets delivered
+    // first.
+    fn dispatch_pending(&mut self) {
+        let mut delivered_tickets = Vec::new();
+        let mut suppressed_tickets = Vec::new();
+        let mut queues_full = false;
+
+        self.pending.retain(|t| {
+            let reason = if self.tickets.contains(t) {
+                Some("duplicate")
+            } else if self.issued_days.overlaps(t) {
+                Some("day already ticketed")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                self.counters.tickets_suppressed += 1;
+                suppressed_tickets.push((t.clone(), reason));
+                return false;
+            }
 
-            InboundMessage::Plate {
-                plate: String::from_utf8(plate).expect("Couldn't convert bytes to utf8"),
-                timestamp: u32::from_be_bytes(timestamp),
+            let dispatcher = self
+                .dispatchers
+                .get_mut(&t.road)
+                .and_then(|road_dispatchers| {
+                    let dispatcher = road_dispatchers.deliver(t);
+                    queues_full |= dispatcher.is_none() && !road_dispatchers.handles.is_empty();
+                    dispatcher
+                });
+            if let Some(dispatcher) = dispatcher {
+                self.tickets.insert(t.clone());
+                self.issued_days.record(t);
+                self.counters.tickets_delivered += 1;
+                delivered_tickets.push((t.clone(), dispatcher));
             }
+
+            dispatcher.is_none()
+        });
+        self.queues_full = queues_full;
+
+        for (ticket, reason) in suppressed_tickets {
+            self.audit(&AuditEntry::Suppressed {
+                ticket: &ticket,
+                reason,
+            });
         }
-        0x40 => {
-            let mut interval = [0u8; 4];
-            reader.read_exact(&mut interval)?;
 
-            InboundMessage::WantHeartbeat {
-                interval: u32::from_be_bytes(interval),
+        for (ticket, dispatcher) in delivered_tickets {
+            self.audit(&AuditEntry::Issued {
+                ticket: &ticket,
+                dispatcher,
+            });
+            let frame = codec::encode_ticket(&ticket);
+
+            if let Some(log) = &mut self.ticket_log
+                && let Err(e) = log.write_all(&frame).and_then(|()| log.flush())
+            {
+                eprintln!("Failed to log ticket: {}", e);
             }
+
+            self.broadcast(frame);
         }
-        0x80 => {
-            let mut road = [0u8; 2];
-            reader.read_exact(&mut road)?;
+    }
 
-            let mut mile = [0u8; 2];
-            reader.read_exact(&mut mile)?;
+    // Flushed line by line, so the log is complete up to the moment a failed
+    // run is killed.
+    fn audit(&mut self, entry: &AuditEntry) {
+        let Some(log) = &mut self.audit_log else {
+            return;
+        };
+
+        let mut line = serde_json::to_vec(entry).expect("Couldn't serialize audit entry");
+        line.push(b'\n');
+        if let Err(e) = log.write_all(&line).and_then(|()| log.flush()) {
+            eprintln!("Failed to write audit log: {}", e);
+        }
+    }
 
-            let mut limit = [0u8; 2];
-            reader.read_exact(&mut limit)?;
+    fn open_audit_log(&mut self, path: &Path) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.audit_log = Some(BufWriter::new(file));
+        Ok(())
+    }
 
-            InboundMessage::IAmCamera {
-                road: u16::from_be_bytes(road),
-                mile: u16::from_be_bytes(mile),
-                limit: u16::from_be_bytes(limit),
+    // Delivered tickets are appended to --tickets FILE as their wire bytes
+    // and read back at startup, so a restarted server doesn't ticket a plate
+    // day twice. A ticket requeued after a failed write is already in the
+    // log, so across a restart it errs towards missing a ticket rather than
+    // issuing one twice.
+    fn open_ticket_log(&mut self, path: &Path) -> std::io::Result<()> {
+        if path.exists() {
+            for ticket in read_tickets(File::open(path)?)? {
+                self.issued_days.record(&ticket);
+                self.tickets.insert(ticket);
             }
         }
-        0x81 => {
-            let mut numroads_buf = [0u8; 1];
-            reader.read_exact(&mut numroads_buf)?;
-            let numroads = u8::from_be_bytes(numroads_buf);
 
-            let mut roads_buf = vec![0u8; (numroads as usize) * 2];
-            reader.read_exact(&mut roads_buf)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.ticket_log = Some(BufWriter::new(file));
+        Ok(())
+    }
+}
 
-            let roads: Vec<u16> = roads_buf
-                .chunks_exact(2)
-                .map(|chunk| {
-                    let array: [u8; 2] = chunk.try_into().unwrap();
-                    u16::from_be_bytes(array)
-                })
-                .collect();
+// One line of the --audit log. Speeds are in hundredths of a mph, as on the
+// wire.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AuditEntry<'a> {
+    Issued {
+        #[serde(flatten)]
+        ticket: &'a Ticket,
+        dispatcher: ClientId,
+    },
+    Suppressed {
+        #[serde(flatten)]
+        ticket: &'a Ticket,
+        reason: &'static str,
+    },
+}
 
-            InboundMessage::IAmDispatcher { roads }
-        }
-        _ => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Unsupported message type",
-            ));
-        }
-    };
+// A torn ticket at the end of the log, from a crash mid-write, is dropped.
+fn read_tickets<R: Read>(mut reader: R) -> std::io::Result<Vec<Ticket>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let mut tickets = Vec::new();
+    let mut rest = &bytes[..];
+
+    while let Some((message, len)) = codec::decode_outbound(rest)? {
+        let OutboundMessage::Ticket(ticket) = message else {
+            return Err(codec::invalid_data("Ticket log holds a non-ticket message"));
+        };
+
+        tickets.push(ticket);
+        rest = &rest[len..];
+    }
 
-    Ok(Some(message))
+    Ok(tickets)
 }
 
+// Returns the error to send the client before it's disconnected.
 fn handle_message(
-    writer: &mut TcpStream,
     message: InboundMessage,
-    flock: &mut Arc<Mutex<FlockState>>,
-    client_id: &Uuid,
-) -> Result<(), std::io::Error> {
+    connection: &mut Connection,
+    state: &UnboundedSender<Command>,
+    config: &Config,
+) -> Result<(), &'static str> {
     match message {
         InboundMessage::WantHeartbeat { interval } => {
-            let mut heartbeat_writer = writer.try_clone().expect("Couldn't clone writer");
-
-            if interval == 0 {
-                return Ok(());
+            if !matches!(connection.heartbeat, HeartbeatState::None) {
+                return Err("Heartbeat already requested");
             }
 
-            thread::spawn(move || {
-                loop {
-                    let _ = heartbeat_writer.write(&[0x41]);
-                    let wait_time = std::time::Duration::from_secs_f64(interval as f64 / 10.0);
-                    thread::sleep(wait_time);
-                }
-            });
+            connection.heartbeat = if interval == 0 {
+                HeartbeatState::Disabled
+            } else {
+                HeartbeatState::Active(spawn_heartbeat(
+                    connection.outbound.clone(),
+                    Duration::from_millis(interval as u64 * 100),
+                ))
+            };
         }
         InboundMessage::IAmCamera { road, mile, limit } => {
-            let client_registry = &mut flock
-                .lock()
-                .expect("Couldn't obtain lock on flock")
-                .client_registry;
-
-            let (client_type, _): &(ClientType, ClientInfo) = client_registry
-                .get(client_id)
-                .expect("Client should already exist in registry");
+            let location = CameraLocation { road, mile, limit };
 
-            match *client_type {
-                ClientType::Unknown => {
-                    let client_info = ClientInfo::CameraInfo { road, mile, limit };
-
-                    client_registry.insert(*client_id, (ClientType::Camera, client_info));
+            match &mut connection.info {
+                ClientInfo::Unknown => {
+                    connection.info = ClientInfo::CameraInfo { location };
                 }
-                _ => {
-                    let msg = "Client already identified";
-                    send_error(writer, msg)?;
-                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+                ClientInfo::CameraInfo { location: current }
+                    if config.capabilities.has("camera-relocation") =>
+                {
+                    *current = location;
                 }
+                _ => return Err("Client already identified"),
             }
+
+            let _ = state.send(Command::Camera {
+                client_id: connection.client_id,
+                location,
+            });
         }
         InboundMessage::IAmDispatcher { roads } => {
-            let client_registry = &mut flock
-                .lock()
-                .expect("Couldn't obtain lock on flock")
-                .client_registry;
-
-            if let Some((client_type, _)) = client_registry.get_mut(client_id) {
-                match client_type {
-                    ClientType::Unknown => {
-                        *client_type = ClientType::Dispatcher;
-
-                        let stream_clone = writer
-                            .try_clone()
-                            .expect("Failed to clone stream for storage");
-
-                        let client_info = ClientInfo::DispatcherInfo {
-                            roads,
-                            stream: stream_clone,
-                        };
-
-                        client_registry.insert(*client_id, (ClientType::Dispatcher, client_info));
-                    }
-                    _ => {
-                        let msg = "Client already identified";
-                        send_error(writer, msg)?;
-                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
-                    }
-                }
+            if !matches!(connection.info, ClientInfo::Unknown) {
+                return Err("Client already identified");
+            }
+
+            connection.info = ClientInfo::DispatcherInfo;
+            let _ = state.send(Command::Dispatcher {
+                client_id: connection.client_id,
+                roads,
+                outbound: connection.outbound.clone(),
+            });
+        }
+        InboundMessage::IAmObserver => {
+            if !config.capabilities.has("observer") {
+                return Err("Illegal message type");
             }
+
+            if !matches!(connection.info, ClientInfo::Unknown) {
+                return Err("Client already identified");
+            }
+
+            connection.info = ClientInfo::ObserverInfo;
+            let _ = state.send(Command::Observer {
+                client_id: connection.client_id,
+                outbound: connection.outbound.clone(),
+            });
         }
         InboundMessage::Plate { plate, timestamp } => {
-            let mut guard = flock.lock().expect("Couldn't obtain lock on flock");
-            let state = &mut *guard;
+            let ClientInfo::CameraInfo { location } = connection.info else {
+                return Err("Only cameras can send plates");
+            };
 
-            let traffic_log = &mut state.traffic_log;
+            let sighting = SightingDetails {
+                road: location.road,
+                mile: location.mile,
+                limit: location.limit,
+                timestamp,
+            };
 
-            let client_registry = &mut state.client_registry;
+            let _ = state.send(Command::Sighting { plate, sighting });
+        }
+    }
 
-            let (client_type, _): &(ClientType, ClientInfo) = client_registry
-                .get(client_id)
-                .expect("Client should already exist in registry");
+    Ok(())
+}
 
-            if !matches!(client_type, ClientType::Camera) {
-                let msg = "Only cameras can send plates";
-                send_error(writer, msg)?;
-                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+// Writes everything queued for one client, until the client goes away or
+// every sender (the connection, its heartbeat and the state task) is dropped.
+// Once a write fails nothing more is accepted, and every ticket that didn't
+// make it is handed back to the state task for another dispatcher.
+async fn write_outbound(
+    client_id: ClientId,
+    mut writer: OwnedWriteHalf,
+    mut outbound: Receiver<Outbound>,
+    state: UnboundedSender<Command>,
+    quotas: Arc<Quotas>,
+) {
+    while let Some(item) = outbound.recv().await {
+        let (frame, ticket) = match item {
+            Outbound::Frame(frame) => (frame, None),
+            Outbound::Ticket(ticket) => (codec::encode_ticket(&ticket), Some(ticket)),
+        };
+
+        quotas.throttle_out_async(frame.len()).await;
+
+        if let Err(e) = writer.write_all(&frame).await {
+            eprintln!("Failed to write to client: {}", e);
+
+            outbound.close();
+            let mut tickets: Vec<Ticket> = ticket.into_iter().collect();
+            while let Ok(item) = outbound.try_recv() {
+                if let Outbound::Ticket(ticket) = item {
+                    tickets.push(ticket);
+                }
             }
 
-            traffic_log.push(Sighting {
-                client_id: *client_id,
-                plate,
-                timestamp,
-            });
+            let _ = state.send(Command::Undelivered { client_id, tickets });
+            return;
         }
     }
-
-    Ok(())
 }
 
-fn handle_client(stream: TcpStream, flock: &mut Arc<Mutex<FlockState>>) {
-    let mut writer = stream.try_clone().expect("Failed to clone stream");
-    let mut reader = BufReader::new(stream);
+// Reads the next chunk from a client, giving up after `timeout` if there is
+// one.
+async fn read_chunk(
+    reader: &mut OwnedReadHalf,
+    chunk: &mut [u8],
+    timeout: Option<Duration>,
+) -> std::io::Result<usize> {
+    let Some(timeout) = timeout else {
+        return reader.read(chunk).await;
+    };
 
-    let client_id = Uuid::new_v4();
-    flock
-        .lock()
-        .expect("Couldn't obtain lock on flock")
-        .client_registry
-        .insert(client_id, (ClientType::Unknown, ClientInfo::Unknown));
+    tokio::time::timeout(timeout, reader.read(chunk))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Idle timeout"))?
+}
+
+// `_permit` holds the client's place in the session quota until it's gone.
+async fn handle_client(
+    stream: TcpStream,
+    _permit: SessionPermit,
+    state: UnboundedSender<Command>,
+    quotas: Arc<Quotas>,
+    config: Config,
+) {
+    let client_id = ClientId::next();
+    let (mut reader, writer) = stream.into_split();
+    let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+    let writer_task = tokio::spawn(write_outbound(
+        client_id,
+        writer,
+        outbound_rx,
+        state.clone(),
+        quotas.clone(),
+    ));
+
+    let mut connection = Connection {
+        client_id,
+        info: ClientInfo::Unknown,
+        outbound: outbound_tx,
+        heartbeat: HeartbeatState::None,
+    };
+    let mut framer = Framer::default();
+    let mut chunk = [0u8; 4096];
+    let idle_timeout = |connection: &Connection| {
+        config
+            .idle_timeout
+            .filter(|_| matches!(connection.info, ClientInfo::Unknown))
+    };
 
     loop {
-        match read_message(&mut reader) {
-            Ok(Some(message)) => {
-                println!("{:?}", message);
-                if let Err(e) = handle_message(&mut writer, message, flock, &client_id) {
-                    eprintln!("Failed to handle message: {}", e);
-                    break;
+        let message = match framer.next_message(codec::decode_inbound) {
+            Ok(Some(message)) => message,
+            Ok(None) => {
+                match read_chunk(&mut reader, &mut chunk, idle_timeout(&connection)).await {
+                    Ok(0) => {
+                        if !framer.is_empty() {
+                            eprintln!("Client error: Connection closed mid-message");
+                        }
+                        break;
+                    }
+                    Ok(bytes_read) => {
+                        quotas.throttle_in_async(bytes_read).await;
+                        framer.push(&chunk[..bytes_read]);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Client error: {}", e);
+                        if e.kind() == std::io::ErrorKind::TimedOut {
+                            let _ = connection
+                                .outbound
+                                .send(Outbound::error(&e.to_string()))
+                                .await;
+                        }
+                        break;
+                    }
                 }
             }
-            Ok(None) => break,
             Err(e) => {
                 eprintln!("Client error: {}", e);
-
-                if e.kind() == std::io::ErrorKind::InvalidData {
-                    let _ = send_error(&mut writer, "Illegal message type");
-                }
-
+                let _ = connection
+                    .outbound
+                    .send(Outbound::error(&e.to_string()))
+                    .await;
                 break;
             }
+        };
+
+        println!("{:?}", message);
+        if let Err(msg) = handle_message(message, &mut connection, &state, &config) {
+            eprintln!("Failed to handle message: {}", msg);
+            let _ = connection.outbound.send(Outbound::error(msg)).await;
+            break;
         }
     }
 
-    let mut guard = flock.lock().expect("Couldn't obtain lock on flock");
-    let should_remove = !matches!(
-        guard.client_registry.get(&client_id),
-        Some((ClientType::Camera, _))
-    );
+    connection.heartbeat.stop();
+    let _ = state.send(Command::Disconnect {
+        client_id: connection.client_id,
+    });
+
+    // Let the writer flush anything still queued, such as a final error.
+    drop(connection);
+    let _ = writer_task.await;
+}
+
+// The sightings can be given in either order; the ticket always has the
+// earlier one as its first observation, as the spec requires. A car is
+// ticketed once its average speed is at least limit + 0.5 mph; with speed =
+// distance * 3600 / time that's checked as distance * 7200 >= (2 * limit +
+// 1) * time, in u64 so it's exact and can't overflow.
+fn ticket_between(plate: &str, a: &SightingDetails, b: &SightingDetails) -> Option<Ticket> {
+    let (s1, s2) = if a.timestamp <= b.timestamp {
+        (a, b)
+    } else {
+        (b, a)
+    };
+
+    let time_delta = (s2.timestamp - s1.timestamp) as u64;
+    let distance = s1.mile.abs_diff(s2.mile) as u64;
 
-    if should_remove {
-        guard.client_registry.remove(&client_id);
+    if distance == 0 || time_delta == 0 {
+        return None;
     }
+
+    if distance * 7200 < (2 * s1.limit as u64 + 1) * time_delta {
+        return None;
+    }
+
+    let speed_100x = (distance * 3600 * 100 / time_delta).min(u16::MAX as u64) as u16;
+
+    Some(Ticket {
+        plate: plate.to_string(),
+        road: s1.road,
+        mile1: s1.mile,
+        timestamp1: s1.timestamp,
+        mile2: s2.mile,
+        timestamp2: s2.timestamp,
+        speed: speed_100x,
+    })
 }
 
-fn check_traffic_log(
-    client_registry: &HashMap<Uuid, (ClientType, ClientInfo)>,
-    traffic_log: &Vec<Sighting>,
+// Inserts a sighting in timestamp order and checks it against the sightings
+// either side of it. That's enough to catch every violation: if a car averaged
+// over the limit between two sightings, it did so between some adjacent pair
+// in between them too.
+fn record_sighting(
+    sightings: &mut SightingIndex,
+    plate: String,
+    sighting: SightingDetails,
 ) -> Vec<Ticket> {
-    let mut candidates = Vec::new();
-
-    let mut sightings_by_plate: HashMap<String, Vec<SightingDetails>> = HashMap::new();
-
-    for sighting in traffic_log {
-        if let Some((ClientType::Camera, ClientInfo::CameraInfo { road, mile, limit })) =
-            client_registry.get(&sighting.client_id)
-        {
-            sightings_by_plate
-                .entry(sighting.plate.clone())
-                .or_default()
-                .push(SightingDetails {
-                    road: *road,
-                    mile: *mile,
-                    limit: *limit,
-                    timestamp: sighting.timestamp,
-                });
-        }
+    let road_sightings = sightings.entry((plate.clone(), sighting.road)).or_default();
+    let index = road_sightings.partition_point(|s| s.timestamp <= sighting.timestamp);
+
+    let mut tickets = Vec::new();
+    if let Some(before) = index.checked_sub(1).map(|i| &road_sightings[i]) {
+        tickets.extend(ticket_between(&plate, before, &sighting));
+    }
+    if let Some(after) = road_sightings.get(index) {
+        tickets.extend(ticket_between(&plate, &sighting, after));
     }
 
-    for (plate, mut sightings) in sightings_by_plate {
-        sightings.sort_by_key(|s| s.timestamp);
+    road_sightings.insert(index, sighting);
+    tickets
+}
+
+#[derive(Debug)]
+struct DispatcherHandle {
+    client_id: ClientId,
+    outbound: Sender<Outbound>,
+}
 
-        for pair in sightings.windows(2) {
-            let s1 = &pair[0];
-            let s2 = &pair[1];
+// The dispatchers for one road. Tickets go to each in turn.
+#[derive(Debug, Default)]
+struct RoadDispatchers {
+    handles: Vec<DispatcherHandle>,
+    next: usize,
+}
 
-            if s1.road != s2.road {
-                continue;
+impl RoadDispatchers {
+    // A dispatcher whose writer has already gone away is dropped, and one
+    // whose queue is full is passed over for the next, so a slow dispatcher
+    // only holds up itself. Returns the dispatcher the ticket was queued for,
+    // or None if it has to wait for room.
+    fn deliver(&mut self, ticket: &Ticket) -> Option<ClientId> {
+        self.handles.retain(|handle| !handle.outbound.is_closed());
+
+        for _ in 0..self.handles.len() {
+            let handle = &self.handles[self.next % self.handles.len()];
+            self.next = self.next.wrapping_add(1);
+
+            if handle
+                .outbound
+                .try_send(Outbound::Ticket(ticket.clone()))
+                .is_ok()
+            {
+                return Some(handle.client_id);
             }
+        }
 
-            let time_delta = s2.timestamp - s1.timestamp;
-            let distance = s1.mile.abs_diff(s2.mile);
+        None
+    }
+}
 
-            if distance == 0 || time_delta == 0 {
-                continue;
-            }
+// Every (plate, day) a delivered ticket covers. A ticket covers each day from
+// its first observation through its last, not just the two endpoints.
+#[derive(Debug, Default)]
+struct IssuedDays {
+    days: HashSet<(String, u32)>,
+}
 
-            let speed_mpg = (distance as f64 / time_delta as f64) * 3600.0;
-            let speed_100x = (speed_mpg * 100.0) as u16;
-            let limit_100x = s1.limit * 100;
-
-            if speed_100x > limit_100x {
-                candidates.push(Ticket {
-                    plate: plate.clone(),
-                    road: s1.road,
-                    mile1: s1.mile,
-                    timestamp1: s1.timestamp,
-                    mile2: s2.mile,
-                    timestamp2: s2.timestamp,
-                    speed: speed_100x,
-                });
-            }
+impl IssuedDays {
+    fn span(ticket: &Ticket) -> std::ops::RangeInclusive<u32> {
+        ticket.timestamp1 / 86400..=ticket.timestamp2 / 86400
+    }
+
+    fn contains(&self, plate: &str, day: u32) -> bool {
+        self.days.contains(&(plate.to_string(), day))
+    }
+
+    fn overlaps(&self, ticket: &Ticket) -> bool {
+        Self::span(ticket).any(|day| self.days.contains(&(ticket.plate.clone(), day)))
+    }
+
+    fn record(&mut self, ticket: &Ticket) {
+        for day in Self::span(ticket) {
+            self.days.insert((ticket.plate.clone(), day));
         }
     }
 
-    candidates
+    // Only ever called for a recorded ticket, and recorded tickets never
+    // overlap, so these days belong to it alone.
+    fn forget(&mut self, ticket: &Ticket) {
+        for day in Self::span(ticket) {
+            self.days.remove(&(ticket.plate.clone(), day));
+        }
+    }
+}
+
+// Answers every line sent to the admin listener with a JSON dump of the
+// state task's view of the world.
+async fn handle_admin(stream: TcpStream, state: UnboundedSender<Command>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(_)) = lines.next_line().await {
+        let (reply, dump) = oneshot::channel();
+        if state.send(Command::Dump { reply }).is_err() {
+            return;
+        }
+        let Ok(dump) = dump.await else {
+            return;
+        };
+
+        let mut line = serde_json::to_vec(&dump).expect("Couldn't serialize state to JSON");
+        line.push(b'\n');
+        if writer.write_all(&line).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn run_admin(listener: TcpListener, state: UnboundedSender<Command>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_admin(stream, state.clone()));
+            }
+            Err(e) => eprintln!("Failed to listen to admin client: {}", e),
+        }
+    }
 }
 
-fn main() {
-    let listener = TcpListener::bind(LOCAL_ADDR).unwrap();
+// The only owner of the flock state. Connections send it sightings and
+// registrations; tickets go from here straight to the dispatchers' writers.
+// Tickets that found every dispatcher's queue full would otherwise wait for
+// the next command, so they're retried on a timer too. Tickets for roads
+// with no dispatcher at all just wait for one to register.
+async fn run_state(mut state: FlockState, mut commands: UnboundedReceiver<Command>) {
+    let mut retry = tokio::time::interval(RETRY_PENDING);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let flock = Arc::new(Mutex::new(FlockState::new()));
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(command) => state.handle(command),
+                None => return,
+            },
+            _ = retry.tick(), if state.queues_full => state.dispatch_pending(),
+        }
+    }
+}
 
-    let dispatcher_flock = flock.clone();
-    thread::spawn(move || {
-        let mut tickets: HashSet<Ticket> = HashSet::new();
-        let mut issued_days: HashSet<(String, u32)> = HashSet::new();
+// Sends a heartbeat every `interval` until aborted or the client's writer
+// goes away. A beat that finds the client's queue full is dropped like an
+// overdue one. Beats are due at fixed deadlines from the first one (start +
+// n * interval), so time spent queueing and writing doesn't accumulate. A
+// beat that's already overdue is skipped rather than sent late, which would
+// shift every deadline after it.
+fn spawn_heartbeat(outbound: Sender<Outbound>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
         loop {
-            let new_tickets: Vec<Ticket> = {
-                let guard = dispatcher_flock
-                    .lock()
-                    .expect("Couldn't obtain lock on flock");
+            ticks.tick().await;
+            let heartbeat = Outbound::Frame(codec::encode_outbound(&OutboundMessage::Heartbeat));
+            if let Err(TrySendError::Closed(_)) = outbound.try_send(heartbeat) {
+                return;
+            }
+        }
+    })
+}
 
-                check_traffic_log(&guard.client_registry, &guard.traffic_log)
-            };
+#[tokio::main]
+async fn main() {
+    let config = Config::from_args().expect("Couldn't parse arguments");
+    let listener = TcpListener::bind(&config.addr)
+        .await
+        .expect("Couldn't bind to local network");
+    let quotas = Quotas::from_env().expect("Couldn't parse QUOTA");
+
+    let mut state = FlockState {
+        horizon: config.horizon,
+        ..FlockState::default()
+    };
+    if let Some(path) = &config.tickets {
+        state
+            .open_ticket_log(path)
+            .expect("Couldn't open ticket log");
+    }
+    if let Some(path) = &config.audit {
+        state.open_audit_log(path).expect("Couldn't open audit log");
+    }
 
-            if !new_tickets.is_empty() {
-                for t in new_tickets {
-                    if tickets.contains(&t) {
-                        continue;
-                    }
+    let (state_tx, state_rx) = mpsc::unbounded_channel::<Command>();
+    tokio::spawn(run_state(state, state_rx));
 
-                    let day1 = t.timestamp1 / 86400;
-                    let day2 = t.timestamp2 / 86400;
+    if let Some(addr) = &config.admin {
+        let admin = TcpListener::bind(addr)
+            .await
+            .expect("Couldn't bind admin listener");
+        tokio::spawn(run_admin(admin, state_tx.clone()));
+    }
 
-                    if issued_days.contains(&(t.plate.clone(), day1))
-                        || issued_days.contains(&(t.plate.clone(), day2))
-                    {
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                // Over the session quota (QUOTA=sessions=N), a client is
+                // turned away as soon as it's accepted.
+                let permit = match quotas.open_session() {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        eprintln!("Rejecting client: {}", e);
+                        tokio::spawn(async move {
+                            let _ = stream
+                                .write_all(&codec::encode_outbound(&OutboundMessage::Error {
+                                    msg: e.to_string(),
+                                }))
+                                .await;
+                        });
                         continue;
                     }
+                };
+
+                tokio::spawn(handle_client(
+                    stream,
+                    permit,
+                    state_tx.clone(),
+                    quotas.clone(),
+                    config.clone(),
+                ));
+            }
+            Err(e) => eprintln!("Failed to listen to client: {}", e),
+        }
+    }
+}
 
-                    let stream_to_write = {
-                        let guard = dispatcher_flock
-                            .lock()
-                            .expect("Couldn't obtain lock on flock");
-
-                        let dispatcher_entry =
-                            guard.client_registry.values().find(|&(_, client_info)| {
-                                if let ClientInfo::DispatcherInfo { roads, .. } = client_info {
-                                    return roads.contains(&t.road);
-                                }
-                                false
-                            });
-
-                        if let Some((_, ClientInfo::DispatcherInfo { stream, .. })) =
-                            dispatcher_entry
-                        {
-                            Some(
-                                stream
-                                    .try_clone()
-                                    .expect("Failed to clone dispatcher stream"),
-                            )
-                        } else {
-                            None
-                        }
-                    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::quota::Quota;
+    use proptest::prelude::*;
+
+    fn details(mile: u16, limit: u16, timestamp: u32) -> SightingDetails {
+        SightingDetails {
+            road: 1,
+            mile,
+            limit,
+            timestamp,
+        }
+    }
 
-                    if let Some(mut stream) = stream_to_write
-                        && t.clone().write(&mut stream).is_ok()
-                    {
-                        tickets.insert(t.clone());
+    fn speed(distance: u16, limit: u16, time: u32) -> Option<u16> {
+        ticket_between(
+            "UN1X",
+            &details(0, limit, 0),
+            &details(distance, limit, time),
+        )
+        .map(|ticket| ticket.speed)
+    }
 
-                        issued_days.insert((t.plate.clone(), day1));
-                        issued_days.insert((t.plate.clone(), day2));
-                    };
-                }
+    #[test]
+    fn exactly_at_the_limit_is_not_ticketed() {
+        assert_eq!(speed(1, 60, 60), None);
+    }
+
+    #[test]
+    fn just_under_half_a_mile_over_is_not_ticketed() {
+        // 6049 miles in 100 hours is 60.49 mph.
+        assert_eq!(speed(6049, 60, 360_000), None);
+    }
+
+    #[test]
+    fn half_a_mile_over_is_ticketed() {
+        // 121 miles in 2 hours is exactly 60.5 mph.
+        assert_eq!(speed(121, 60, 7200), Some(6050));
+    }
+
+    #[test]
+    fn limits_above_655_do_not_overflow() {
+        assert_eq!(speed(700, 700, 3600), None);
+        assert_eq!(speed(701, 700, 3600), Some(u16::MAX));
+    }
+
+    #[test]
+    fn sightings_given_latest_first_are_put_in_order() {
+        let earlier = details(8, 60, 0);
+        let later = details(9, 60, 45);
+
+        let ticket = ticket_between("UN1X", &later, &earlier).expect("Should be ticketed");
+
+        assert_eq!((ticket.mile1, ticket.timestamp1), (8, 0));
+        assert_eq!((ticket.mile2, ticket.timestamp2), (9, 45));
+        assert_eq!(ticket_between("UN1X", &earlier, &later), Some(ticket));
+    }
+
+    fn sighting() -> impl Strategy<Value = SightingDetails> {
+        (any::<u16>(), 1..=u16::MAX, any::<u32>())
+            .prop_map(|(mile, limit, timestamp)| details(mile, limit, timestamp))
+    }
+
+    proptest! {
+        #[test]
+        fn tickets_keep_each_mile_with_its_timestamp(a in sighting(), b in sighting()) {
+            if let Some(ticket) = ticket_between("UN1X", &a, &b) {
+                prop_assert!(ticket.timestamp1 <= ticket.timestamp2);
+
+                let first = (ticket.mile1, ticket.timestamp1);
+                let second = (ticket.mile2, ticket.timestamp2);
+                let observations = [(a.mile, a.timestamp), (b.mile, b.timestamp)];
+                prop_assert!(
+                    [first, second] == observations || [second, first] == observations
+                );
             }
+        }
+    }
 
-            let wait_time = std::time::Duration::from_millis(100);
-            thread::sleep(wait_time);
+    fn ticket_spanning(plate: &str, day1: u32, day2: u32) -> Ticket {
+        Ticket {
+            plate: plate.to_string(),
+            road: 1,
+            mile1: 0,
+            timestamp1: day1 * 86400 + 100,
+            mile2: 100,
+            timestamp2: day2 * 86400 + 100,
+            speed: 10000,
         }
-    });
+    }
 
-    for stream in listener.incoming() {
-        let mut flock_clone = flock.clone();
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move || handle_client(stream, &mut flock_clone));
-            }
-            Err(e) => eprintln!("Failed to listen to client: {}", e),
+    #[test]
+    fn a_multi_day_ticket_covers_the_days_in_between() {
+        let mut issued = IssuedDays::default();
+        issued.record(&ticket_spanning("UN1X", 10, 12));
+
+        assert!(issued.overlaps(&ticket_spanning("UN1X", 11, 11)));
+        assert!(issued.overlaps(&ticket_spanning("UN1X", 12, 13)));
+        assert!(!issued.overlaps(&ticket_spanning("UN1X", 13, 14)));
+        assert!(!issued.overlaps(&ticket_spanning("RE05BKG", 11, 11)));
+    }
+
+    #[test]
+    fn a_later_ticket_spanning_an_issued_day_is_rejected() {
+        let mut issued = IssuedDays::default();
+        issued.record(&ticket_spanning("UN1X", 11, 11));
+
+        assert!(issued.overlaps(&ticket_spanning("UN1X", 10, 12)));
+        assert!(!issued.overlaps(&ticket_spanning("UN1X", 8, 10)));
+    }
+
+    #[test]
+    fn a_torn_ticket_at_the_end_of_the_log_is_dropped() {
+        let mut log = Vec::new();
+        for ticket in [
+            ticket_spanning("UN1X", 1, 1),
+            ticket_spanning("RE05BKG", 2, 2),
+        ] {
+            log.extend(codec::encode_ticket(&ticket));
+        }
+        log.truncate(log.len() - 3);
+
+        let tickets = read_tickets(&log[..]).expect("Couldn't read ticket log");
+
+        assert_eq!(tickets, vec![ticket_spanning("UN1X", 1, 1)]);
+    }
+
+    fn add_dispatcher(state: &mut FlockState, client_id: ClientId) -> Receiver<Outbound> {
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        state.handle(Command::Dispatcher {
+            client_id,
+            roads: vec![1],
+            outbound,
+        });
+        outbound_rx
+    }
+
+    fn received_ticket(outbound_rx: &mut Receiver<Outbound>) -> Option<Ticket> {
+        match outbound_rx.try_recv() {
+            Ok(Outbound::Ticket(ticket)) => Some(ticket),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn an_undelivered_ticket_goes_to_another_dispatcher() {
+        let mut state = FlockState::default();
+        let first_id = ClientId::next();
+        let mut first = add_dispatcher(&mut state, first_id);
+        let mut second = add_dispatcher(&mut state, ClientId::next());
+        let ticket = ticket_spanning("UN1X", 1, 1);
+
+        state.pending.push(ticket.clone());
+        state.dispatch_pending();
+        assert_eq!(received_ticket(&mut first), Some(ticket.clone()));
+
+        state.handle(Command::Undelivered {
+            client_id: first_id,
+            tickets: vec![ticket.clone()],
+        });
+        assert_eq!(received_ticket(&mut second), Some(ticket));
+    }
+
+    #[test]
+    fn an_undelivered_ticket_waits_for_a_new_dispatcher() {
+        let mut state = FlockState::default();
+        let first_id = ClientId::next();
+        let _first = add_dispatcher(&mut state, first_id);
+        let ticket = ticket_spanning("UN1X", 1, 1);
+
+        state.pending.push(ticket.clone());
+        state.dispatch_pending();
+        state.handle(Command::Undelivered {
+            client_id: first_id,
+            tickets: vec![ticket.clone()],
+        });
+        assert_eq!(state.pending, vec![ticket.clone()]);
+        // With no dispatcher left there's nothing to retry on a timer.
+        assert!(!state.queues_full);
+
+        let mut second = add_dispatcher(&mut state, ClientId::next());
+        assert_eq!(received_ticket(&mut second), Some(ticket));
+    }
+
+    #[test]
+    fn a_closed_dispatcher_is_skipped() {
+        let mut state = FlockState::default();
+        drop(add_dispatcher(&mut state, ClientId::next()));
+        let mut second = add_dispatcher(&mut state, ClientId::next());
+        let ticket = ticket_spanning("UN1X", 1, 1);
+
+        state.pending.push(ticket.clone());
+        state.dispatch_pending();
+
+        assert_eq!(received_ticket(&mut second), Some(ticket));
+        assert_eq!(state.dispatchers[&1].handles.len(), 1);
+    }
+
+    #[test]
+    fn a_full_dispatcher_is_passed_over() {
+        let mut state = FlockState::default();
+        let (outbound, mut full) = mpsc::channel(1);
+        state.handle(Command::Dispatcher {
+            client_id: ClientId::next(),
+            roads: vec![1],
+            outbound,
+        });
+        let mut second = add_dispatcher(&mut state, ClientId::next());
+
+        for day in 1..=3 {
+            state.pending.push(ticket_spanning("UN1X", day, day));
+        }
+        state.dispatch_pending();
+
+        assert_eq!(
+            received_ticket(&mut full),
+            Some(ticket_spanning("UN1X", 1, 1))
+        );
+        assert_eq!(received_ticket(&mut full), None);
+        assert_eq!(
+            received_ticket(&mut second),
+            Some(ticket_spanning("UN1X", 2, 2))
+        );
+        assert_eq!(
+            received_ticket(&mut second),
+            Some(ticket_spanning("UN1X", 3, 3))
+        );
+    }
+
+    #[test]
+    fn a_ticket_waits_while_every_queue_is_full() {
+        let mut state = FlockState::default();
+        let (outbound, mut full) = mpsc::channel(1);
+        state.handle(Command::Dispatcher {
+            client_id: ClientId::next(),
+            roads: vec![1],
+            outbound,
+        });
+
+        state.pending.push(ticket_spanning("UN1X", 1, 1));
+        state.pending.push(ticket_spanning("UN1X", 2, 2));
+        state.dispatch_pending();
+        assert_eq!(state.pending, vec![ticket_spanning("UN1X", 2, 2)]);
+        assert!(state.queues_full);
+
+        assert_eq!(
+            received_ticket(&mut full),
+            Some(ticket_spanning("UN1X", 1, 1))
+        );
+        state.dispatch_pending();
+        assert_eq!(
+            received_ticket(&mut full),
+            Some(ticket_spanning("UN1X", 2, 2))
+        );
+        assert!(state.pending.is_empty());
+        assert!(!state.queues_full);
+    }
+
+    #[test]
+    fn audit_entries_are_flat_json() {
+        let ticket = ticket_spanning("UN1X", 1, 1);
+        let entry = AuditEntry::Suppressed {
+            ticket: &ticket,
+            reason: "day already ticketed",
+        };
+
+        assert_eq!(
+            serde_json::to_value(&entry).expect("Couldn't serialize audit entry"),
+            json!({
+                "event": "suppressed",
+                "plate": "UN1X",
+                "road": 1,
+                "mile1": 0,
+                "timestamp1": 86500,
+                "mile2": 100,
+                "timestamp2": 86500,
+                "speed": 10000,
+                "reason": "day already ticketed",
+            })
+        );
+    }
+
+    #[test]
+    fn a_conflicting_limit_is_flagged_and_overridden() {
+        let mut state = FlockState::default();
+        for (mile, limit) in [(0, 60), (10, 30)] {
+            state.handle(Command::Camera {
+                client_id: ClientId::next(),
+                location: CameraLocation {
+                    road: 1,
+                    mile,
+                    limit,
+                },
+            });
+        }
+
+        // 45 mph: over the second camera's limit, but not the road's.
+        for sighting in [details(0, 60, 0), details(10, 30, 800)] {
+            state.handle(Command::Sighting {
+                plate: "UN1X".to_string(),
+                sighting,
+            });
+        }
+
+        assert_eq!(state.counters.cameras_with_conflicting_limits, 1);
+        assert_eq!(state.counters.tickets_generated, 0);
+    }
+
+    #[test]
+    fn counters_track_tickets_through_the_state() {
+        let mut state = FlockState::default();
+        let dispatcher_id = ClientId::next();
+        let _dispatcher = add_dispatcher(&mut state, dispatcher_id);
+        assert_eq!(state.dump()["connected"]["dispatchers"], 1);
+
+        state.pending.push(ticket_spanning("UN1X", 1, 1));
+        state.pending.push(ticket_spanning("UN1X", 1, 2));
+        state.dispatch_pending();
+        state.handle(Command::Undelivered {
+            client_id: dispatcher_id,
+            tickets: vec![ticket_spanning("UN1X", 1, 1)],
+        });
+
+        let dump = state.dump();
+        assert_eq!(dump["counters"]["tickets_delivered"], 1);
+        assert_eq!(dump["counters"]["tickets_suppressed"], 1);
+        assert_eq!(dump["counters"]["tickets_requeued"], 1);
+        assert_eq!(dump["tickets_queued"], 1);
+    }
+
+    fn camera(road: u16) -> InboundMessage {
+        InboundMessage::IAmCamera {
+            road,
+            mile: 8,
+            limit: 60,
+        }
+    }
+
+    fn plate() -> InboundMessage {
+        InboundMessage::Plate {
+            plate: "UN1X".to_string(),
+            timestamp: 0,
+        }
+    }
+
+    fn dispatcher() -> InboundMessage {
+        InboundMessage::IAmDispatcher { roads: vec![123] }
+    }
+
+    // Feeds `messages` to a fresh connection, stopping at the first error,
+    // and returns that error along with everything sent to the state task.
+    fn run_session(
+        messages: Vec<InboundMessage>,
+        capabilities: Capabilities,
+    ) -> (Result<(), &'static str>, Vec<Command>) {
+        let config = Config {
+            capabilities,
+            addr: LOCAL_ADDR.to_string(),
+            horizon: None,
+            admin: None,
+            tickets: None,
+            audit: None,
+            idle_timeout: None,
+        };
+        let (outbound, _outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        let (state, mut state_rx) = mpsc::unbounded_channel();
+        let mut connection = Connection {
+            client_id: ClientId::next(),
+            info: ClientInfo::Unknown,
+            outbound,
+            heartbeat: HeartbeatState::None,
+        };
+
+        let result = messages
+            .into_iter()
+            .try_for_each(|message| handle_message(message, &mut connection, &state, &config));
+
+        let mut commands = Vec::new();
+        while let Ok(command) = state_rx.try_recv() {
+            commands.push(command);
+        }
+
+        (result, commands)
+    }
+
+    fn strict() -> Capabilities {
+        Capabilities::strict(protocol::FLOCK)
+    }
+
+    #[test]
+    fn plate_before_identifying_is_an_error() {
+        let (result, commands) = run_session(vec![plate()], strict());
+
+        assert_eq!(result, Err("Only cameras can send plates"));
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn plate_from_a_dispatcher_is_an_error() {
+        let (result, commands) = run_session(vec![dispatcher(), plate()], strict());
+
+        assert_eq!(result, Err("Only cameras can send plates"));
+        assert!(matches!(commands[..], [Command::Dispatcher { .. }]));
+    }
+
+    #[test]
+    fn identifying_twice_is_an_error() {
+        for messages in [
+            vec![camera(123), dispatcher()],
+            vec![dispatcher(), camera(123)],
+            vec![dispatcher(), dispatcher()],
+            vec![camera(123), camera(124)],
+        ] {
+            let (result, _) = run_session(messages, strict());
+            assert_eq!(result, Err("Client already identified"));
+        }
+    }
+
+    #[test]
+    fn a_camera_can_relocate_only_with_the_extension() {
+        let messages = vec![camera(123), camera(124), plate()];
+
+        let mut capabilities = strict();
+        capabilities
+            .enable("camera-relocation")
+            .expect("Couldn't enable camera-relocation");
+
+        let (result, commands) = run_session(messages, capabilities);
+
+        assert_eq!(result, Ok(()));
+        assert!(matches!(
+            commands[..],
+            [
+                Command::Camera { .. },
+                Command::Camera { .. },
+                Command::Sighting {
+                    sighting: SightingDetails { road: 124, .. },
+                    ..
+                }
+            ]
+        ));
+    }
+
+    #[test]
+    fn heartbeats_can_be_requested_before_identifying() {
+        let want_heartbeat = InboundMessage::WantHeartbeat { interval: 0 };
+        let messages = vec![want_heartbeat, camera(123), plate()];
+
+        let (result, commands) = run_session(messages, strict());
+
+        assert_eq!(result, Ok(()));
+        assert!(matches!(
+            commands[..],
+            [Command::Camera { .. }, Command::Sighting { .. }]
+        ));
+    }
+
+    #[test]
+    fn a_second_heartbeat_request_is_an_error() {
+        let want_heartbeat = InboundMessage::WantHeartbeat { interval: 0 };
+        let messages = vec![camera(123), want_heartbeat.clone(), want_heartbeat];
+
+        let (result, _) = run_session(messages, strict());
+
+        assert_eq!(result, Err("Heartbeat already requested"));
+    }
+
+    #[tokio::test]
+    async fn heartbeats_cant_be_turned_off_once_running() {
+        let messages = vec![
+            InboundMessage::WantHeartbeat { interval: 10 },
+            InboundMessage::WantHeartbeat { interval: 0 },
+        ];
+
+        let (result, _) = run_session(messages, strict());
+
+        assert_eq!(result, Err("Heartbeat already requested"));
+    }
+
+    // Heartbeats and idle timeouts run on tokio's timer, so these tests pause
+    // it and let the runtime jump straight to each deadline instead of
+    // waiting in real time.
+    #[tokio::test(start_paused = true)]
+    async fn heartbeats_keep_to_their_interval_in_virtual_time() {
+        let (outbound, mut outbound_rx) = mpsc::channel(OUTBOUND_QUEUE);
+        let heartbeat = spawn_heartbeat(outbound, Duration::from_millis(500));
+        let beat = codec::encode_outbound(&OutboundMessage::Heartbeat);
+
+        // The first beat is immediate, then one every half second.
+        tokio::time::sleep(Duration::from_millis(10_250)).await;
+        let mut beats = 0;
+        while let Ok(item) = outbound_rx.try_recv() {
+            assert!(matches!(item, Outbound::Frame(frame) if frame == beat));
+            beats += 1;
         }
+        assert_eq!(beats, 21);
+
+        // Once the writer is gone the heartbeat stops by itself.
+        drop(outbound_rx);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(heartbeat.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_clients_are_dropped_after_the_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Couldn't bind listener");
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .expect("Couldn't connect");
+        let (stream, _) = listener.accept().await.expect("Couldn't accept");
+
+        let quotas = Quotas::new(Quota::default());
+        let permit = quotas.open_session().expect("Within the session quota");
+        let (state, _state_rx) = mpsc::unbounded_channel();
+        let config = Config {
+            capabilities: strict(),
+            addr: LOCAL_ADDR.to_string(),
+            horizon: None,
+            admin: None,
+            tickets: None,
+            audit: None,
+            idle_timeout: Some(Duration::from_secs(60)),
+        };
+
+        let started = tokio::time::Instant::now();
+        tokio::spawn(handle_client(stream, permit, state, quotas, config));
+
+        let mut received = Vec::new();
+        client
+            .read_to_end(&mut received)
+            .await
+            .expect("Couldn't read from flock");
+        assert_eq!(
+            received,
+            codec::encode_outbound(&OutboundMessage::Error {
+                msg: "Idle timeout".to_string()
+            })
+        );
+        assert!(started.elapsed() >= Duration::from_secs(60));
     }
 }
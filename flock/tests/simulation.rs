@@ -0,0 +1,387 @@
+// Drives a real flock server over sockets with scripted camera and dispatcher
+// sessions, and checks the tickets it sends against a brute-force model of
+// the spec. Which of two overlapping tickets wins a day depends on the order
+// the server happens to see them, so the model checks invariants rather than
+// an exact list:
+//
+// - every ticket is for a real pair of sightings that broke the limit;
+// - no plate is ticketed twice for the same day;
+// - every pair of sightings that broke the limit shares a day with some
+//   ticket for that plate.
+
+use flock::codec::{self, Framer, InboundMessage, OutboundMessage, Ticket};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DAY: u32 = 86400;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+struct Camera {
+    road: u16,
+    mile: u16,
+    limit: u16,
+}
+
+// Sightings are sent in the order they're listed, each from its camera's own
+// connection.
+#[derive(Debug)]
+struct Scenario {
+    cameras: Vec<Camera>,
+    sightings: Vec<(usize, String, u32)>,
+}
+
+// A flock server on free local ports, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+    admin: String,
+}
+
+fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't find a free port");
+    listener
+        .local_addr()
+        .expect("Couldn't read local address")
+        .to_string()
+}
+
+impl Server {
+    fn start() -> Self {
+        let addr = free_addr();
+        let admin = free_addr();
+        let child = Command::new(env!("CARGO_BIN_EXE_flock"))
+            .args(["--bind", &addr, "--admin", &admin])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Couldn't start flock");
+
+        // The admin listener is bound after the main one, so once it answers
+        // both are up.
+        let started = Instant::now();
+        while TcpStream::connect(&admin).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "flock never started listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Server { child, addr, admin }
+    }
+
+    fn connect(&self, hello: &InboundMessage) -> TcpStream {
+        let mut stream = TcpStream::connect(&self.addr).expect("Couldn't connect to flock");
+        stream
+            .write_all(&codec::encode_inbound(hello))
+            .expect("Couldn't identify client");
+        stream
+    }
+
+    fn dump(&self) -> Value {
+        let mut stream = TcpStream::connect(&self.admin).expect("Couldn't connect to admin");
+        stream.write_all(b"\n").expect("Couldn't request dump");
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .expect("Couldn't read dump");
+        serde_json::from_str(&line).expect("Couldn't parse dump")
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Plays the scenario against a fresh server and returns every ticket sent to
+// a dispatcher covering all of its roads.
+fn run(scenario: &Scenario) -> Vec<Ticket> {
+    let server = Server::start();
+    let roads: BTreeSet<u16> = scenario.cameras.iter().map(|c| c.road).collect();
+    let mut dispatcher = server.connect(&InboundMessage::IAmDispatcher {
+        roads: roads.into_iter().collect(),
+    });
+
+    let mut cameras: Vec<TcpStream> = scenario
+        .cameras
+        .iter()
+        .map(|c| {
+            server.connect(&InboundMessage::IAmCamera {
+                road: c.road,
+                mile: c.mile,
+                limit: c.limit,
+            })
+        })
+        .collect();
+
+    for (camera, plate, timestamp) in &scenario.sightings {
+        let message = InboundMessage::Plate {
+            plate: plate.clone(),
+            timestamp: *timestamp,
+        };
+        cameras[*camera]
+            .write_all(&codec::encode_inbound(&message))
+            .expect("Couldn't send plate");
+    }
+
+    // Once every sighting is in and nothing is queued, every ticket the
+    // server will ever send is on its way to the dispatcher.
+    let started = Instant::now();
+    let delivered = loop {
+        let dump = server.dump();
+        let counters = &dump["counters"];
+        if counters["sightings"] == scenario.sightings.len() && dump["tickets_queued"] == 0 {
+            break counters["tickets_delivered"]
+                .as_u64()
+                .expect("Dump should count delivered tickets") as usize;
+        }
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "flock never caught up: {}",
+            dump
+        );
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    dispatcher
+        .set_read_timeout(Some(TIMEOUT))
+        .expect("Couldn't set read timeout");
+    let mut framer = Framer::default();
+    let mut chunk = [0u8; 4096];
+    let mut tickets = Vec::new();
+
+    while tickets.len() < delivered {
+        match framer
+            .next_message(codec::decode_outbound)
+            .expect("flock sent an invalid message")
+        {
+            Some(OutboundMessage::Ticket(ticket)) => tickets.push(ticket),
+            Some(other) => panic!("Unexpected message for dispatcher: {:?}", other),
+            None => {
+                let bytes_read = dispatcher.read(&mut chunk).expect("Couldn't read tickets");
+                assert!(bytes_read > 0, "flock closed the dispatcher connection");
+                framer.push(&chunk[..bytes_read]);
+            }
+        }
+    }
+
+    tickets
+}
+
+// Every pair of sightings of a plate on one road that averaged at least
+// limit + 0.5 mph, as the ticket it would earn.
+fn violations(scenario: &Scenario) -> Vec<Ticket> {
+    let mut violations = Vec::new();
+
+    for (i, (camera1, plate1, t1)) in scenario.sightings.iter().enumerate() {
+        for (camera2, plate2, t2) in &scenario.sightings[i + 1..] {
+            let (c1, c2) = (scenario.cameras[*camera1], scenario.cameras[*camera2]);
+            if plate1 != plate2 || c1.road != c2.road || t1 == t2 {
+                continue;
+            }
+
+            let ((c1, t1), (c2, t2)) = if t1 < t2 {
+                ((c1, *t1), (c2, *t2))
+            } else {
+                ((c2, *t2), (c1, *t1))
+            };
+            let hours = (t2 - t1) as f64 / 3600.0;
+            let speed = c1.mile.abs_diff(c2.mile) as f64 / hours;
+
+            if speed >= c1.limit as f64 + 0.5 {
+                violations.push(Ticket {
+                    plate: plate1.clone(),
+                    road: c1.road,
+                    mile1: c1.mile,
+                    timestamp1: t1,
+                    mile2: c2.mile,
+                    timestamp2: t2,
+                    speed: (speed * 100.0).min(u16::MAX as f64) as u16,
+                });
+            }
+        }
+    }
+
+    violations
+}
+
+fn days(ticket: &Ticket) -> impl Iterator<Item = u32> {
+    ticket.timestamp1 / DAY..=ticket.timestamp2 / DAY
+}
+
+fn check(scenario: &Scenario, tickets: &[Ticket]) {
+    let violations = violations(scenario);
+    let mut ticketed = HashSet::new();
+
+    for ticket in tickets {
+        let earned = violations.iter().any(|v| {
+            (
+                &v.plate,
+                v.road,
+                v.mile1,
+                v.timestamp1,
+                v.mile2,
+                v.timestamp2,
+            ) == (
+                &ticket.plate,
+                ticket.road,
+                ticket.mile1,
+                ticket.timestamp1,
+                ticket.mile2,
+                ticket.timestamp2,
+            ) && v.speed.abs_diff(ticket.speed) <= 1
+        });
+        assert!(earned, "Ticket wasn't earned: {:?}", ticket);
+
+        for day in days(ticket) {
+            assert!(
+                ticketed.insert((ticket.plate.clone(), day)),
+                "{} was ticketed twice on day {}",
+                ticket.plate,
+                day
+            );
+        }
+    }
+
+    for violation in &violations {
+        assert!(
+            days(violation).any(|day| ticketed.contains(&(violation.plate.clone(), day))),
+            "No ticket covers {:?}",
+            violation
+        );
+    }
+}
+
+fn simulate(scenario: Scenario) -> Vec<Ticket> {
+    let tickets = run(&scenario);
+    check(&scenario, &tickets);
+    tickets
+}
+
+fn road(road: u16, limit: u16, miles: &[u16]) -> Vec<Camera> {
+    miles
+        .iter()
+        .map(|&mile| Camera { road, mile, limit })
+        .collect()
+}
+
+#[test]
+fn sightings_reported_out_of_order() {
+    // 10 miles apart at 100 mph is 360s between cameras. Each trip is
+    // reported last camera first.
+    let mut sightings = Vec::new();
+    for day in [0, 2, 5] {
+        for camera in (0..4).rev() {
+            sightings.push((
+                camera,
+                "OUT0F0RD".to_string(),
+                day * DAY + camera as u32 * 360,
+            ));
+        }
+    }
+
+    let tickets = simulate(Scenario {
+        cameras: road(1, 60, &[0, 10, 20, 30]),
+        sightings,
+    });
+
+    assert_eq!(tickets.len(), 3);
+}
+
+#[test]
+fn one_ticket_per_day_across_roads() {
+    let mut cameras = road(1, 60, &[0, 10]);
+    cameras.extend(road(2, 30, &[0, 10]));
+    cameras.extend(road(3, 100, &[0, 10]));
+
+    let tickets = simulate(Scenario {
+        cameras,
+        sightings: vec![
+            // Speeding on roads 1 and 2 on the same day.
+            (0, "FAST".to_string(), 1000),
+            (1, "FAST".to_string(), 1360),
+            (2, "FAST".to_string(), 5000),
+            (3, "FAST".to_string(), 5360),
+            // 45 mph is only over the limit on road 2.
+            (0, "STEADY".to_string(), 1000),
+            (1, "STEADY".to_string(), 1800),
+            (2, "STEADY".to_string(), 1000),
+            (3, "STEADY".to_string(), 1800),
+            (4, "STEADY".to_string(), 1000),
+            (5, "STEADY".to_string(), 1800),
+        ],
+    });
+
+    assert_eq!(tickets.len(), 2);
+    assert!(tickets.iter().any(|t| t.plate == "STEADY" && t.road == 2));
+}
+
+#[test]
+fn tickets_spanning_midnight() {
+    let tickets = simulate(Scenario {
+        cameras: road(1, 60, &[0, 20, 40, 60]),
+        sightings: vec![
+            // Days 0 and 1, then day 1 again, which is already ticketed.
+            (0, "MIDN1GHT".to_string(), DAY - 400),
+            (1, "MIDN1GHT".to_string(), DAY + 400),
+            (2, "MIDN1GHT".to_string(), DAY + 1200),
+            // Crossing midnight exactly, at 72 mph.
+            (0, "EDGE".to_string(), 3 * DAY - 1),
+            (3, "EDGE".to_string(), 3 * DAY + 2999),
+            // The last second of one day and the first of the next.
+            (1, "EDGE".to_string(), 5 * DAY - 1),
+            (2, "EDGE".to_string(), 5 * DAY + 899),
+        ],
+    });
+
+    assert_eq!(tickets.len(), 3);
+}
+
+// A fixed-seed xorshift, so a failure can be replayed.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as u32
+    }
+}
+
+#[test]
+fn random_traffic_matches_the_model() {
+    let mut rng = Rng(0x5eed_f10c);
+    let mut cameras = Vec::new();
+    for (road_id, limit) in [(1, 40), (2, 60), (3, 80)] {
+        cameras.extend(road(road_id, limit, &[0, 5, 12, 30, 31]));
+    }
+
+    // Each car drives a whole road at a random speed, starting at a random
+    // time over a few days, then its sightings are sent in a random order.
+    let mut sightings = Vec::new();
+    for car in 0..60 {
+        let plate = format!("CAR{}", car % 20);
+        let road_id = rng.below(3) as usize;
+        let mph = 20 + rng.below(100);
+        let start = rng.below(4 * DAY);
+
+        for (camera, c) in cameras.iter().enumerate().skip(road_id * 5).take(5) {
+            let timestamp = start + c.mile as u32 * 3600 / mph;
+            sightings.push((camera, plate.clone(), timestamp));
+        }
+    }
+    for i in (1..sightings.len()).rev() {
+        sightings.swap(i, rng.below(i as u32 + 1) as usize);
+    }
+
+    let tickets = simulate(Scenario { cameras, sightings });
+
+    assert!(!tickets.is_empty());
+}
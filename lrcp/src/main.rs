@@ -1,9 +1,71 @@
+use common::clock::{Clock, SystemClock};
+use common::quota::{Quotas, SessionPermit};
+use std::sync::Arc;
 use std::net::{SocketAddr, UdpSocket};
 use std::time::{Duration, Instant};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(3);
 const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CHUNK_LEN: usize = 400;
+
+#[derive(Debug, Clone, Copy)]
+enum App {
+	Reverse,
+	Echo,
+}
+
+// `max_in_flight` caps the unacknowledged bytes per session. Anything beyond
+// it waits in the session's backlog until acks make room, so a long reply
+// doesn't go out as one burst for a lossy network to drop.
+#[derive(Debug, Clone, Copy)]
+struct Config {
+	app: App,
+	max_in_flight: usize,
+}
+
+impl Config {
+	fn from_args() -> Result<Self, String> {
+		let mut args = std::env::args().skip(1);
+		let mut config = Config {
+			app: App::Reverse,
+			max_in_flight: usize::MAX,
+		};
+
+		while let Some(arg) = args.next() {
+			match arg.as_str() {
+				"--app" => {
+					config.app = match args.next().as_deref() {
+						Some("reverse") => App::Reverse,
+						Some("echo") => App::Echo,
+						Some(other) => return Err(format!("Unknown app '{}'", other)),
+						None => return Err("Expected a value after --app".to_string()),
+					};
+				},
+				"--max-in-flight" => {
+					config.max_in_flight = match args.next().map(|v| v.parse::<usize>()) {
+						Some(Ok(bytes)) if bytes > 0 => bytes,
+						Some(_) => return Err("--max-in-flight must be a positive number of bytes".to_string()),
+						None => return Err("Expected a value after --max-in-flight".to_string()),
+					};
+				},
+				other => return Err(format!("Unknown argument '{}'", other)),
+			}
+		}
+
+		Ok(config)
+	}
+}
+
+impl App {
+	fn respond(&self, line: &str) -> String {
+		match self {
+			App::Reverse => line.chars().rev().collect(),
+			App::Echo => line.to_string(),
+		}
+	}
+}
 
 #[derive(Debug)]
 enum SessionState {
@@ -19,27 +81,112 @@ struct Session {
 	state: SessionState,
 	last_active: Instant,
 	next_expected_pos: usize,
-	pending_data: BTreeMap<usize, String>,
+	pending_line: String,
 	next_seq_to_send: usize,
+	acked: usize,
 	send_queue: BTreeMap<usize, (Instant, String)>,
+	backlog: VecDeque<String>,
+	max_in_flight: usize,
+	_permit: SessionPermit,
 }
 
 impl Session {
-	fn new(id: String, source: SocketAddr) -> Self {
+	fn new(id: String, source: SocketAddr, now: Instant, max_in_flight: usize, permit: SessionPermit) -> Self {
 		Self {
 			id,
 			source,
 			state: SessionState::Handshake,
-			last_active: Instant::now(),
+			last_active: now,
 			next_expected_pos: 0,
-			pending_data: BTreeMap::new(),
+			pending_line: String::new(),
 			next_seq_to_send: 0,
+			acked: 0,
 			send_queue: BTreeMap::new(),
+			backlog: VecDeque::new(),
+			max_in_flight,
+			_permit: permit,
+		}
+	}
+
+	// Splits outbound data into chunks small enough to fit in a single packet
+	// even after escaping and adds them to the backlog.
+	fn send(&mut self, socket: &mut UdpSocket, data: &str, now: Instant) {
+		let mut chars = data.chars().peekable();
+		while chars.peek().is_some() {
+			self.backlog.push_back(chars.by_ref().take(MAX_CHUNK_LEN).collect());
+		}
+
+		self.flush(socket, now);
+	}
+
+	// Sends backlogged chunks, queueing each for retransmission, for as long
+	// as they fit under max_in_flight. A chunk always goes out when nothing is
+	// in flight so a cap smaller than one chunk can't stall the session.
+	fn flush(&mut self, socket: &mut UdpSocket, now: Instant) {
+		let mut in_flight: usize = self.send_queue.values().map(|(_, data)| data.len()).sum();
+
+		while let Some(chunk) = self.backlog.front() {
+			if in_flight > 0 && in_flight + chunk.len() > self.max_in_flight {
+				break;
+			}
+
+			let chunk = self.backlog.pop_front().expect("Backlog should have a front chunk");
+			let pos = self.next_seq_to_send;
+			self.next_seq_to_send += chunk.len();
+			in_flight += chunk.len();
+
+			let response_str = format!("/data/{}/{}/{}/", self.id, pos, escape(&chunk));
+			let response = response_str.as_bytes();
+			let _ = socket.send_to(response, self.source);
+
+			self.send_queue.insert(pos, (now, chunk));
 		}
 	}
 }
 
-#[derive(Debug)]
+fn escape(data: &str) -> String {
+	data.replace('\\', "\\\\").replace('/', "\\/")
+}
+
+// Splits a packet body on '/' separators, skipping over escaped "\/" and
+// "\\" sequences and unescaping them in the returned fields.
+fn split_fields(raw: &str) -> Result<Vec<String>, &'static str> {
+	let mut fields = Vec::new();
+	let mut field = String::new();
+	let mut chars = raw.chars();
+
+	while let Some(c) = chars.next() {
+		match c {
+			'\\' => match chars.next() {
+				Some(escaped @ ('\\' | '/')) => field.push(escaped),
+				_ => return Err("Invalid escape sequence"),
+			},
+			'/' => fields.push(std::mem::take(&mut field)),
+			_ => field.push(c),
+		}
+	}
+	fields.push(field);
+
+	Ok(fields)
+}
+
+// Parses a numeric field, which must be written without a sign or leading
+// zeros and be smaller than 2147483648, so each value has one spelling.
+fn numeric(field: &str) -> Result<usize, &'static str> {
+	if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+		return Err("Numeric field should only contain digits");
+	}
+	if field.len() > 1 && field.starts_with('0') {
+		return Err("Numeric field shouldn't have leading zeros");
+	}
+
+	match field.parse() {
+		Ok(n) if n < 2147483648 => Ok(n),
+		_ => Err("Numeric field should be smaller than 2147483648"),
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum Packet {
 	Connect { session_id: String },
 	Data { session_id: String, pos: usize, data: String },
@@ -47,39 +194,50 @@ enum Packet {
 	Close { session_id: String },
 }
 
+impl std::fmt::Display for Packet {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Packet::Connect { session_id } => write!(f, "/connect/{}/", session_id),
+			Packet::Data { session_id, pos, data } => write!(f, "/data/{}/{}/{}/", session_id, pos, escape(data)),
+			Packet::Ack { session_id, length } => write!(f, "/ack/{}/{}/", session_id, length),
+			Packet::Close { session_id } => write!(f, "/close/{}/", session_id),
+		}
+	}
+}
+
 impl TryFrom<&[u8]> for Packet {
 	type Error = &'static str;
 
 	fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
 		let raw = str::from_utf8(value)
-			.expect("Couldn't convert packet to string")
+			.map_err(|_| "Packet isn't valid UTF-8")?
 			.trim_ascii_end();
 		println!("{}", raw);
 
-		if raw.chars().next() != Some('/') {
+		if !raw.starts_with('/') {
 			return Err("Expected first character to be '/'");
 		}
 
-		if raw.chars().last() != Some('/') {
+		// Splitting everything after the leading '/' leaves an empty final
+		// field exactly when the packet ends in an unescaped '/'.
+		let mut splits = split_fields(&raw[1..])?;
+		println!("{:?}", splits);
+
+		if splits.pop().as_deref() != Some("") {
 			return Err("Expected last character to be '/'");
 		}
 
-		let trimmed = raw.trim_matches('/');
-		println!("{:?}", trimmed);
-
-		let splits: Vec<&str> = trimmed.split("/").collect();
-		println!("{:?}", splits);
-
 		if splits.is_empty() {
 			return Err("Got empty message");
 		}
 
-		match splits[0] {
+		match splits[0].as_str() {
 			"connect" => {
 				if splits.len() != 2 {
-					return Err("Message with type 'data' should have 4 parts including the type");
+					return Err("Message with type 'connect' should have 2 parts including the type");
 				}
 
+				numeric(&splits[1])?;
 				Ok(Packet::Connect {
 					session_id: splits[1].to_string()
 				})
@@ -89,8 +247,9 @@ impl TryFrom<&[u8]> for Packet {
 					return Err("Message with type 'data' should have 4 parts including the type");
 				}
 
+				numeric(&splits[1])?;
 				let session_id = splits[1].to_string();
-				let pos: usize = splits[2].parse().expect("Couldn't parse data position to usize");
+				let pos = numeric(&splits[2])?;
 				let data = splits[3].to_string();
 
 				Ok(Packet::Data {
@@ -104,8 +263,9 @@ impl TryFrom<&[u8]> for Packet {
 					return Err("Message with type 'ack' should have 3 parts including the type");
 				}
 
+				numeric(&splits[1])?;
 				let session_id = splits[1].to_string();
-				let length: usize = splits[2].parse().expect("Couldn't parse ack length to usize");
+				let length = numeric(&splits[2])?;
 
 				Ok(Packet::Ack {
 					session_id,
@@ -117,77 +277,107 @@ impl TryFrom<&[u8]> for Packet {
 					return Err("Message with type 'close' should have 2 parts including the type");
 				}
 
+				numeric(&splits[1])?;
 				let session_id = splits[1].to_string();
 				Ok(Packet::Close {
 					session_id
 				})
 			},
-			_ => {
-				return Err("Unsupported message type");
-			},
+			_ => Err("Unsupported message type"),
 		}
 	}
 }
 
-fn handle_packet(packet: Packet, source: SocketAddr, socket: &mut UdpSocket, sessions: &mut HashMap<String, Session>) {
+fn handle_packet(packet: Packet, source: SocketAddr, socket: &mut UdpSocket, sessions: &mut HashMap<String, Session>, config: &Config, quotas: &Arc<Quotas>, now: Instant) {
 	match packet {
 		Packet::Connect { session_id } => {
-			let session = Session::new(session_id.clone(), source);
-			sessions.entry(session_id.to_string())
-				.or_insert(session);
+			if !sessions.contains_key(&session_id) {
+				match quotas.open_session() {
+					Ok(permit) => {
+						sessions.insert(session_id.clone(), Session::new(session_id.clone(), source, now, config.max_in_flight, permit));
+					},
+					Err(e) => {
+						eprintln!("Rejecting session {}: {}", session_id, e);
+						let response_str = format!("/close/{}/", session_id);
+						let response = response_str.as_bytes();
+						let _ = socket.send_to(response, source);
+						return;
+					},
+				}
+			}
+
+			let session = sessions.get_mut(&session_id).expect("Session should exist after connect");
+			session.state = SessionState::Established;
+			session.last_active = now;
 
 			let response_str = format!("/ack/{}/0/", session_id);
 			let response = response_str.as_bytes();
 			let _ = socket.send_to(response, source);
 		},
 		Packet::Data { session_id, pos, data } => {
-			match sessions.get(&session_id) {
+			match sessions.get_mut(&session_id) {
 				Some(session) => {
-					if session.next_expected_pos == pos {
-						let mut session_len = session.pending_data.values()
-							.fold(0, |acc, s| {
-								acc + s.len()
-							});
-						session_len += data.len();
-						let response_str = format!("/ack/{}/{}/", session_id, session_len);
-						let response = response_str.as_bytes();
-						let _ = socket.send_to(response, source);
-						return;
-					} else {
-						if session.pending_data.is_empty() {
-							let response_str = format!("/ack/{}/0/", session_id);
-							let response = response_str.as_bytes();
-							let _ = socket.send_to(response, source);
-							return;
-						} else {
-							let session_len = session.pending_data.values()
-								.fold(0, |acc, s| {
-									acc + s.len()
-								});
-							let response_str = format!("/ack/{}/{}/", session_id, session_len);
-							let response = response_str.as_bytes();
-							let _ = socket.send_to(response, source);
-							return;
-						}
+					session.last_active = now;
+
+					// Only data that starts at or before what we've already
+					// received can be used; anything else gets a duplicate ack
+					// so the peer retransmits from where we are. Positions
+					// that land inside a multibyte character can't be valid,
+					// so that data is dropped the same way.
+					if pos <= session.next_expected_pos
+						&& pos + data.len() > session.next_expected_pos
+						&& let Some(new_data) = data.get(session.next_expected_pos - pos..)
+					{
+						session.next_expected_pos += new_data.len();
+						session.pending_line.push_str(new_data);
+					}
+
+					let response_str = format!("/ack/{}/{}/", session_id, session.next_expected_pos);
+					let response = response_str.as_bytes();
+					let _ = socket.send_to(response, source);
+
+					while let Some(end) = session.pending_line.find('\n') {
+						let line: String = session.pending_line.drain(..=end).collect();
+						let reply = format!("{}\n", config.app.respond(&line[..end]));
+						session.send(socket, &reply, now);
 					}
 				},
 				None => {
 					let response_str = format!("/close/{}/", session_id);
 					let response = response_str.as_bytes();
 					let _ = socket.send_to(response, source);
-					return;
 				},
 			}
 		},
-		Packet::Ack { session_id, length} => {
-			match sessions.get(&session_id) {
+		Packet::Ack { session_id, length } => {
+			match sessions.get_mut(&session_id) {
 				Some(session) => {
+					session.last_active = now;
+
+					// Acks can arrive late and out of order, so one that
+					// doesn't move past the largest seen so far is a duplicate.
+					if length <= session.acked {
+						return;
+					}
+
+					if length > session.next_seq_to_send {
+						let response_str = format!("/close/{}/", session_id);
+						let response = response_str.as_bytes();
+						let _ = socket.send_to(response, source);
+						session.state = SessionState::Closing;
+						return;
+					}
+
+					// Whatever is still unacknowledged is left to tick()'s
+					// retransmission timer rather than resent on every ack.
+					session.acked = length;
+					session.send_queue.retain(|pos, (_, data)| pos + data.len() > length);
+					session.flush(socket, now);
 				},
 				None => {
 					let response_str = format!("/close/{}/", session_id);
 					let response = response_str.as_bytes();
 					let _ = socket.send_to(response, source);
-					return;
 				},
 			}
 		},
@@ -196,14 +386,42 @@ fn handle_packet(packet: Packet, source: SocketAddr, socket: &mut UdpSocket, ses
 			let response_str = format!("/close/{}/", session_id);
 			let response = response_str.as_bytes();
 			let _ = socket.send_to(response, source);
-			return;
 		},
 	}
 }
 
+// Retransmits anything unacknowledged for longer than RETRANSMISSION_TIMEOUT
+// and closes sessions that have been silent for SESSION_TIMEOUT. Driven by the
+// main loop with the clock's current time, so a virtual clock can fast-forward
+// through timeouts deterministically.
+fn tick(socket: &mut UdpSocket, sessions: &mut HashMap<String, Session>, now: Instant) {
+	for session in sessions.values_mut() {
+		if now.duration_since(session.last_active) >= SESSION_TIMEOUT {
+			session.state = SessionState::Closing;
+			continue;
+		}
+
+		for (pos, (sent_at, data)) in session.send_queue.iter_mut() {
+			if now.duration_since(*sent_at) >= RETRANSMISSION_TIMEOUT {
+				let response_str = format!("/data/{}/{}/{}/", session.id, pos, escape(data));
+				let response = response_str.as_bytes();
+				let _ = socket.send_to(response, session.source);
+				*sent_at = now;
+			}
+		}
+	}
+
+	sessions.retain(|_, session| !matches!(session.state, SessionState::Closing));
+}
+
 fn main() -> std::io::Result<()> {
+	let config = Config::from_args().map_err(std::io::Error::other)?;
+
 	let socket = UdpSocket::bind("0.0.0.0:8080")?;
+	socket.set_read_timeout(Some(TICK_INTERVAL))?;
 
+	let clock: Box<dyn Clock> = Box::new(SystemClock);
+	let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
 	let mut sessions: HashMap<String, Session> = HashMap::new();
 
 	let mut buf = [0u8; 999];
@@ -211,20 +429,286 @@ fn main() -> std::io::Result<()> {
 	loop {
 		match socket.recv_from(&mut buf) {
 			Ok((amt, source)) => {
+				quotas.throttle_in(amt);
 				match Packet::try_from(&buf[..amt]) {
 					Ok(p) => {
 						println!("{:?}", p);
-						handle_packet(p, source, &mut socket_clone, &mut sessions)
+						handle_packet(p, source, &mut socket_clone, &mut sessions, &config, &quotas, clock.now())
 					},
 					Err(e) => eprintln!("Couldn't successfully parse the packet: {}", e),
 				}
 			},
+			Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {},
 			Err(e) => {
 				eprintln!("Error receiving packet from client: {}", e);
 				break;
 			}
 		};
+
+		tick(&mut socket_clone, &mut sessions, clock.now());
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use common::clock::VirtualClock;
+	use common::quota::Quota;
+	use proptest::prelude::*;
+
+	struct Harness {
+		server: UdpSocket,
+		client: UdpSocket,
+		sessions: HashMap<String, Session>,
+		config: Config,
+		quotas: Arc<Quotas>,
+	}
+
+	impl Harness {
+		fn new(config: Config) -> Self {
+			let server = UdpSocket::bind("127.0.0.1:0").expect("Couldn't bind server socket");
+			let client = UdpSocket::bind("127.0.0.1:0").expect("Couldn't bind client socket");
+			client.set_nonblocking(true).expect("Couldn't make client socket nonblocking");
+
+			Harness {
+				server,
+				client,
+				sessions: HashMap::new(),
+				config,
+				quotas: Quotas::new(Quota::default()),
+			}
+		}
+
+		fn tick(&mut self, now: Instant) {
+			tick(&mut self.server, &mut self.sessions, now);
+		}
+
+		fn handle(&mut self, packet: Packet, now: Instant) {
+			let source = self.client.local_addr().expect("Client socket should have an address");
+			handle_packet(packet, source, &mut self.server, &mut self.sessions, &self.config, &self.quotas, now);
+		}
+
+		// Everything the server has sent so far. Loopback datagrams are
+		// queued by the time send_to returns, so there's nothing to wait for.
+		fn received(&self) -> Vec<Packet> {
+			let mut buf = [0u8; 1024];
+			let mut packets = Vec::new();
+			while let Ok(amt) = self.client.recv(&mut buf) {
+				packets.push(Packet::try_from(&buf[..amt]).expect("Server sent a malformed packet"));
+			}
+			packets
+		}
+	}
+
+	fn config(max_in_flight: usize) -> Config {
+		Config {
+			app: App::Echo,
+			max_in_flight,
+		}
+	}
+
+	fn connect(session_id: &str) -> Packet {
+		Packet::Connect { session_id: session_id.to_string() }
+	}
+
+	fn data(session_id: &str, pos: usize, data: &str) -> Packet {
+		Packet::Data { session_id: session_id.to_string(), pos, data: data.to_string() }
+	}
+
+	fn ack(session_id: &str, length: usize) -> Packet {
+		Packet::Ack { session_id: session_id.to_string(), length }
+	}
+
+	#[test]
+	fn connecting_establishes_the_session() {
+		let mut harness = Harness::new(config(usize::MAX));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		assert!(matches!(harness.sessions["1"].state, SessionState::Established));
+
+		// A repeated connect is acked again without starting over.
+		harness.handle(data("1", 0, "hi"), now);
+		harness.handle(connect("1"), now);
+		assert_eq!(harness.received(), vec![ack("1", 0), ack("1", 2), ack("1", 0)]);
+		assert_eq!(harness.sessions["1"].next_expected_pos, 2);
+	}
+
+	#[test]
+	fn acks_drop_the_data_they_cover() {
+		let mut harness = Harness::new(config(usize::MAX));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, "one\ntwo\n"), now);
+		assert_eq!(harness.sessions["1"].send_queue.len(), 2);
+
+		harness.handle(ack("1", 4), now);
+		assert_eq!(harness.sessions["1"].send_queue.keys().collect::<Vec<_>>(), vec![&4]);
+
+		harness.handle(ack("1", 8), now);
+		assert!(harness.sessions["1"].send_queue.is_empty());
+	}
+
+	#[test]
+	fn acks_for_data_never_sent_close_the_session() {
+		let mut harness = Harness::new(config(usize::MAX));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, "hi\n"), now);
+		harness.received();
+
+		harness.handle(ack("1", 4), now);
+		assert_eq!(harness.received(), vec![Packet::Close { session_id: "1".to_string() }]);
+		assert!(matches!(harness.sessions["1"].state, SessionState::Closing));
+	}
+
+	#[test]
+	fn acks_that_dont_advance_are_ignored() {
+		let mut harness = Harness::new(config(usize::MAX));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, "hello\n"), now);
+		assert_eq!(harness.received(), vec![ack("1", 0), ack("1", 6), data("1", 0, "hello\n")]);
+
+		// A partial ack leaves the rest to the retransmission timer instead of
+		// resending it straight away, and repeats of it do nothing at all.
+		harness.handle(ack("1", 3), now);
+		harness.handle(ack("1", 3), now);
+		assert_eq!(harness.received(), vec![]);
+		assert_eq!(harness.sessions["1"].send_queue.len(), 1);
+
+		harness.handle(ack("1", 6), now);
+		assert!(harness.sessions["1"].send_queue.is_empty());
+
+		// A stale ack arriving late isn't mistaken for lost data.
+		harness.handle(ack("1", 2), now);
+		assert_eq!(harness.received(), vec![]);
+		assert!(harness.sessions.contains_key("1"));
+	}
+
+	#[test]
+	fn data_positions_inside_a_character_are_dropped() {
+		let mut harness = Harness::new(config(usize::MAX));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, "x"), now);
+		harness.handle(data("1", 0, "\u{e9}\n"), now);
+		assert_eq!(harness.received(), vec![ack("1", 0), ack("1", 1), ack("1", 1)]);
+
+		harness.handle(data("1", 1, "\u{e9}\n"), now);
+		assert_eq!(harness.received(), vec![ack("1", 4), data("1", 0, "x\u{e9}\n")]);
+	}
+
+	#[test]
+	fn backlog_is_held_back_until_acks_make_room() {
+		let mut harness = Harness::new(config(500));
+		let now = Instant::now();
+		let line = format!("{}\n", "a".repeat(1000));
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, &line), now);
+		assert_eq!(
+			harness.received(),
+			vec![ack("1", 0), ack("1", 1001), data("1", 0, &line[..400])]
+		);
+		assert_eq!(harness.sessions["1"].backlog.len(), 2);
+
+		harness.handle(ack("1", 400), now);
+		assert_eq!(harness.received(), vec![data("1", 400, &line[400..800])]);
+
+		// A partial ack doesn't free the chunk it lands in.
+		harness.handle(ack("1", 600), now);
+		assert_eq!(harness.received(), vec![]);
+
+		harness.handle(ack("1", 800), now);
+		assert_eq!(harness.received(), vec![data("1", 800, &line[800..])]);
+		assert!(harness.sessions["1"].backlog.is_empty());
+	}
+
+	#[test]
+	fn a_chunk_goes_out_even_when_the_cap_is_smaller() {
+		let mut harness = Harness::new(config(4));
+		let now = Instant::now();
+
+		harness.handle(connect("1"), now);
+		harness.handle(data("1", 0, "hello\nworld\n"), now);
+		assert_eq!(
+			harness.received(),
+			vec![ack("1", 0), ack("1", 12), data("1", 0, "hello\n")]
+		);
+
+		harness.handle(ack("1", 6), now);
+		assert_eq!(harness.received(), vec![data("1", 6, "world\n")]);
+	}
+
+	#[test]
+	fn tick_retransmits_once_the_timeout_has_passed() {
+		let clock = VirtualClock::new();
+		let mut harness = Harness::new(config(usize::MAX));
+
+		harness.handle(connect("1"), clock.now());
+		harness.handle(data("1", 0, "hi\n"), clock.now());
+		assert_eq!(harness.received(), vec![ack("1", 0), ack("1", 3), data("1", 0, "hi\n")]);
+
+		clock.advance(RETRANSMISSION_TIMEOUT - Duration::from_millis(1));
+		harness.tick(clock.now());
+		assert_eq!(harness.received(), vec![]);
+
+		clock.advance(Duration::from_millis(1));
+		harness.tick(clock.now());
+		assert_eq!(harness.received(), vec![data("1", 0, "hi\n")]);
+
+		// The timer restarts from the retransmission, and an ack stops it.
+		clock.advance(RETRANSMISSION_TIMEOUT);
+		harness.handle(ack("1", 3), clock.now());
+		harness.tick(clock.now());
+		assert_eq!(harness.received(), vec![]);
+	}
+
+	#[test]
+	fn tick_expires_sessions_that_go_quiet() {
+		let clock = VirtualClock::new();
+		let mut harness = Harness::new(config(usize::MAX));
+
+		harness.handle(connect("1"), clock.now());
+		harness.handle(connect("2"), clock.now());
+
+		clock.advance(SESSION_TIMEOUT / 2);
+		harness.handle(data("2", 0, "x"), clock.now());
+
+		clock.advance(SESSION_TIMEOUT / 2);
+		harness.tick(clock.now());
+		assert!(!harness.sessions.contains_key("1"));
+		assert!(harness.sessions.contains_key("2"));
+
+		clock.advance(SESSION_TIMEOUT / 2);
+		harness.tick(clock.now());
+		assert!(harness.sessions.is_empty());
+	}
+
+	fn packet() -> impl Strategy<Value = Packet> {
+		let numeric = 0..2147483648usize;
+		let session_id = numeric.clone().prop_map(|id| id.to_string());
+
+		prop_oneof![
+			session_id.clone().prop_map(|session_id| Packet::Connect { session_id }),
+			(session_id.clone(), numeric.clone(), "[ -~\n]{0,200}")
+				.prop_map(|(session_id, pos, data)| Packet::Data { session_id, pos, data }),
+			(session_id.clone(), numeric).prop_map(|(session_id, length)| Packet::Ack { session_id, length }),
+			session_id.prop_map(|session_id| Packet::Close { session_id }),
+		]
+	}
+
+	common::roundtrip_tests!(
+		packet_roundtrip,
+		packet(),
+		|packet: &Packet| packet.to_string().into_bytes(),
+		|bytes: &[u8]| Packet::try_from(bytes).ok().map(|packet| (packet, bytes.trim_ascii_end().len())),
+	);
+}
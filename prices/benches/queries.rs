@@ -0,0 +1,74 @@
+// Compares a PriceHistory query with the fold over a BTreeMap range it
+// replaced, on sessions of increasing size with inserts in shuffled order.
+// Each query spans the middle half of the session's timestamps.
+//
+//   cargo bench -p prices
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use prices::history::PriceHistory;
+use std::collections::BTreeMap;
+use std::hint::black_box;
+
+const SIZES: [i32; 3] = [1_000, 10_000, 100_000];
+
+// Timestamps 0..n in a scrambled but repeatable order, with made-up prices.
+fn inserts(n: i32) -> impl Iterator<Item = (i32, i32)> {
+    // 7919 is prime, so stepping by it modulo n visits every timestamp.
+    (0..n).map(move |i| ((i as i64 * 7919 % n as i64) as i32, 1000 + i % 97))
+}
+
+fn folded_mean(prices: &BTreeMap<i32, i32>, min: i32, max: i32) -> i32 {
+    let (count, sum) = prices
+        .range(min..=max)
+        .fold((0i64, 0i64), |(c, s), (_, &price)| {
+            (c + 1, s + price as i64)
+        });
+    if count == 0 { 0 } else { (sum / count) as i32 }
+}
+
+fn queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("query");
+
+    for n in SIZES {
+        let btree: BTreeMap<i32, i32> = inserts(n).collect();
+        let mut history = PriceHistory::new();
+        for (timestamp, price) in inserts(n) {
+            history.insert(timestamp, price);
+        }
+        let (min, max) = (n / 4, 3 * n / 4);
+
+        group.bench_with_input(BenchmarkId::new("btreemap_fold", n), &n, |b, _| {
+            b.iter(|| folded_mean(&btree, black_box(min), black_box(max)))
+        });
+        group.bench_with_input(BenchmarkId::new("price_history", n), &n, |b, _| {
+            b.iter(|| history.mean(black_box(min), black_box(max)))
+        });
+    }
+
+    group.finish();
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.sample_size(10);
+
+    for n in SIZES {
+        group.bench_with_input(BenchmarkId::new("btreemap", n), &n, |b, &n| {
+            b.iter(|| inserts(n).collect::<BTreeMap<_, _>>())
+        });
+        group.bench_with_input(BenchmarkId::new("price_history", n), &n, |b, &n| {
+            b.iter(|| {
+                let mut history = PriceHistory::new();
+                for (timestamp, price) in inserts(n) {
+                    history.insert(timestamp, price);
+                }
+                history
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, queries, insert);
+criterion_main!(benches);
@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+// One session's prices by timestamp, kept as a treap: a search tree on
+// timestamp that's also a heap on a random priority, which keeps it balanced
+// however out of order the inserts arrive. Every node caches the count and
+// sum of its subtree, so a query's mean takes two O(log n) walks rather than
+// a fold over every price in the range.
+//
+// Priorities are the timestamp hashed with a per-history RandomState, so a
+// client can't pick timestamps that unbalance the tree.
+#[derive(Debug, Default, Clone)]
+pub struct PriceHistory {
+    nodes: Vec<Node>,
+    root: Option<usize>,
+    priorities: RandomState,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    timestamp: i32,
+    price: i32,
+    priority: u64,
+    left: Option<usize>,
+    right: Option<usize>,
+    count: u64,
+    sum: i64,
+}
+
+impl PriceHistory {
+    pub fn new() -> Self {
+        PriceHistory::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.totals(self.root).0 as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    pub fn get(&self, timestamp: i32) -> Option<i32> {
+        let mut node = self.root;
+        while let Some(i) = node {
+            let n = &self.nodes[i];
+            node = match timestamp.cmp(&n.timestamp) {
+                Ordering::Less => n.left,
+                Ordering::Greater => n.right,
+                Ordering::Equal => return Some(n.price),
+            };
+        }
+        None
+    }
+
+    pub fn contains_key(&self, timestamp: i32) -> bool {
+        self.get(timestamp).is_some()
+    }
+
+    // Sets the price at `timestamp`, returning the one it replaced.
+    pub fn insert(&mut self, timestamp: i32, price: i32) -> Option<i32> {
+        let (root, previous) = self.insert_under(self.root, timestamp, price);
+        self.root = Some(root);
+        previous
+    }
+
+    // The mean price from `min` to `max` inclusive, rounded towards zero, or
+    // 0 when there are no prices in that range.
+    pub fn mean(&self, min: i32, max: i32) -> i32 {
+        mean(self.summary(min, max))
+    }
+
+    // The count and sum of the prices from `min` to `max` inclusive.
+    pub fn summary(&self, min: i32, max: i32) -> (u64, i64) {
+        if min > max {
+            return (0, 0);
+        }
+
+        let (count, sum) = self.up_to(max);
+        let (below_count, below_sum) = min.checked_sub(1).map_or((0, 0), |t| self.up_to(t));
+        (count - below_count, sum - below_sum)
+    }
+
+    // Every (timestamp, price), in timestamp order.
+    pub fn iter(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        let mut stack = Vec::new();
+        let mut node = self.root;
+
+        std::iter::from_fn(move || {
+            while let Some(i) = node {
+                stack.push(i);
+                node = self.nodes[i].left;
+            }
+            let i = stack.pop()?;
+            node = self.nodes[i].right;
+            Some((self.nodes[i].timestamp, self.nodes[i].price))
+        })
+    }
+
+    fn insert_under(
+        &mut self,
+        node: Option<usize>,
+        timestamp: i32,
+        price: i32,
+    ) -> (usize, Option<i32>) {
+        let Some(i) = node else {
+            return (self.push(timestamp, price), None);
+        };
+
+        match timestamp.cmp(&self.nodes[i].timestamp) {
+            Ordering::Equal => {
+                let previous = std::mem::replace(&mut self.nodes[i].price, price);
+                self.update(i);
+                (i, Some(previous))
+            }
+            Ordering::Less => {
+                let (child, previous) = self.insert_under(self.nodes[i].left, timestamp, price);
+                self.nodes[i].left = Some(child);
+                (self.rebalance(i, child), previous)
+            }
+            Ordering::Greater => {
+                let (child, previous) = self.insert_under(self.nodes[i].right, timestamp, price);
+                self.nodes[i].right = Some(child);
+                (self.rebalance(i, child), previous)
+            }
+        }
+    }
+
+    fn push(&mut self, timestamp: i32, price: i32) -> usize {
+        self.nodes.push(Node {
+            timestamp,
+            price,
+            priority: self.priorities.hash_one(timestamp),
+            left: None,
+            right: None,
+            count: 1,
+            sum: price as i64,
+        });
+        self.nodes.len() - 1
+    }
+
+    // Rotates `child` above `i` if its priority is higher, returning
+    // whichever of them is now on top.
+    fn rebalance(&mut self, i: usize, child: usize) -> usize {
+        if self.nodes[child].priority <= self.nodes[i].priority {
+            self.update(i);
+            return i;
+        }
+
+        if self.nodes[i].left == Some(child) {
+            self.nodes[i].left = self.nodes[child].right;
+            self.nodes[child].right = Some(i);
+        } else {
+            self.nodes[i].right = self.nodes[child].left;
+            self.nodes[child].left = Some(i);
+        }
+        self.update(i);
+        self.update(child);
+        child
+    }
+
+    fn update(&mut self, i: usize) {
+        let (left_count, left_sum) = self.totals(self.nodes[i].left);
+        let (right_count, right_sum) = self.totals(self.nodes[i].right);
+        let n = &mut self.nodes[i];
+        n.count = left_count + 1 + right_count;
+        n.sum = left_sum + n.price as i64 + right_sum;
+    }
+
+    fn totals(&self, node: Option<usize>) -> (u64, i64) {
+        node.map_or((0, 0), |i| (self.nodes[i].count, self.nodes[i].sum))
+    }
+
+    // Count and sum of the prices at or before `timestamp`.
+    fn up_to(&self, timestamp: i32) -> (u64, i64) {
+        let (mut count, mut sum) = (0, 0);
+        let mut node = self.root;
+
+        while let Some(i) = node {
+            let n = &self.nodes[i];
+            if n.timestamp <= timestamp {
+                let (left_count, left_sum) = self.totals(n.left);
+                count += left_count + 1;
+                sum += left_sum + n.price as i64;
+                node = n.right;
+            } else {
+                node = n.left;
+            }
+        }
+
+        (count, sum)
+    }
+}
+
+// The mean of `count` prices adding up to `sum`, rounded towards zero, or 0
+// when there are none.
+pub fn mean((count, sum): (u64, i64)) -> i32 {
+    if count == 0 {
+        0
+    } else {
+        (sum / count as i64) as i32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    // The fold over a BTreeMap range that PriceHistory replaced.
+    fn folded_mean(prices: &BTreeMap<i32, i32>, min: i32, max: i32) -> i32 {
+        if min > max {
+            return 0;
+        }
+        let (count, sum) = prices
+            .range(min..=max)
+            .fold((0i64, 0i64), |(c, s), (_, &price)| {
+                (c + 1, s + price as i64)
+            });
+        if count == 0 { 0 } else { (sum / count) as i32 }
+    }
+
+    #[test]
+    fn means_cover_inclusive_ranges() {
+        let mut history = PriceHistory::new();
+        for (timestamp, price) in [(12345, 101), (12347, 100), (12346, 102), (40960, 5)] {
+            assert_eq!(history.insert(timestamp, price), None);
+        }
+
+        assert_eq!(history.mean(12288, 16384), 101);
+        assert_eq!(history.mean(12346, 12346), 102);
+        assert_eq!(history.mean(i32::MIN, i32::MAX), 77);
+        assert_eq!(history.mean(16384, 12288), 0);
+        assert_eq!(history.mean(0, 100), 0);
+        assert_eq!(history.insert(40960, -5), Some(5));
+        assert_eq!(history.len(), 4);
+        assert_eq!(
+            history.iter().collect::<Vec<_>>(),
+            vec![(12345, 101), (12346, 102), (12347, 100), (40960, -5)]
+        );
+    }
+
+    #[test]
+    fn sorted_inserts_stay_shallow() {
+        let mut history = PriceHistory::new();
+        for timestamp in 0..100_000 {
+            history.insert(timestamp, 1);
+        }
+
+        fn depth(history: &PriceHistory, node: Option<usize>) -> usize {
+            node.map_or(0, |i| {
+                let n = &history.nodes[i];
+                1 + depth(history, n.left).max(depth(history, n.right))
+            })
+        }
+        assert!(depth(&history, history.root) < 100);
+    }
+
+    proptest! {
+        #[test]
+        fn agrees_with_a_btreemap_fold(
+            inserts in prop::collection::vec((-50i32..50, any::<i32>()), 0..200),
+            queries in prop::collection::vec((-60i32..60, -60i32..60), 1..20),
+        ) {
+            let mut history = PriceHistory::new();
+            let mut model = BTreeMap::new();
+            for (timestamp, price) in inserts {
+                prop_assert_eq!(history.insert(timestamp, price), model.insert(timestamp, price));
+            }
+
+            prop_assert_eq!(history.len(), model.len());
+            prop_assert!(history.iter().eq(model.iter().map(|(&t, &p)| (t, p))));
+            for (min, max) in queries {
+                prop_assert_eq!(history.mean(min, max), folded_mean(&model, min, max));
+            }
+        }
+    }
+}
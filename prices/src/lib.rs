@@ -0,0 +1,5 @@
+pub mod history;
+pub mod spill;
+
+#[cfg(test)]
+mod scratch;
@@ -1,7 +1,18 @@
-use std::collections::BTreeMap;
-use std::io::{BufReader, BufWriter, Error, Read, Write};
+use common::protocol::{self, Capabilities};
+use common::quota::{Quotas, Throttled};
+use prices::history::PriceHistory;
+use prices::spill::SpilledHistory;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 // Need to know what client we are dealing with
 // and hash it into some kind of session identifier
@@ -30,6 +41,8 @@ use std::thread;
 // Insertions may occur out-of-order.
 // Prices can go negative.
 // Behavior undefined for multiple prices with same timestamp for same client.
+// Here it's up to --duplicates: overwrite (the default) keeps the latest
+// price, ignore keeps the first and disconnect closes the connection.
 //
 // ---
 // Query Message
@@ -50,19 +63,314 @@ use std::thread;
 // system endianness which is often little-endian.
 // We have to set endianness with i32::from_be_bytes()
 //
+// Each session's prices live in a PriceHistory, which answers a query in
+// O(log n) however many prices fall in its range.
+//
+// ---
+// Extensions (--lab, --enable token,resume or the older --extensions)
+// ---
+// 'T': request a resumption token. Payload is ignored. Replies with the
+//      token as an 8-byte big-endian integer.
+// 'R': resume a previous session. Payload is the 8-byte token. If the
+//      token's session disconnected less than RESUME_GRACE_PERIOD ago its
+//      data replaces this connection's data. Replies with an int32, 1 if
+//      the session was resumed and 0 otherwise.
+//
+// Without them these are rejected like any other unknown type, so sessions
+// stay strictly per-connection.
+//
+// ---
+// Write-ahead log (--wal DIR only)
+// ---
+// Every insert frame a connection sends is appended, raw, to DIR/<n>.wal
+// before it's applied. Frames are flushed every WAL_BATCH inserts and when
+// the connection ends. `prices --replay FILE` rebuilds the session's data
+// from a WAL and prints it as "timestamp price" lines.
+//
+// Only this connection's inserts are logged, so a session resumed with 'R'
+// also needs the WAL of the connection that created it. Duplicates are
+// logged as sent, so replay with the --duplicates the server ran with.
+//
+// ---
+// Spilling to disk (--spill-dir DIR only)
+// ---
+// A session holding more than --spill-after prices (DEFAULT_SPILL_AFTER
+// unless given) moves them to sorted run files in DIR and keeps at most that
+// many in memory from then on. Its prices stop counting against the memory
+// quota. The files are removed when the session ends or its parked data
+// expires.
+//
+
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(300);
+
+// Rough cost of one stored price, charged against the memory quota.
+const ENTRY_SIZE: usize = std::mem::size_of::<(i32, i32)>();
+
+const WAL_BATCH: usize = 64;
+
+const DEFAULT_SPILL_AFTER: usize = 1 << 20;
+
+// What an insert does to a timestamp the session already has a price for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DuplicatePolicy {
+    Overwrite,
+    Ignore,
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    capabilities: Capabilities,
+    wal_dir: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    duplicates: DuplicatePolicy,
+    spill_dir: Option<PathBuf>,
+    spill_after: usize,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            capabilities: Capabilities::strict(protocol::PRICES),
+            wal_dir: None,
+            replay: None,
+            duplicates: DuplicatePolicy::Overwrite,
+            spill_dir: None,
+            spill_after: DEFAULT_SPILL_AFTER,
+        };
+        let mut args = std::env::args().skip(1);
 
+        while let Some(arg) = args.next() {
+            if config.capabilities.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+
+            let mut path = || {
+                args.next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| format!("Expected a path after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--extensions" => config.capabilities = Capabilities::lab(protocol::PRICES),
+                "--wal" => config.wal_dir = Some(path()?),
+                "--replay" => config.replay = Some(path()?),
+                "--spill-dir" => config.spill_dir = Some(path()?),
+                "--spill-after" => {
+                    config.spill_after = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --spill-after".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --spill-after: {}", e))?;
+                    if config.spill_after == 0 {
+                        return Err("--spill-after must be at least 1".to_string());
+                    }
+                }
+                "--duplicates" => {
+                    config.duplicates = match args.next().as_deref() {
+                        Some("overwrite") => DuplicatePolicy::Overwrite,
+                        Some("ignore") => DuplicatePolicy::Ignore,
+                        Some("disconnect") => DuplicatePolicy::Disconnect,
+                        _ => {
+                            return Err(
+                                "Expected overwrite, ignore or disconnect after --duplicates"
+                                    .to_string(),
+                            );
+                        }
+                    };
+                }
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Batches raw insert frames for one connection's log file.
+struct Wal {
+    writer: BufWriter<File>,
+    pending: usize,
+}
+
+impl Wal {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        Ok(Wal {
+            writer: BufWriter::new(File::create(path)?),
+            pending: 0,
+        })
+    }
+
+    fn append(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(frame)?;
+        self.pending += 1;
+
+        if self.pending == WAL_BATCH {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.pending = 0;
+        self.writer.flush()
+    }
+}
+
+// Rebuilds a session's data by applying every insert frame in a WAL in order.
+// A duplicate that disconnected the session is the last frame it sent, so
+// replay stops there too.
+fn replay_wal<R: Read>(
+    mut reader: R,
+    duplicates: DuplicatePolicy,
+) -> std::io::Result<PriceHistory> {
+    let mut client_data = Prices::default();
+    let mut frame = [0u8; 9];
+
+    loop {
+        match reader.read_exact(&mut frame) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+
+        let message = Message::try_from(&frame[..]).map_err(std::io::Error::other)?;
+        if handle_insert(&message.content, &mut client_data, duplicates).is_err() {
+            break;
+        }
+    }
+
+    // Only handle_request spills.
+    match client_data {
+        Prices::Memory(history) => Ok(history),
+        Prices::Spilled(_) => unreachable!(),
+    }
+}
+
+// A session's prices: in memory to begin with, and on disk once there are
+// too many of them.
 #[derive(Debug)]
+enum Prices {
+    Memory(PriceHistory),
+    Spilled(SpilledHistory),
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        Prices::Memory(PriceHistory::new())
+    }
+}
+
+impl Prices {
+    fn contains_key(&self, timestamp: i32) -> std::io::Result<bool> {
+        match self {
+            Prices::Memory(history) => Ok(history.contains_key(timestamp)),
+            Prices::Spilled(spilled) => spilled.contains_key(timestamp),
+        }
+    }
+
+    fn insert(&mut self, timestamp: i32, price: i32) -> std::io::Result<Option<i32>> {
+        match self {
+            Prices::Memory(history) => Ok(history.insert(timestamp, price)),
+            Prices::Spilled(spilled) => spilled.insert(timestamp, price),
+        }
+    }
+
+    fn insert_new(&mut self, timestamp: i32, price: i32) -> std::io::Result<Option<i32>> {
+        match self {
+            Prices::Memory(history) => match history.get(timestamp) {
+                Some(existing) => Ok(Some(existing)),
+                None => Ok(history.insert(timestamp, price)),
+            },
+            Prices::Spilled(spilled) => spilled.insert_new(timestamp, price),
+        }
+    }
+
+    fn mean(&self, min: i32, max: i32) -> std::io::Result<i32> {
+        match self {
+            Prices::Memory(history) => Ok(history.mean(min, max)),
+            Prices::Spilled(spilled) => spilled.mean(min, max),
+        }
+    }
+}
+
+// Sessions that have handed out a token and then disconnected, waiting to
+// be resumed.
+#[derive(Debug, Default)]
+struct ParkedSessions {
+    sessions: HashMap<u64, (Instant, Prices)>,
+    hasher: RandomState,
+    issued: u64,
+}
+
+impl ParkedSessions {
+    fn new_token(&mut self) -> u64 {
+        self.issued += 1;
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(self.issued);
+        hasher.finish()
+    }
+
+    fn park(&mut self, token: u64, client_data: Prices) {
+        self.expire();
+        self.sessions.insert(token, (Instant::now(), client_data));
+    }
+
+    fn resume(&mut self, token: u64) -> Option<Prices> {
+        self.expire();
+        self.sessions.remove(&token).map(|(_, data)| data)
+    }
+
+    fn expire(&mut self) {
+        self.sessions
+            .retain(|_, (parked_at, _)| parked_at.elapsed() < RESUME_GRACE_PERIOD);
+    }
+}
+
+// Per-connection state: the price data plus the resumption token, if the
+// client asked for one. `reserved` is what this connection has charged to the
+// memory quota; parked and spilled sessions don't count against it.
+#[derive(Default)]
+struct Session {
+    id: u64,
+    client_data: Prices,
+    token: Option<u64>,
+    reserved: usize,
+    wal: Option<Wal>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 enum MessageType {
     Insert,
     Query,
+    Token,
+    Resume,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 struct Message {
     kind: MessageType,
     content: (i32, i32),
 }
 
+impl From<&Message> for [u8; 9] {
+    fn from(message: &Message) -> Self {
+        let mut bytes = [0u8; 9];
+
+        bytes[0] = match message.kind {
+            MessageType::Insert => b'I',
+            MessageType::Query => b'Q',
+            MessageType::Token => b'T',
+            MessageType::Resume => b'R',
+        };
+        bytes[1..5].copy_from_slice(&message.content.0.to_be_bytes());
+        bytes[5..9].copy_from_slice(&message.content.1.to_be_bytes());
+
+        bytes
+    }
+}
+
 impl TryFrom<&[u8]> for Message {
     type Error = &'static str;
 
@@ -75,7 +383,9 @@ impl TryFrom<&[u8]> for Message {
         let message_kind = match b[0] {
             b'I' => MessageType::Insert,
             b'Q' => MessageType::Query,
-            _ => return Err("First byte must be 'I' or 'Q'"),
+            b'T' => MessageType::Token,
+            b'R' => MessageType::Resume,
+            _ => return Err("First byte must be 'I', 'Q', 'T' or 'R'"),
         };
 
         let a = i32::from_be_bytes(
@@ -98,71 +408,199 @@ impl TryFrom<&[u8]> for Message {
 
 fn handle_insert(
     message_data: &(i32, i32),
-    client_data: &mut BTreeMap<i32, i32>,
+    client_data: &mut Prices,
+    duplicates: DuplicatePolicy,
 ) -> Result<Option<i32>, Error> {
-    client_data.insert(message_data.0, message_data.1);
+    let (timestamp, price) = *message_data;
+
+    // One lookup both applies the insert and says whether it was a duplicate,
+    // which matters once the prices are on disk.
+    let existing = match duplicates {
+        DuplicatePolicy::Overwrite => client_data.insert(timestamp, price)?,
+        DuplicatePolicy::Ignore | DuplicatePolicy::Disconnect => {
+            client_data.insert_new(timestamp, price)?
+        }
+    };
+    if existing.is_some() && duplicates == DuplicatePolicy::Disconnect {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Duplicate insert for timestamp {}", timestamp),
+        ));
+    }
+
     Ok(None)
 }
 
-fn handle_query(
+fn handle_query(message_data: &(i32, i32), client_data: &mut Prices) -> Result<Option<i32>, Error> {
+    Ok(Some(client_data.mean(message_data.0, message_data.1)?))
+}
+
+fn handle_token(session: &mut Session, parked: &Mutex<ParkedSessions>) -> u64 {
+    let token = match session.token {
+        Some(token) => token,
+        None => parked
+            .lock()
+            .expect("Couldn't obtain lock on parked sessions")
+            .new_token(),
+    };
+    session.token = Some(token);
+
+    token
+}
+
+fn handle_resume(
     message_data: &(i32, i32),
-    client_data: &mut BTreeMap<i32, i32>,
+    session: &mut Session,
+    parked: &Mutex<ParkedSessions>,
 ) -> Result<Option<i32>, Error> {
-    if message_data.0 > message_data.1 {
-        return Ok(Some(0));
-    }
+    let token = ((message_data.0 as u32 as u64) << 32) | message_data.1 as u32 as u64;
 
-    let (count, sum) = client_data
-        .range(message_data.0..=message_data.1)
-        .fold((0i64, 0i64), |(c, s), (_, &price)| {
-            (c + 1, s + price as i64)
-        });
+    let resumed = parked
+        .lock()
+        .expect("Couldn't obtain lock on parked sessions")
+        .resume(token);
+
+    match resumed {
+        Some(client_data) => {
+            session.client_data = client_data;
+            session.token = Some(token);
+            Ok(Some(1))
+        }
+        None => Ok(Some(0)),
+    }
+}
 
-    let mean = if count == 0 { 0 } else { sum / count };
+// Moves a session's prices to disk once it has --spill-after of them, and
+// hands back what they were charged to the memory quota.
+fn spill_if_large(session: &mut Session, config: &Config, quotas: &Quotas) -> std::io::Result<()> {
+    let Some(dir) = &config.spill_dir else {
+        return Ok(());
+    };
+    let Prices::Memory(history) = &session.client_data else {
+        return Ok(());
+    };
+    if history.len() < config.spill_after {
+        return Ok(());
+    }
 
-    Ok(Some(mean as i32))
+    let name = session.id.to_string();
+    let spilled = SpilledHistory::from_history(dir, &name, config.spill_after, history)?;
+    println!(
+        "Session {} spilled {} prices to {:?}",
+        session.id,
+        spilled.len(),
+        dir
+    );
+    session.client_data = Prices::Spilled(spilled);
+    quotas.release(session.reserved);
+    session.reserved = 0;
+    Ok(())
 }
 
 fn handle_request(
     request: &[u8],
-    writer: &mut BufWriter<TcpStream>,
-    client_data: &mut BTreeMap<i32, i32>,
+    writer: &mut BufWriter<Throttled<TcpStream>>,
+    session: &mut Session,
+    parked: &Mutex<ParkedSessions>,
+    config: &Config,
+    quotas: &Quotas,
 ) -> std::io::Result<()> {
     let message = Message::try_from(request).map_err(std::io::Error::other)?;
 
     let res = match &message.kind {
-        MessageType::Insert => handle_insert(&message.content, client_data),
-        MessageType::Query => handle_query(&message.content, client_data),
-    };
-
-    match res {
-        Ok(Some(n)) => {
-            writer.write_all(&n.to_be_bytes())?;
+        MessageType::Insert => {
+            if matches!(session.client_data, Prices::Memory(_))
+                && !session.client_data.contains_key(message.content.0)?
+            {
+                quotas.reserve(ENTRY_SIZE).map_err(std::io::Error::other)?;
+                session.reserved += ENTRY_SIZE;
+            }
+            if let Some(wal) = &mut session.wal {
+                wal.append(request)?;
+            }
+            let res = handle_insert(
+                &message.content,
+                &mut session.client_data,
+                config.duplicates,
+            );
+            spill_if_large(session, config, quotas)?;
+            res
+        }
+        MessageType::Query => handle_query(&message.content, &mut session.client_data),
+        MessageType::Token if !config.capabilities.has("token") => {
+            return Err(std::io::Error::other("Token extension isn't enabled"));
+        }
+        MessageType::Resume if !config.capabilities.has("resume") => {
+            return Err(std::io::Error::other("Resume extension isn't enabled"));
+        }
+        MessageType::Token => {
+            let token = handle_token(session, parked);
+            writer.write_all(&token.to_be_bytes())?;
             writer.flush()?;
+            return Ok(());
         }
-        Ok(None) => {}
-        Err(e) => eprintln!("Failed to respond to message: {}", e),
+        MessageType::Resume => handle_resume(&message.content, session, parked),
+    };
+
+    if let Some(n) = res? {
+        writer.write_all(&n.to_be_bytes())?;
+        writer.flush()?;
     }
 
     Ok(())
 }
 
-fn handle_client(stream: TcpStream) {
+fn handle_client(
+    stream: TcpStream,
+    parked: Arc<Mutex<ParkedSessions>>,
+    config: Config,
+    session_id: u64,
+    quotas: Arc<Quotas>,
+) {
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("Rejecting client: {}", e);
+            return;
+        }
+    };
+
     let write_stream = stream
         .try_clone()
         .expect("Couldn't clone stream for writing");
 
-    let mut reader = BufReader::new(stream);
-    let mut writer = BufWriter::new(write_stream);
+    let mut reader = BufReader::new(quotas.wrap(stream));
+    let mut writer = BufWriter::new(quotas.wrap(write_stream));
 
-    let mut client_data: BTreeMap<i32, i32> = BTreeMap::new();
+    let mut session = Session {
+        id: session_id,
+        ..Session::default()
+    };
+
+    if let Some(dir) = &config.wal_dir {
+        let path = dir.join(format!("{}.wal", session_id));
+        match Wal::create(path.clone()) {
+            Ok(wal) => {
+                println!("Session {} logging inserts to {:?}", session_id, path);
+                session.wal = Some(wal);
+            }
+            Err(e) => eprintln!("Couldn't create WAL {:?}: {}", path, e),
+        }
+    }
 
     let chunk_size = 9;
     loop {
         let mut buffer = vec![0u8; chunk_size];
         match reader.read_exact(&mut buffer) {
             Ok(_) => {
-                if let Err(e) = handle_request(&buffer, &mut writer, &mut client_data) {
+                if let Err(e) = handle_request(
+                    &buffer,
+                    &mut writer,
+                    &mut session,
+                    &parked,
+                    &config,
+                    &quotas,
+                ) {
                     eprintln!("Failed to handle request: {}", e);
                     break;
                 }
@@ -170,16 +608,48 @@ fn handle_client(stream: TcpStream) {
             Err(_) => break,
         }
     }
+
+    quotas.release(session.reserved);
+
+    if let Some(wal) = &mut session.wal
+        && let Err(e) = wal.flush()
+    {
+        eprintln!("Couldn't flush WAL for session {}: {}", session_id, e);
+    }
+
+    if let Some(token) = session.token {
+        parked
+            .lock()
+            .expect("Couldn't obtain lock on parked sessions")
+            .park(token, session.client_data);
+    }
 }
 
 fn main() -> std::io::Result<()> {
+    let config = Config::from_args().map_err(std::io::Error::other)?;
+
+    if let Some(path) = &config.replay {
+        let client_data = replay_wal(BufReader::new(File::open(path)?), config.duplicates)?;
+        for (timestamp, price) in client_data.iter() {
+            println!("{} {}", timestamp, price);
+        }
+        return Ok(());
+    }
+
+    let sessions = AtomicU64::new(0);
     let listener = TcpListener::bind("0.0.0.0:8080")?;
+    let parked = Arc::new(Mutex::new(ParkedSessions::default()));
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                let parked = parked.clone();
+                let config = config.clone();
+                let session_id = sessions.fetch_add(1, Ordering::Relaxed);
+                let quotas = quotas.clone();
                 thread::spawn(move || {
-                    handle_client(stream);
+                    handle_client(stream, parked, config, session_id, quotas);
                 });
             }
             Err(e) => {
@@ -190,3 +660,142 @@ fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+// Shared with the library's tests, which compile it separately.
+#[cfg(test)]
+mod scratch;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+    use common::quota::Quota;
+    use proptest::prelude::*;
+
+    fn message() -> impl Strategy<Value = Message> {
+        let kind = prop_oneof![
+            Just(MessageType::Insert),
+            Just(MessageType::Query),
+            Just(MessageType::Token),
+            Just(MessageType::Resume),
+        ];
+
+        (kind, any::<i32>(), any::<i32>()).prop_map(|(kind, a, b)| Message {
+            kind,
+            content: (a, b),
+        })
+    }
+
+    fn insert(timestamp: i32, price: i32) -> [u8; 9] {
+        <[u8; 9]>::from(&Message {
+            kind: MessageType::Insert,
+            content: (timestamp, price),
+        })
+    }
+
+    #[test]
+    fn duplicate_timestamps_follow_the_policy() {
+        let cases = [
+            (DuplicatePolicy::Overwrite, true, Some(20)),
+            (DuplicatePolicy::Ignore, true, Some(10)),
+            (DuplicatePolicy::Disconnect, false, Some(10)),
+        ];
+
+        for (policy, accepted, price) in cases {
+            let mut client_data = Prices::default();
+            handle_insert(&(1, 10), &mut client_data, policy).expect("First insert is new");
+            assert_eq!(
+                handle_insert(&(1, 20), &mut client_data, policy).is_ok(),
+                accepted,
+                "{:?}",
+                policy
+            );
+            let Prices::Memory(history) = client_data else {
+                panic!("Nothing was spilled");
+            };
+            assert_eq!(history.get(1), price, "{:?}", policy);
+            assert_eq!(history.len(), 1, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn sessions_spill_once_they_reach_the_threshold() {
+        let dir = ScratchDir::new();
+        let config = Config {
+            capabilities: Capabilities::strict(protocol::PRICES),
+            wal_dir: None,
+            replay: None,
+            duplicates: DuplicatePolicy::Overwrite,
+            spill_dir: Some(dir.0.clone()),
+            spill_after: 4,
+        };
+        let quotas = Quotas::new(Quota {
+            max_memory: Some(4 * ENTRY_SIZE),
+            ..Quota::default()
+        });
+        let mut session = Session {
+            id: 7,
+            ..Session::default()
+        };
+
+        for timestamp in 0..4 {
+            quotas.reserve(ENTRY_SIZE).expect("Within the memory quota");
+            session.reserved += ENTRY_SIZE;
+            handle_insert(
+                &(timestamp, 10),
+                &mut session.client_data,
+                config.duplicates,
+            )
+            .expect("Insert");
+            spill_if_large(&mut session, &config, &quotas).expect("Spill");
+        }
+        assert!(matches!(session.client_data, Prices::Spilled(_)));
+        assert_eq!(session.reserved, 0);
+        quotas
+            .reserve(ENTRY_SIZE)
+            .expect("Spilled prices were released from the quota");
+
+        handle_insert(&(2, 50), &mut session.client_data, config.duplicates).expect("Insert");
+        handle_insert(&(9, 30), &mut session.client_data, config.duplicates).expect("Insert");
+        assert_eq!(
+            handle_query(&(0, 9), &mut session.client_data).expect("Query"),
+            Some(22)
+        );
+
+        drop(session);
+        assert_eq!(dir.files(), 0);
+    }
+
+    #[test]
+    fn replay_applies_the_same_policy() {
+        let wal = [insert(1, 10), insert(2, 30), insert(1, 20), insert(3, 50)].concat();
+
+        let replayed = |policy| {
+            replay_wal(&wal[..], policy)
+                .expect("WAL should replay")
+                .iter()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            replayed(DuplicatePolicy::Overwrite),
+            vec![(1, 20), (2, 30), (3, 50)]
+        );
+        assert_eq!(
+            replayed(DuplicatePolicy::Ignore),
+            vec![(1, 10), (2, 30), (3, 50)]
+        );
+        assert_eq!(
+            replayed(DuplicatePolicy::Disconnect),
+            vec![(1, 10), (2, 30)]
+        );
+    }
+
+    common::roundtrip_tests!(
+        message_roundtrip,
+        message(),
+        |message: &Message| <[u8; 9]>::from(message).to_vec(),
+        |bytes: &[u8]| Message::try_from(bytes)
+            .ok()
+            .map(|message| (message, bytes.len())),
+    );
+}
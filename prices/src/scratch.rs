@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// A directory of its own for each test, removed afterwards.
+pub(crate) struct ScratchDir(pub(crate) PathBuf);
+
+impl ScratchDir {
+    pub(crate) fn new() -> Self {
+        static CREATED: AtomicU64 = AtomicU64::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "prices-spill-{}-{}",
+            std::process::id(),
+            CREATED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("Couldn't create scratch directory");
+        ScratchDir(dir)
+    }
+
+    pub(crate) fn files(&self) -> usize {
+        fs::read_dir(&self.0)
+            .expect("Couldn't list scratch directory")
+            .count()
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,475 @@
+use crate::history::{self, PriceHistory};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Records per block: a block is the unit read from disk, 4 KiB by default.
+const BLOCK_LEN: usize = 512;
+
+// Each record is a big-endian timestamp and price.
+const RECORD_SIZE: usize = 8;
+
+// A session's prices with most of them on disk. New timestamps collect in an
+// in-memory PriceHistory of up to `buffer_limit` prices, which is then written
+// out as a sorted run file. Runs are merged as they pile up, smaller into
+// larger, so there are only ever logarithmically many.
+//
+// A timestamp is only ever in one place: inserting one that's already on
+// disk overwrites its price there. Runs never overlap, so a range's count
+// and sum is the buffer's plus each run's.
+//
+// In memory each run keeps one timestamp and one sum per block, a few bytes
+// per BLOCK_LEN prices, so looking up a timestamp reads one block and
+// summing a range reads two.
+#[derive(Debug)]
+pub struct SpilledHistory {
+    dir: PathBuf,
+    name: String,
+    buffer: PriceHistory,
+    buffer_limit: usize,
+    block_len: usize,
+    runs: Vec<Run>,
+    files_created: u64,
+}
+
+impl SpilledHistory {
+    // Runs are written to `dir` as `<name>-<n>.spill` and removed when they're
+    // merged or the history is dropped.
+    pub fn new(dir: &Path, name: &str, buffer_limit: usize) -> Self {
+        SpilledHistory::with_block_len(dir, name, buffer_limit, BLOCK_LEN)
+    }
+
+    fn with_block_len(dir: &Path, name: &str, buffer_limit: usize, block_len: usize) -> Self {
+        SpilledHistory {
+            dir: dir.to_path_buf(),
+            name: name.to_string(),
+            buffer: PriceHistory::new(),
+            buffer_limit: buffer_limit.max(1),
+            block_len,
+            runs: Vec::new(),
+            files_created: 0,
+        }
+    }
+
+    // Copies an in-memory history to disk as the first run.
+    pub fn from_history(
+        dir: &Path,
+        name: &str,
+        buffer_limit: usize,
+        history: &PriceHistory,
+    ) -> std::io::Result<Self> {
+        let mut spilled = SpilledHistory::new(dir, name, buffer_limit);
+        let mut writer = RunWriter::create(spilled.next_path(), spilled.block_len)?;
+        for (timestamp, price) in history.iter() {
+            writer.push(timestamp, price)?;
+        }
+        spilled.runs.push(writer.finish()?);
+        Ok(spilled)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len() + self.runs.iter().map(|run| run.len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, timestamp: i32) -> std::io::Result<Option<i32>> {
+        if let Some(price) = self.buffer.get(timestamp) {
+            return Ok(Some(price));
+        }
+        for run in &self.runs {
+            if let Some((_, price)) = run.find(timestamp)? {
+                return Ok(Some(price));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains_key(&self, timestamp: i32) -> std::io::Result<bool> {
+        Ok(self.get(timestamp)?.is_some())
+    }
+
+    // Sets the price at `timestamp`, returning the one it replaced.
+    pub fn insert(&mut self, timestamp: i32, price: i32) -> std::io::Result<Option<i32>> {
+        self.set(timestamp, price, true)
+    }
+
+    // Sets the price at `timestamp` only if it has none, returning the one
+    // already there instead.
+    pub fn insert_new(&mut self, timestamp: i32, price: i32) -> std::io::Result<Option<i32>> {
+        self.set(timestamp, price, false)
+    }
+
+    // Looks `timestamp` up once, replacing its price if `overwrite` is set
+    // and adding it to the buffer if it isn't anywhere yet.
+    fn set(&mut self, timestamp: i32, price: i32, overwrite: bool) -> std::io::Result<Option<i32>> {
+        for run in &mut self.runs {
+            if let Some((position, previous)) = run.find(timestamp)? {
+                if overwrite {
+                    run.overwrite(position, previous, price)?;
+                }
+                return Ok(Some(previous));
+            }
+        }
+
+        if !overwrite && let Some(previous) = self.buffer.get(timestamp) {
+            return Ok(Some(previous));
+        }
+        let previous = self.buffer.insert(timestamp, price);
+        if self.buffer.len() >= self.buffer_limit {
+            self.spill()?;
+        }
+        Ok(previous)
+    }
+
+    // The mean price from `min` to `max` inclusive, as PriceHistory::mean.
+    pub fn mean(&self, min: i32, max: i32) -> std::io::Result<i32> {
+        let (mut count, mut sum) = self.buffer.summary(min, max);
+        for run in &self.runs {
+            let (run_count, run_sum) = run.summary(min, max)?;
+            count += run_count;
+            sum += run_sum;
+        }
+        Ok(history::mean((count, sum)))
+    }
+
+    // Writes the buffer out as a run, then merges the newest runs while the
+    // last is at least half the size of the one before it.
+    fn spill(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer = RunWriter::create(self.next_path(), self.block_len)?;
+        for (timestamp, price) in self.buffer.iter() {
+            writer.push(timestamp, price)?;
+        }
+        self.runs.push(writer.finish()?);
+        self.buffer = PriceHistory::new();
+
+        while let [.., older, newer] = &self.runs[..]
+            && newer.len * 2 >= older.len
+        {
+            let (older, newer) = (older.reader()?, newer.reader()?);
+            let mut writer = RunWriter::create(self.next_path(), self.block_len)?;
+            merge(older, newer, &mut writer)?;
+            self.runs.truncate(self.runs.len() - 2);
+            self.runs.push(writer.finish()?);
+        }
+        Ok(())
+    }
+
+    fn next_path(&mut self) -> PathBuf {
+        self.files_created += 1;
+        self.dir
+            .join(format!("{}-{}.spill", self.name, self.files_created))
+    }
+}
+
+// One sorted run file, with the first timestamp and the price sum of each of
+// its blocks. The sums are in a Fenwick tree so an overwrite can update them.
+#[derive(Debug)]
+struct Run {
+    path: PathBuf,
+    file: File,
+    len: usize,
+    block_len: usize,
+    firsts: Vec<i32>,
+    sums: Fenwick,
+}
+
+impl Run {
+    fn read_block(&self, block: usize) -> std::io::Result<Vec<(i32, i32)>> {
+        let start = block * self.block_len;
+        let records = self.block_len.min(self.len - start);
+        let mut bytes = vec![0; records * RECORD_SIZE];
+
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start((start * RECORD_SIZE) as u64))?;
+        file.read_exact(&mut bytes)?;
+
+        Ok(bytes.chunks_exact(RECORD_SIZE).map(decode).collect())
+    }
+
+    // The block `timestamp` would be in, if it's not before the whole run.
+    fn block_of(&self, timestamp: i32) -> Option<usize> {
+        self.firsts
+            .partition_point(|&first| first <= timestamp)
+            .checked_sub(1)
+    }
+
+    // Where `timestamp` is in the run and its price, if it's there.
+    fn find(&self, timestamp: i32) -> std::io::Result<Option<(usize, i32)>> {
+        let Some(block) = self.block_of(timestamp) else {
+            return Ok(None);
+        };
+
+        let records = self.read_block(block)?;
+        Ok(records
+            .binary_search_by_key(&timestamp, |&(t, _)| t)
+            .ok()
+            .map(|i| (block * self.block_len + i, records[i].1)))
+    }
+
+    fn overwrite(&mut self, position: usize, previous: i32, price: i32) -> std::io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start((position * RECORD_SIZE + 4) as u64))?;
+        file.write_all(&price.to_be_bytes())?;
+        self.sums
+            .add(position / self.block_len, price as i64 - previous as i64);
+        Ok(())
+    }
+
+    // Count and sum of the prices at or before `timestamp`.
+    fn up_to(&self, timestamp: i32) -> std::io::Result<(u64, i64)> {
+        let Some(block) = self.block_of(timestamp) else {
+            return Ok((0, 0));
+        };
+
+        let records = self.read_block(block)?;
+        let within = records.partition_point(|&(t, _)| t <= timestamp);
+        let sum: i64 = records[..within].iter().map(|&(_, p)| p as i64).sum();
+
+        Ok((
+            (block * self.block_len + within) as u64,
+            self.sums.prefix(block) + sum,
+        ))
+    }
+
+    fn summary(&self, min: i32, max: i32) -> std::io::Result<(u64, i64)> {
+        if min > max {
+            return Ok((0, 0));
+        }
+
+        let (count, sum) = self.up_to(max)?;
+        let (below_count, below_sum) = match min.checked_sub(1) {
+            Some(t) => self.up_to(t)?,
+            None => (0, 0),
+        };
+        Ok((count - below_count, sum - below_sum))
+    }
+
+    // Reads every record, in order, through a handle of its own.
+    fn reader(&self) -> std::io::Result<RunReader> {
+        Ok(RunReader {
+            reader: BufReader::new(File::open(&self.path)?),
+            remaining: self.len,
+        })
+    }
+}
+
+// Builds a run from records pushed in timestamp order.
+struct RunWriter {
+    path: PathBuf,
+    file: File,
+    writer: BufWriter<File>,
+    len: usize,
+    block_len: usize,
+    firsts: Vec<i32>,
+    block_sums: Vec<i64>,
+}
+
+impl RunWriter {
+    fn create(path: PathBuf, block_len: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(RunWriter {
+            writer: BufWriter::new(file.try_clone()?),
+            path,
+            file,
+            len: 0,
+            block_len,
+            firsts: Vec::new(),
+            block_sums: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, timestamp: i32, price: i32) -> std::io::Result<()> {
+        if self.len.is_multiple_of(self.block_len) {
+            self.firsts.push(timestamp);
+            self.block_sums.push(0);
+        }
+        if let Some(sum) = self.block_sums.last_mut() {
+            *sum += price as i64;
+        }
+        self.writer.write_all(&timestamp.to_be_bytes())?;
+        self.writer.write_all(&price.to_be_bytes())?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<Run> {
+        self.writer.flush()?;
+
+        Ok(Run {
+            path: self.path,
+            file: self.file,
+            len: self.len,
+            block_len: self.block_len,
+            firsts: self.firsts,
+            sums: Fenwick::new(&self.block_sums),
+        })
+    }
+}
+
+struct RunReader {
+    reader: BufReader<File>,
+    remaining: usize,
+}
+
+impl RunReader {
+    fn next(&mut self) -> std::io::Result<Option<(i32, i32)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let mut record = [0; RECORD_SIZE];
+        self.reader.read_exact(&mut record)?;
+        Ok(Some(decode(&record)))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn decode(record: &[u8]) -> (i32, i32) {
+    let timestamp = i32::from_be_bytes(record[..4].try_into().expect("Record is 8 bytes"));
+    let price = i32::from_be_bytes(record[4..].try_into().expect("Record is 8 bytes"));
+    (timestamp, price)
+}
+
+// Merges two sorted runs that share no timestamps.
+fn merge(mut a: RunReader, mut b: RunReader, writer: &mut RunWriter) -> std::io::Result<()> {
+    let (mut x, mut y) = (a.next()?, b.next()?);
+
+    loop {
+        let (timestamp, price) = match (x, y) {
+            (Some(first), Some(second)) if first.0 <= second.0 => {
+                x = a.next()?;
+                first
+            }
+            (_, Some(second)) => {
+                y = b.next()?;
+                second
+            }
+            (Some(first), None) => {
+                x = a.next()?;
+                first
+            }
+            (None, None) => return Ok(()),
+        };
+        writer.push(timestamp, price)?;
+    }
+}
+
+// Prefix sums over block sums that can still be updated one block at a time.
+#[derive(Debug)]
+struct Fenwick {
+    tree: Vec<i64>,
+}
+
+impl Fenwick {
+    fn new(values: &[i64]) -> Self {
+        let mut fenwick = Fenwick {
+            tree: vec![0; values.len() + 1],
+        };
+        for (i, &value) in values.iter().enumerate() {
+            fenwick.add(i, value);
+        }
+        fenwick
+    }
+
+    fn add(&mut self, index: usize, delta: i64) {
+        let mut i = index + 1;
+        while i < self.tree.len() {
+            self.tree[i] += delta;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    // The sum of the first `count` values.
+    fn prefix(&self, count: usize) -> i64 {
+        let mut sum = 0;
+        let mut i = count;
+        while i > 0 {
+            sum += self.tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::ScratchDir;
+    use proptest::prelude::*;
+
+    #[test]
+    fn spilled_prices_are_overwritten_on_disk() {
+        let dir = ScratchDir::new();
+        let mut spilled = SpilledHistory::with_block_len(&dir.0, "test", 4, 2);
+
+        for timestamp in 0..20 {
+            assert_eq!(spilled.insert(timestamp, 10).expect("Insert"), None);
+        }
+        assert_eq!(spilled.buffer.len(), 0);
+        assert_eq!(spilled.len(), 20);
+
+        assert_eq!(spilled.insert(3, 50).expect("Overwrite"), Some(10));
+        assert_eq!(spilled.get(3).expect("Lookup"), Some(50));
+        assert_eq!(spilled.mean(0, 3).expect("Query"), 20);
+        assert_eq!(spilled.mean(i32::MIN, i32::MAX).expect("Query"), 12);
+        assert_eq!(spilled.len(), 20);
+
+        assert_eq!(spilled.insert_new(3, 70).expect("Insert"), Some(50));
+        assert_eq!(spilled.insert_new(20, 70).expect("Insert"), None);
+        assert_eq!(spilled.get(3).expect("Lookup"), Some(50));
+        assert_eq!(spilled.get(20).expect("Lookup"), Some(70));
+        assert_eq!(spilled.len(), 21);
+
+        assert!(dir.files() <= spilled.runs.len());
+        drop(spilled);
+        assert_eq!(dir.files(), 0);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig {
+            cases: 64,
+            ..ProptestConfig::default()
+        })]
+
+        #[test]
+        fn agrees_with_an_in_memory_history(
+            buffer_limit in 1usize..16,
+            block_len in 1usize..8,
+            inserts in prop::collection::vec((-100i32..100, any::<i32>()), 0..300),
+            queries in prop::collection::vec((-120i32..120, -120i32..120), 1..20),
+        ) {
+            let dir = ScratchDir::new();
+            let mut spilled = SpilledHistory::with_block_len(&dir.0, "test", buffer_limit, block_len);
+            let mut history = PriceHistory::new();
+
+            for (timestamp, price) in inserts {
+                prop_assert_eq!(spilled.insert(timestamp, price)?, history.insert(timestamp, price));
+            }
+
+            prop_assert_eq!(spilled.len(), history.len());
+            for (min, max) in queries {
+                prop_assert_eq!(spilled.mean(min, max)?, history.mean(min, max));
+                prop_assert_eq!(spilled.get(min)?, history.get(min));
+            }
+        }
+    }
+}
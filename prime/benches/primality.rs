@@ -0,0 +1,62 @@
+// Compares Miller–Rabin with the trial division it replaced. Trial division
+// near 2^63 takes seconds per check, so the largest input is only run
+// through Miller–Rabin. Inputs below the server's default sieve limit are
+// also looked up in a sieve, alongside what building it costs.
+//
+//   cargo bench -p prime
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use prime::primality::{Sieve, is_prime, trial_division};
+use std::hint::black_box;
+
+const PRIMES: [u64; 3] = [1_000_003, 4_294_967_291, 1_125_899_906_842_597];
+const NEAR_2_63: u64 = 9_223_372_036_854_775_783;
+
+fn primality(c: &mut Criterion) {
+    let mut group = c.benchmark_group("is_prime");
+    group.sample_size(10);
+
+    for n in PRIMES {
+        group.bench_with_input(BenchmarkId::new("trial_division", n), &n, |b, &n| {
+            b.iter(|| trial_division(black_box(n)))
+        });
+        group.bench_with_input(BenchmarkId::new("miller_rabin", n), &n, |b, &n| {
+            b.iter(|| is_prime(black_box(n)))
+        });
+    }
+    group.bench_with_input(
+        BenchmarkId::new("miller_rabin", NEAR_2_63),
+        &NEAR_2_63,
+        |b, &n| b.iter(|| is_prime(black_box(n))),
+    );
+
+    group.finish();
+}
+
+const SIEVE_LIMIT: u64 = 10_000_000;
+const BELOW_SIEVE_LIMIT: [u64; 3] = [97, 1_000_003, 9_999_991];
+
+fn sieve(c: &mut Criterion) {
+    let sieve = Sieve::new(SIEVE_LIMIT);
+    let mut group = c.benchmark_group("sieved");
+
+    for n in BELOW_SIEVE_LIMIT {
+        group.bench_with_input(BenchmarkId::new("miller_rabin", n), &n, |b, &n| {
+            b.iter(|| is_prime(black_box(n)))
+        });
+        group.bench_with_input(BenchmarkId::new("sieve", n), &n, |b, &n| {
+            b.iter(|| sieve.is_prime(black_box(n)))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("sieve_startup");
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("build", SIEVE_LIMIT), |b| {
+        b.iter(|| Sieve::new(black_box(SIEVE_LIMIT)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, primality, sieve);
+criterion_main!(benches);
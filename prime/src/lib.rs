@@ -0,0 +1,3 @@
+pub mod pool;
+pub mod primality;
+pub mod request;
@@ -1,134 +1,1283 @@
-use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
-use std::net::{TcpListener, TcpStream};
+use common::clock::{Clock, SystemClock};
+use common::protocol::{self, Capabilities};
+use common::quota::Quotas;
+use futures_util::{SinkExt, StreamExt};
+use prime::pool::Pool;
+use prime::primality::Sieve;
+use prime::request::{
+    self, FactorizePartial, FactorizeResponse, MalformedResponse, Method, NextPrimeResponse,
+    Number, PrimeRequest, PrimeResponse, TimeoutResponse,
+};
+use serde::Serialize;
+use serde::de::IgnoredAny;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct PrimeRequest {
-    method: String,
-    number: f64,
+const LOCAL_ADDR: &str = "0.0.0.0:8080";
+
+// Trial divisions between progress lines while factorizing, so a client
+// waiting on a large input keeps hearing from us and a dead one is noticed.
+const FACTORIZE_PROGRESS_INTERVAL: u64 = 1 << 24;
+
+// Trial divisions between deadline checks, keeping the clock off the hot path.
+const DEADLINE_CHECK_INTERVAL: u64 = 1 << 16;
+
+// Factorization lines buffered between the computation and the connection.
+const FACTORIZE_BUFFER: usize = 16;
+
+// Longest request line accepted by default, not counting the newline.
+const DEFAULT_MAX_LINE_LEN: usize = 1 << 20;
+
+// Largest datagram the UDP listener reads.
+const MAX_DATAGRAM_LEN: usize = 64 * 1024;
+
+// Requests read ahead of the responses written for them, per connection.
+const MAX_PIPELINED: usize = 64;
+
+// Requests queued for the worker pool per worker before connections block.
+const QUEUE_PER_WORKER: usize = 16;
+
+// Numbers up to this are looked up in a sieve built at startup, which takes
+// about 600 KiB.
+const DEFAULT_SIEVE_LIMIT: u64 = 10_000_000;
+
+// `deadline` bounds the compute time of a single request. Past it, the
+// timeout extension answers with a timeout response and strict mode closes
+// the connection. `workers` sizes the pool primality checks run on, and
+// defaults to one per core. Lines longer than `max_line_len` bytes, not
+// counting the newline, are malformed. `udp`, if set, also answers requests
+// sent as datagrams on that address. isPrime checks up to the `sieve`'s limit
+// are a table lookup. A connection that has sent `max_requests` lines gets
+// the responses to them and is then closed.
+//
+// A malformed request is answered with `malformed_response`, which needn't
+// be JSON. With `echo_malformed` it must be a JSON object, and the offending
+// line is added to it as `request`. Unless `disconnect_on_malformed` is
+// cleared, the connection is then closed. Overlong and non-UTF-8 lines always
+// close it, since the line codec can't resume after them.
+#[derive(Debug, Clone)]
+struct Config {
+    addr: String,
+    udp: Option<String>,
+    capabilities: Capabilities,
+    deadline: Option<Duration>,
+    workers: usize,
+    max_line_len: usize,
+    max_requests: Option<u64>,
+    malformed_response: String,
+    echo_malformed: bool,
+    disconnect_on_malformed: bool,
+    sieve: Arc<Sieve>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: LOCAL_ADDR.to_string(),
+            udp: None,
+            capabilities: Capabilities::strict(protocol::PRIME),
+            deadline: None,
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            max_requests: None,
+            malformed_response: serde_json::to_string(&MalformedResponse::new())
+                .expect("Couldn't serialize JSON"),
+            echo_malformed: false,
+            disconnect_on_malformed: true,
+            sieve: Arc::new(Sieve::new(DEFAULT_SIEVE_LIMIT)),
+        }
+    }
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            if config.capabilities.parse_arg(&arg, &mut args)? {
+                continue;
+            }
+
+            match arg.as_str() {
+                "--bind" => {
+                    config.addr = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --bind".to_string())?;
+                }
+                "--udp" => {
+                    config.udp = Some(
+                        args.next()
+                            .ok_or_else(|| "Expected a value after --udp".to_string())?,
+                    );
+                }
+                "--deadline-ms" => {
+                    let millis = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --deadline-ms".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --deadline-ms: {}", e))?;
+                    config.deadline = Some(Duration::from_millis(millis));
+                }
+                "--workers" => {
+                    config.workers = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --workers".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --workers: {}", e))?;
+                    if config.workers == 0 {
+                        return Err("--workers must be at least 1".to_string());
+                    }
+                }
+                "--max-line-len" => {
+                    config.max_line_len = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --max-line-len".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --max-line-len: {}", e))?;
+                    if config.max_line_len == 0 {
+                        return Err("--max-line-len must be at least 1".to_string());
+                    }
+                }
+                "--max-requests" => {
+                    let max = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --max-requests".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --max-requests: {}", e))?;
+                    if max == 0 {
+                        return Err("--max-requests must be at least 1".to_string());
+                    }
+                    config.max_requests = Some(max);
+                }
+                "--malformed-response" => {
+                    config.malformed_response = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --malformed-response".to_string())?;
+                }
+                "--echo-malformed" => config.echo_malformed = true,
+                "--keep-open-on-malformed" => config.disconnect_on_malformed = false,
+                "--sieve-limit" => {
+                    let limit = args
+                        .next()
+                        .ok_or_else(|| "Expected a value after --sieve-limit".to_string())?
+                        .parse()
+                        .map_err(|e| format!("Invalid --sieve-limit: {}", e))?;
+                    config.sieve = Arc::new(Sieve::new(limit));
+                }
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        if config.echo_malformed
+            && serde_json::from_str::<Map<String, Value>>(&config.malformed_response).is_err()
+        {
+            return Err(
+                "--echo-malformed needs a JSON object as the malformed response".to_string(),
+            );
+        }
+
+        Ok(config)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct PrimeResponse {
-    method: String,
-    prime: bool,
+struct Deadline<'a> {
+    clock: &'a dyn Clock,
+    at: Option<Instant>,
 }
 
-impl PrimeResponse {
-    fn new(req: &PrimeRequest) -> Self {
-        PrimeResponse {
-            method: "isPrime".to_string(),
-            prime: is_prime(req.number),
+impl<'a> Deadline<'a> {
+    fn new(clock: &'a dyn Clock, budget: Option<Duration>) -> Self {
+        Deadline {
+            clock,
+            at: budget.map(|budget| clock.now() + budget),
         }
     }
+
+    fn passed(&self) -> bool {
+        self.at.is_some_and(|at| self.clock.now() >= at)
+    }
+}
+
+// One client's request lines in and response lines out, with the traffic
+// charged against the quotas.
+struct Connection<S> {
+    lines: Framed<S, LinesCodec>,
+    quotas: Arc<Quotas>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct MalformedResponse {
-    method: String,
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S, max_line_len: usize, quotas: Arc<Quotas>) -> Self {
+        Connection {
+            lines: Framed::new(stream, LinesCodec::new_with_max_length(max_line_len)),
+            quotas,
+        }
+    }
+
+    // Overlong and non-UTF-8 lines come back as InvalidData errors.
+    async fn next_line(&mut self) -> Option<std::io::Result<String>> {
+        let line = self.lines.next().await?.map_err(codec_error);
+        if let Ok(line) = &line {
+            self.quotas.throttle_in_async(line.len() + 1).await;
+        }
+        Some(line)
+    }
+
+    async fn send_line(&mut self, line: String) -> std::io::Result<()> {
+        self.quotas.throttle_out_async(line.len() + 1).await;
+        self.lines.send(line).await.map_err(codec_error)
+    }
+
+    async fn write_json<T: Serialize>(&mut self, value: &T) -> std::io::Result<()> {
+        self.send_line(serde_json::to_string(value)?).await
+    }
+
+    // Flushes and shuts down the write half, so the client reads everything
+    // sent and then EOF rather than a reset.
+    async fn close(&mut self) -> std::io::Result<()> {
+        SinkExt::<String>::close(&mut self.lines)
+            .await
+            .map_err(codec_error)
+    }
 }
 
-impl MalformedResponse {
-    fn new() -> Self {
-        MalformedResponse {
-            method: "Malformed".to_string(),
+fn codec_error(e: LinesCodecError) -> Error {
+    match e {
+        LinesCodecError::MaxLineLengthExceeded => {
+            Error::new(ErrorKind::InvalidData, "Request line is too long")
         }
+        LinesCodecError::Io(e) => e,
     }
+}
+
+// Hands a line to the connection writing factorize's output. Fails once the
+// connection has stopped listening.
+fn emit<T: Serialize>(lines: &mpsc::Sender<String>, value: &T) -> std::io::Result<()> {
+    lines
+        .blocking_send(serde_json::to_string(value)?)
+        .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Connection went away"))
+}
+
+// Factorizes by trial division, emitting a partial line whenever a factor is
+// found and every FACTORIZE_PROGRESS_INTERVAL divisions. A failed write means
+// the client has gone away, which cancels the computation.
+fn factorize(n: Number, lines: &mpsc::Sender<String>, deadline: &Deadline) -> std::io::Result<()> {
+    let Some(number) = n.whole().filter(|&n| n >= 2) else {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Can only factorize integers greater than 1",
+        ));
+    };
+
+    let mut remaining = number;
+    let mut factors = Vec::new();
+    let mut divisor = 2u64;
+    let mut since_progress = 0u64;
 
-    fn write(self, writer: &mut BufWriter<TcpStream>) -> std::io::Result<()> {
-        writer.write_all(&serde_json::to_vec(&self).expect("Couldn't serialize to JSON"))?;
-        writer.write_all(b"\n")?;
-        writer.flush()?;
+    while divisor <= remaining / divisor {
+        if remaining.is_multiple_of(divisor) {
+            remaining /= divisor;
+            factors.push(divisor);
+            emit(
+                lines,
+                &FactorizePartial {
+                    method: "factorize",
+                    partial: true,
+                    factors: &factors,
+                    checked: divisor,
+                },
+            )?;
+            continue;
+        }
+
+        divisor += if divisor == 2 { 1 } else { 2 };
+        since_progress += 1;
+
+        if since_progress.is_multiple_of(DEADLINE_CHECK_INTERVAL) && deadline.passed() {
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                "Factorization deadline passed",
+            ));
+        }
+
+        if since_progress == FACTORIZE_PROGRESS_INTERVAL {
+            since_progress = 0;
+            emit(
+                lines,
+                &FactorizePartial {
+                    method: "factorize",
+                    partial: true,
+                    factors: &factors,
+                    checked: divisor,
+                },
+            )?;
+        }
+    }
 
-        Ok(())
+    if remaining > 1 {
+        factors.push(remaining);
     }
+
+    emit(
+        lines,
+        &FactorizeResponse {
+            method: "factorize",
+            number,
+            factors: &factors,
+        },
+    )
 }
 
-fn is_prime(n: f64) -> bool {
-    if n < 0.0 || n.fract() != 0.0 {
-        return false;
+// Runs factorize off the runtime, forwarding its lines to the client as
+// they come. Dropping the receiver when a send fails cancels the work.
+async fn handle_factorize<S: AsyncRead + AsyncWrite + Unpin>(
+    n: Number,
+    connection: &mut Connection<S>,
+    config: &Config,
+    clock: &Arc<dyn Clock>,
+) -> std::io::Result<()> {
+    let (lines, mut emitted) = mpsc::channel(FACTORIZE_BUFFER);
+    let clock = clock.clone();
+    let budget = config.deadline;
+    let work = tokio::task::spawn_blocking(move || {
+        factorize(n, &lines, &Deadline::new(clock.as_ref(), budget))
+    });
+
+    while let Some(line) = emitted.recv().await {
+        connection.send_line(line).await?;
     }
 
-    let num = n as u64;
+    work.await.map_err(Error::other)?
+}
+
+// A response owed to the client. Its work is already on the pool; awaiting
+// it gives the line to write. Each connection queues these and writes them
+// strictly in request order, however the pool finishes them.
+type Pending = Pin<Box<dyn Future<Output = std::io::Result<String>> + Send>>;
+
+fn pending_response<T: Serialize + Send + 'static>(
+    result: oneshot::Receiver<T>,
+    method: Method,
+    config: &Config,
+) -> Pending {
+    Box::pin(answer_by(
+        result,
+        method,
+        job_deadline(config),
+        config.capabilities.has("timeout"),
+    ))
+}
+
+// When a job put on the pool now has to have answered by, if there's a
+// deadline at all. Time spent queued counts against it.
+fn job_deadline(config: &Config) -> Option<tokio::time::Instant> {
+    config
+        .deadline
+        .map(|budget| tokio::time::Instant::now() + budget)
+}
+
+// Waits for a pool job's answer until `deadline`. Past it, the timeout
+// extension gets a TimeoutResponse instead and strict mode a TimedOut error,
+// which closes the connection. A job can't be stopped once it's queued, so a
+// late one still runs and its answer is dropped.
+async fn answer_by<T: Serialize>(
+    result: oneshot::Receiver<T>,
+    method: Method,
+    deadline: Option<tokio::time::Instant>,
+    timeout_response: bool,
+) -> std::io::Result<String> {
+    let result = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, result).await,
+        None => Ok(result.await),
+    };
 
-    match num {
-        0 | 1 => false,
-        2 => true,
-        _ if num.is_multiple_of(2) => false,
-        _ => {
-            let limit = num.isqrt() + 1;
-            !(3..=limit).step_by(2).any(|i| num.is_multiple_of(i))
+    match result {
+        Ok(response) => {
+            let response = response.map_err(|_| Error::other("Worker pool went away"))?;
+            Ok(serde_json::to_string(&response)?)
         }
+        Err(_) if timeout_response => Ok(serde_json::to_string(&TimeoutResponse {
+            method,
+            prime: (method == Method::IsPrime).then_some(false),
+            timeout: true,
+        })?),
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Request deadline passed")),
+    }
+}
+
+// Writes every queued response, in order.
+async fn flush<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    pending: &mut VecDeque<Pending>,
+) -> std::io::Result<()> {
+    while let Some(response) = pending.pop_front() {
+        let line = response.await?;
+        connection.send_line(line).await?;
+    }
+    Ok(())
+}
+
+// Puts a batch of isPrime requests on the pool at once, returning where
+// each answer will arrive.
+async fn submit_batch(
+    requests: &[PrimeRequest],
+    sieve: &Arc<Sieve>,
+    pool: &Pool,
+) -> std::io::Result<Vec<oneshot::Receiver<PrimeResponse>>> {
+    if requests.iter().any(|req| req.method != Method::IsPrime) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Batches may only contain isPrime requests",
+        ));
+    }
+
+    let mut results = Vec::with_capacity(requests.len());
+    for req in requests {
+        let number = req.number;
+        let sieve = sieve.clone();
+        results.push(
+            pool.submit(move || PrimeResponse::new(request::is_prime_sieved(number, &sieve)))
+                .await,
+        );
+    }
+    Ok(results)
+}
+
+// Collects a submitted batch's answers in request order, as one JSON array.
+// The whole batch shares one deadline, and entries still unanswered past it
+// each get a timeout response.
+async fn collect_batch(
+    results: Vec<oneshot::Receiver<PrimeResponse>>,
+    deadline: Option<tokio::time::Instant>,
+    timeout_response: bool,
+) -> std::io::Result<String> {
+    let mut responses = Vec::with_capacity(results.len());
+    for result in results {
+        responses.push(answer_by(result, Method::IsPrime, deadline, timeout_response).await?);
     }
+    Ok(format!("[{}]", responses.join(",")))
 }
 
-fn handle_prime_request(
+// The batch extension takes a JSON array of requests on one line and answers
+// with one array of responses. A single malformed entry makes the whole line
+// malformed.
+async fn handle_batch(
     request_str: &str,
-    writer: &mut BufWriter<TcpStream>,
+    pending: &mut VecDeque<Pending>,
+    config: &Config,
+    pool: &Pool,
 ) -> std::io::Result<()> {
-    let req: PrimeRequest = serde_json::from_str(request_str)?;
-    println!("{:?}", req);
+    let requests: Vec<PrimeRequest> =
+        request::parse(request_str, config.capabilities.has("lenient-trailing"))?;
+
+    let deadline = job_deadline(config);
+    let results = submit_batch(&requests, &config.sieve, pool).await?;
+    pending.push_back(Box::pin(collect_batch(
+        results,
+        deadline,
+        config.capabilities.has("timeout"),
+    )));
+    Ok(())
+}
+
+// Starts answering one request. isPrime work goes to the pool and its
+// response is queued; factorize streams its own lines, so everything queued
+// before it is written first.
+async fn handle_prime_request<S: AsyncRead + AsyncWrite + Unpin>(
+    request_str: &str,
+    connection: &mut Connection<S>,
+    pending: &mut VecDeque<Pending>,
+    config: &Config,
+    clock: &Arc<dyn Clock>,
+    pool: &Pool,
+) -> std::io::Result<()> {
+    if config.capabilities.has("batch") && request_str.trim_start().starts_with('[') {
+        return handle_batch(request_str, pending, config, pool).await;
+    }
+
+    let req: PrimeRequest =
+        request::parse(request_str, config.capabilities.has("lenient-trailing"))?;
+
+    match req.method {
+        Method::IsPrime => {
+            let number = req.number;
+            let sieve = config.sieve.clone();
+            let result = pool
+                .submit(move || PrimeResponse::new(request::is_prime_sieved(number, &sieve)))
+                .await;
+            pending.push_back(pending_response(result, req.method, config));
+            Ok(())
+        }
+        Method::NextPrime if config.capabilities.has("next-prime") => {
+            let number = req.number;
+            if !request::has_next_prime(number) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "No larger prime fits in a u64",
+                ));
+            }
+            let result = pool
+                .submit(move || request::next_prime(number).map(NextPrimeResponse::new))
+                .await;
+            pending.push_back(pending_response(result, req.method, config));
+            Ok(())
+        }
+        Method::Factorize if config.capabilities.has("factorize") => {
+            flush(connection, pending).await?;
+            match handle_factorize(req.number, connection, config, clock).await {
+                Err(e) if e.kind() == ErrorKind::TimedOut && config.capabilities.has("timeout") => {
+                    let timeout = TimeoutResponse {
+                        method: req.method,
+                        prime: None,
+                        timeout: true,
+                    };
+                    connection.write_json(&timeout).await
+                }
+                result => result,
+            }
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "Invalid method")),
+    }
+}
 
-    if req.method != "isPrime" {
-        return Err(Error::new(ErrorKind::InvalidData, "Invalid method"));
+// With the concatenated extension a line may hold several requests back to
+// back, like `{...}{...}`, and each is answered in turn. The first one that
+// doesn't parse makes the rest of the line malformed, after the ones before
+// it have been answered.
+async fn handle_line<S: AsyncRead + AsyncWrite + Unpin>(
+    line: &str,
+    connection: &mut Connection<S>,
+    pending: &mut VecDeque<Pending>,
+    config: &Config,
+    clock: &Arc<dyn Clock>,
+    pool: &Pool,
+) -> std::io::Result<()> {
+    if !config.capabilities.has("concatenated") {
+        return handle_prime_request(line, connection, pending, config, clock, pool).await;
     }
 
-    let resp = PrimeResponse::new(&req);
-    writer
-        .write_all(&serde_json::to_vec(&resp).expect("Couldn't serialize JSON to bytes"))
-        .expect("Couldn't write response to buffer");
+    let mut values = serde_json::Deserializer::from_str(line).into_iter::<IgnoredAny>();
+    let mut start = 0;
+    while let Some(value) = values.next() {
+        value.map_err(request::malformed)?;
+        let end = values.byte_offset();
+        handle_prime_request(&line[start..end], connection, pending, config, clock, pool).await?;
+        start = end;
+    }
 
-    writer
-        .write_all(b"\n")
-        .expect("Couldn't write newline to writer");
-    writer.flush().expect("Couldn't flush writer");
+    if start == 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "Empty request line"));
+    }
     Ok(())
 }
 
-fn handle_client(stream: TcpStream) {
-    let write_stream = stream
-        .try_clone()
-        .expect("Couldn't clone stream for writing");
+// The line answering a malformed request. `offending` is the line that was
+// malformed, when there is one to echo; overlong and non-UTF-8 lines aren't
+// kept.
+fn malformed_line(config: &Config, offending: Option<&str>) -> String {
+    let response = &config.malformed_response;
+    match offending {
+        Some(line) if config.echo_malformed => {
+            let mut object: Map<String, Value> =
+                serde_json::from_str(response).expect("Checked to be a JSON object at startup");
+            object.insert("request".to_string(), line.into());
+            Value::Object(object).to_string()
+        }
+        _ => response.clone(),
+    }
+}
+
+// What one connection asked of us, logged as a JSON line when it closes so a
+// failed checker run can be traced back to its connection. Latency is the
+// time spent with at least one request read but not yet answered.
+#[derive(Debug, Default, PartialEq)]
+struct ConnectionStats {
+    requests: u64,
+    malformed: u64,
+    latency: Duration,
+}
+
+impl ConnectionStats {
+    fn log(&self, peer: &str) {
+        let line = serde_json::json!({
+            "event": "disconnect",
+            "peer": peer,
+            "requests": self.requests,
+            "malformed": self.malformed,
+            "latency_ms": self.latency.as_millis() as u64,
+        });
+        println!("{}", line);
+    }
+}
 
-    let mut reader = BufReader::new(stream);
-    let mut writer = BufWriter::new(write_stream);
+// Reads ahead of the responses still being computed, up to MAX_PIPELINED of
+// them, while writing each response as soon as everything before it is out.
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: String,
+    config: Config,
+    clock: Arc<dyn Clock>,
+    quotas: Arc<Quotas>,
+    pool: Pool,
+) -> ConnectionStats {
+    let mut connection = Connection::new(stream, config.max_line_len, quotas.clone());
+    let mut stats = ConnectionStats::default();
 
-    let mut line = String::new();
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("[{}] Rejecting client: {}", peer, e);
+            let _ = connection.send_line(malformed_line(&config, None)).await;
+            return stats;
+        }
+    };
+
+    let mut pending: VecDeque<Pending> = VecDeque::new();
+    let mut busy_since: Option<Instant> = None;
     loop {
-        line.clear();
-        match reader.read_line(&mut line) {
-            Ok(0) => break,
-            Ok(_) => {
-                if let Err(e) = handle_prime_request(&line, &mut writer) {
-                    eprintln!("Failed to handle request: {}", e);
-                    let resp = MalformedResponse::new();
-                    if let Err(e) = resp.write(&mut writer) {
-                        eprintln!("Failed to send malformed response: {}", e);
-                    };
-                    break;
+        if pending.is_empty()
+            && let Some(since) = busy_since.take()
+        {
+            stats.latency += clock.now() - since;
+        }
+
+        // Past the request limit, nothing more is read; the connection closes
+        // once everything read has been answered.
+        let exhausted = config.max_requests.is_some_and(|max| stats.requests >= max);
+        if exhausted && pending.is_empty() {
+            break;
+        }
+
+        let mut offending = None;
+        let result = tokio::select! {
+            response = async { pending.front_mut().expect("Queue checked non-empty").await },
+                if !pending.is_empty() =>
+            {
+                pending.pop_front();
+                match response {
+                    Ok(line) => connection.send_line(line).await,
+                    Err(e) => Err(e),
+                }
+            }
+            line = connection.next_line(), if !exhausted && pending.len() < MAX_PIPELINED => match line {
+                Some(Ok(line)) => {
+                    stats.requests += 1;
+                    busy_since.get_or_insert_with(|| clock.now());
+                    let result =
+                        handle_line(&line, &mut connection, &mut pending, &config, &clock, &pool)
+                            .await;
+                    offending = Some(line);
+                    result
+                }
+                // Overlong or not UTF-8: malformed, like a request that doesn't parse.
+                Some(Err(e)) if e.kind() == ErrorKind::InvalidData => {
+                    stats.requests += 1;
+                    busy_since.get_or_insert_with(|| clock.now());
+                    Err(e)
                 }
+                Some(Err(_)) => break,
+                // The client is done asking, but is still owed what's queued.
+                None => match flush(&mut connection, &mut pending).await {
+                    Ok(()) => break,
+                    Err(e) => Err(e),
+                },
+            },
+        };
+
+        if let Err(e) = result {
+            eprintln!("[{}] Failed to handle request: {}", peer, e);
+            // Only a malformed request is answered. A timeout, a client that
+            // hung up mid-response or a lost worker just ends the connection.
+            if e.kind() != ErrorKind::InvalidData {
+                break;
+            }
+
+            // Requests before the malformed one are still answered first.
+            stats.malformed += 1;
+            let response = malformed_line(&config, offending.as_deref());
+            if !config.disconnect_on_malformed && offending.is_some() {
+                pending.push_back(Box::pin(std::future::ready(Ok(response))));
+                continue;
             }
-            Err(_) => break,
+            let _ = flush(&mut connection, &mut pending).await;
+            if let Err(e) = connection.send_line(response).await {
+                eprintln!("[{}] Failed to send malformed response: {}", peer, e);
+            };
+            break;
+        }
+    }
+
+    if let Err(e) = connection.close().await {
+        eprintln!("[{}] Couldn't close connection: {}", peer, e);
+    }
+
+    if let Some(since) = busy_since {
+        stats.latency += clock.now() - since;
+    }
+    stats.log(&peer);
+    stats
+}
+
+// Answers one datagram holding one request, with the same parsing and
+// primality checks as a TCP line. There's no connection to close, so
+// whatever TCP would treat as malformed gets the malformed response, as does
+// any method but isPrime. For the same reason the deadline isn't applied.
+async fn answer_datagram(datagram: &[u8], config: &Config, pool: &Pool) -> String {
+    let lenient = config.capabilities.has("lenient-trailing");
+    let line = std::str::from_utf8(datagram).ok();
+    let answer = async {
+        let line = line.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Not UTF-8"))?;
+
+        if config.capabilities.has("batch") && line.trim_start().starts_with('[') {
+            let requests: Vec<PrimeRequest> = request::parse(line, lenient)?;
+            let results = submit_batch(&requests, &config.sieve, pool).await?;
+            return collect_batch(results, None, false).await;
         }
+
+        let req: PrimeRequest = request::parse(line, lenient)?;
+        if req.method != Method::IsPrime {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid method"));
+        }
+        let number = req.number;
+        let sieve = config.sieve.clone();
+        let prime = pool
+            .submit(move || request::is_prime_sieved(number, &sieve))
+            .await
+            .await
+            .map_err(|_| Error::other("Worker pool went away"))?;
+        Ok::<_, Error>(serde_json::to_string(&PrimeResponse::new(prime))?)
+    };
+
+    let response = answer.await.unwrap_or_else(|e| {
+        eprintln!("Failed to handle datagram: {}", e);
+        malformed_line(config, line.map(|line| line.trim_end_matches(['\r', '\n'])))
+    });
+    response + "\n"
+}
+
+async fn run_udp(socket: UdpSocket, config: Config, quotas: Arc<Quotas>, pool: Pool) {
+    let socket = Arc::new(socket);
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Couldn't receive datagram: {}", e);
+                continue;
+            }
+        };
+        quotas.throttle_in_async(len).await;
+
+        let datagram = buf[..len].to_vec();
+        let socket = socket.clone();
+        let config = config.clone();
+        let quotas = quotas.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let response = answer_datagram(&datagram, &config, &pool).await;
+            quotas.throttle_out_async(response.len()).await;
+            if let Err(e) = socket.send_to(response.as_bytes(), peer).await {
+                eprintln!("Couldn't answer {}: {}", peer, e);
+            }
+        });
     }
 }
 
-fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8080")?;
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = Config::from_args().map_err(std::io::Error::other)?;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let quotas = Quotas::from_env().map_err(std::io::Error::other)?;
+    let pool = Pool::new(config.workers, config.workers * QUEUE_PER_WORKER);
+    let listener = TcpListener::bind(&config.addr).await?;
+
+    if let Some(addr) = &config.udp {
+        let socket = UdpSocket::bind(addr).await?;
+        tokio::spawn(run_udp(
+            socket,
+            config.clone(),
+            quotas.clone(),
+            pool.clone(),
+        ));
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                thread::spawn(move || {
-                    handle_client(stream);
-                });
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_client(
+                    stream,
+                    peer.to_string(),
+                    config.clone(),
+                    clock.clone(),
+                    quotas.clone(),
+                    pool.clone(),
+                ));
             }
             Err(e) => {
                 eprintln!("Connection failed: {}", e);
             }
         }
     }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::quota::Quota;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Runs one client session over an in-memory stream, sending `input` and
+    // returning everything the server wrote before it hung up.
+    async fn session(config: Config, input: Vec<u8>) -> String {
+        session_with_stats(config, input).await.0
+    }
+
+    async fn session_with_stats(config: Config, input: Vec<u8>) -> (String, ConnectionStats) {
+        let (client, server) = tokio::io::duplex(1 << 12);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let server = tokio::spawn(handle_client(
+            server,
+            "test".to_string(),
+            config,
+            clock,
+            Quotas::new(Quota::default()),
+            Pool::new(2, 8),
+        ));
+
+        let (mut reader, mut writer) = tokio::io::split(client);
+        tokio::spawn(async move {
+            // The server may hang up before reading everything.
+            let _ = writer.write_all(&input).await;
+            let _ = writer.shutdown().await;
+        });
+
+        let mut output = String::new();
+        reader
+            .read_to_string(&mut output)
+            .await
+            .expect("Couldn't read responses");
+        let stats = server.await.expect("Session panicked");
+        (output, stats)
+    }
+
+    #[test]
+    fn factorize_rejects_numbers_past_u64() {
+        let (lines, mut emitted) = mpsc::channel(FACTORIZE_BUFFER);
+        let deadline = Deadline::new(&SystemClock, None);
+
+        for n in [
+            Number::Float(18446744073709551616.0),
+            Number::Float(1e20),
+            Number::Float(f64::INFINITY),
+            Number::Float(6.5),
+            Number::Int(1),
+            Number::Neg,
+        ] {
+            let err = factorize(n, &lines, &deadline).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", n);
+        }
+        assert!(emitted.try_recv().is_err());
+
+        factorize(Number::Int(12), &lines, &deadline).unwrap();
+        assert!(emitted.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn responses_are_one_line_per_request() {
+        let input =
+            b"{\"method\":\"isPrime\",\"number\":7}\r\n{\"method\":\"isPrime\",\"number\":8}";
+        assert_eq!(
+            session(Config::default(), input.to_vec()).await,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"isPrime\",\"prime\":false}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn lines_longer_than_the_limit_are_malformed() {
+        let config = Config {
+            max_line_len: 40,
+            ..Config::default()
+        };
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+            "{\"method\":\"isPrime\",\"number\":7,\"padding\":\"xxxx\"}\n",
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+        );
+        assert_eq!(
+            session(config, input.as_bytes().to_vec()).await,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"Malformed\"}\n"
+        );
+
+        // A line far longer than the limit is cut off without waiting for
+        // the rest of it.
+        let config = Config {
+            max_line_len: 1 << 10,
+            ..Config::default()
+        };
+        assert_eq!(
+            session(config, vec![b'1'; 1 << 20]).await,
+            "{\"method\":\"Malformed\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_are_answered_in_order() {
+        let pool = Pool::new(4, 16);
+        // The first number is past the sieve's limit, the others within it.
+        let sieve = Arc::new(Sieve::new(1_000));
+        let requests: Vec<PrimeRequest> = serde_json::from_str(
+            r#"[{"method":"isPrime","number":1000000007},
+                {"method":"isPrime","number":4},
+                {"method":"isPrime","number":7}]"#,
+        )
+        .expect("Batch should parse");
+
+        let results = submit_batch(&requests, &sieve, &pool)
+            .await
+            .expect("Batch should be submitted");
+        assert_eq!(
+            collect_batch(results, None, false)
+                .await
+                .expect("Batch should be answered"),
+            concat!(
+                "[{\"method\":\"isPrime\",\"prime\":true},",
+                "{\"method\":\"isPrime\",\"prime\":false},",
+                "{\"method\":\"isPrime\",\"prime\":true}]",
+            )
+        );
+
+        let factorize = [PrimeRequest {
+            method: Method::Factorize,
+            number: Number::Int(12),
+        }];
+        assert!(submit_batch(&factorize, &sieve, &pool).await.is_err());
+        assert!(serde_json::from_str::<Vec<PrimeRequest>>(r#"[{"method":"isPrime"}]"#).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pool_jobs_past_the_deadline_time_out() {
+        let pool = Pool::new(1, 4);
+        let deadline = Some(tokio::time::Instant::now() + Duration::from_millis(50));
+
+        // Holds the only worker so the job queued behind it can't finish
+        // before the deadline.
+        let (release, held) = std::sync::mpsc::channel::<()>();
+        let _busy = pool
+            .submit(move || {
+                let _ = held.recv();
+            })
+            .await;
+
+        let result = pool.submit(|| PrimeResponse::new(true)).await;
+        assert_eq!(
+            answer_by(result, Method::IsPrime, deadline, true)
+                .await
+                .expect("Timeout extension should answer"),
+            "{\"method\":\"isPrime\",\"prime\":false,\"timeout\":true}"
+        );
+
+        let result = pool.submit(|| PrimeResponse::new(true)).await;
+        let err = answer_by(result, Method::IsPrime, deadline, false)
+            .await
+            .expect_err("Strict mode should fail");
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        release.send(()).expect("Worker should be waiting");
+        let result = pool.submit(|| PrimeResponse::new(true)).await;
+        assert_eq!(
+            answer_by(result, Method::IsPrime, None, false)
+                .await
+                .expect("Job without a deadline should be answered"),
+            "{\"method\":\"isPrime\",\"prime\":true}"
+        );
+    }
+
+    #[tokio::test]
+    async fn concatenated_requests_are_answered_in_turn() {
+        let mut config = Config::default();
+        config
+            .capabilities
+            .enable("concatenated")
+            .expect("concatenated is a prime extension");
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7}{\"method\":\"isPrime\",\"number\":8}\n",
+            " {\"method\":\"isPrime\",\"number\":11} \n",
+            "{\"method\":\"isPrime\",\"number\":13}{\"method\":\n",
+        );
+        assert_eq!(
+            session(config, input.as_bytes().to_vec()).await,
+            concat!(
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"isPrime\",\"prime\":false}\n",
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"Malformed\"}\n",
+            )
+        );
+
+        // Without the extension the whole line is one malformed request.
+        let input = "{\"method\":\"isPrime\",\"number\":7}{\"method\":\"isPrime\",\"number\":8}\n";
+        assert_eq!(
+            session(Config::default(), input.as_bytes().to_vec()).await,
+            "{\"method\":\"Malformed\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn trailing_garbage_is_malformed_unless_lenient() {
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7}   \n",
+            "{\"method\":\"isPrime\",\"number\":7} trailing junk\n",
+        );
+        assert_eq!(
+            session(Config::default(), input.as_bytes().to_vec()).await,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"Malformed\"}\n"
+        );
+
+        let mut config = Config::default();
+        config
+            .capabilities
+            .enable("lenient-trailing")
+            .expect("lenient-trailing is a prime extension");
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7} trailing junk\n",
+            "{\"method\":\"isPrime\",\"number\":8}}}\n",
+            "junk {\"method\":\"isPrime\",\"number\":7}\n",
+        );
+        assert_eq!(
+            session(config, input.as_bytes().to_vec()).await,
+            concat!(
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"isPrime\",\"prime\":false}\n",
+                "{\"method\":\"Malformed\"}\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn datagrams_get_one_response_each() {
+        let pool = Pool::new(2, 8);
+        let config = Config::default();
+        let answer = |datagram: &'static str| answer_datagram(datagram.as_bytes(), &config, &pool);
+
+        assert_eq!(
+            answer(r#"{"method":"isPrime","number":7}"#).await,
+            "{\"method\":\"isPrime\",\"prime\":true}\n"
+        );
+        assert_eq!(
+            answer("{\"method\":\"isPrime\",\"number\":8}\n").await,
+            "{\"method\":\"isPrime\",\"prime\":false}\n"
+        );
+        for malformed in [
+            r#"{"method":"isPrime","number":"7"}"#,
+            r#"{"method":"factorize","number":12}"#,
+            "not json",
+            "",
+        ] {
+            assert_eq!(
+                answer(malformed).await,
+                "{\"method\":\"Malformed\"}\n",
+                "{}",
+                malformed
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_keep_request_order() {
+        let config = Config {
+            capabilities: Capabilities::lab(protocol::PRIME),
+            ..Config::default()
+        };
+
+        // The batch takes far longer than the single requests behind it, which
+        // the pool finishes first.
+        let big = r#"{"method":"isPrime","number":9007199254740881}"#;
+        let mut input = format!("[{}]\n", vec![big; 2000].join(","));
+        let mut expected = format!(
+            "[{}]\n",
+            vec![r#"{"method":"isPrime","prime":true}"#; 2000].join(",")
+        );
+        for n in 0..200 {
+            input += &format!("{{\"method\":\"isPrime\",\"number\":{}}}\n", n);
+            expected += &format!(
+                "{{\"method\":\"isPrime\",\"prime\":{}}}\n",
+                request::is_prime(Number::Int(n))
+            );
+        }
+        input += "{\"method\":\"factorize\",\"number\":6}\n{\"method\":\"isPrime\",\"number\":5}\n";
+        expected += concat!(
+            "{\"method\":\"factorize\",\"partial\":true,\"factors\":[2],\"checked\":2}\n",
+            "{\"method\":\"factorize\",\"number\":6,\"factors\":[2,3]}\n",
+            "{\"method\":\"isPrime\",\"prime\":true}\n",
+        );
+
+        assert_eq!(session(config, input.into_bytes()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn malformed_responses_follow_the_config() {
+        let config = Config {
+            malformed_response: r#"{"error":"bad request"}"#.to_string(),
+            echo_malformed: true,
+            disconnect_on_malformed: false,
+            max_line_len: 64,
+            ..Config::default()
+        };
+        let input = format!(
+            "{}\n{}\n{}\n{}\n{}\n",
+            r#"{"method":"isPrime","number":"7"}"#,
+            r#"{"method":"isPrime","number":7}"#,
+            r#"{"method":"isPrime","number":8}"#,
+            "x".repeat(100),
+            r#"{"method":"isPrime","number":7}"#,
+        );
+        assert_eq!(
+            session(config, input.into_bytes()).await,
+            concat!(
+                "{\"error\":\"bad request\",\"request\":\"{\\\"method\\\":\\\"isPrime\\\",\\\"number\\\":\\\"7\\\"}\"}\n",
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"isPrime\",\"prime\":false}\n",
+                "{\"error\":\"bad request\"}\n",
+            )
+        );
+
+        let config = Config {
+            malformed_response: "malformed".to_string(),
+            ..Config::default()
+        };
+        let input = "nope\n{\"method\":\"isPrime\",\"number\":7}\n";
+        assert_eq!(
+            session(config, input.as_bytes().to_vec()).await,
+            "malformed\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn next_prime_is_answered_only_when_enabled() {
+        let input = concat!(
+            "{\"method\":\"nextPrime\",\"number\":7}\n",
+            "{\"method\":\"nextPrime\",\"number\":-3.5}\n",
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+            "{\"method\":\"nextPrime\",\"number\":1e20}\n",
+        );
+        let config = Config {
+            capabilities: Capabilities::lab(protocol::PRIME),
+            ..Config::default()
+        };
+        assert_eq!(
+            session(config, input.as_bytes().to_vec()).await,
+            concat!(
+                "{\"method\":\"nextPrime\",\"next\":11}\n",
+                "{\"method\":\"nextPrime\",\"next\":2}\n",
+                "{\"method\":\"isPrime\",\"prime\":true}\n",
+                "{\"method\":\"Malformed\"}\n",
+            )
+        );
+
+        assert_eq!(
+            session(Config::default(), input.as_bytes().to_vec()).await,
+            "{\"method\":\"Malformed\"}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_count_their_requests_and_malformed_lines() {
+        let config = Config {
+            disconnect_on_malformed: false,
+            ..Config::default()
+        };
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+            "nonsense\n",
+            "{\"method\":\"isPrime\",\"number\":8}\n",
+        );
+        let (_, stats) = session_with_stats(config, input.as_bytes().to_vec()).await;
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.malformed, 1);
+
+        let (_, stats) = session_with_stats(Config::default(), input.as_bytes().to_vec()).await;
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.malformed, 1);
+    }
+
+    #[tokio::test]
+    async fn clients_that_hang_up_mid_response_are_let_go() {
+        let (mut client, server) = tokio::io::duplex(1 << 12);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let server = tokio::spawn(handle_client(
+            server,
+            "test".to_string(),
+            Config::default(),
+            clock,
+            Quotas::new(Quota::default()),
+            Pool::new(2, 8),
+        ));
+
+        // Dropping the client closes both directions at once, so every
+        // response the server still owes fails to write.
+        let input = "{\"method\":\"isPrime\",\"number\":7}\n".repeat(16);
+        client
+            .write_all(input.as_bytes())
+            .await
+            .expect("Couldn't send requests");
+        drop(client);
+
+        let stats = server.await.expect("Session panicked");
+        assert!(stats.requests >= 1);
+        assert_eq!(stats.malformed, 0);
+    }
+
+    #[tokio::test]
+    async fn connections_close_after_the_request_limit() {
+        let (client, server) = tokio::io::duplex(1 << 12);
+        let config = Config {
+            max_requests: Some(2),
+            ..Config::default()
+        };
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let server = tokio::spawn(handle_client(
+            server,
+            "test".to_string(),
+            config,
+            clock,
+            Quotas::new(Quota::default()),
+            Pool::new(2, 8),
+        ));
+
+        // The client keeps its side open; the server has to be the one to close.
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let input = concat!(
+            "{\"method\":\"isPrime\",\"number\":7}\n",
+            "{\"method\":\"isPrime\",\"number\":8}\n",
+            "{\"method\":\"isPrime\",\"number\":11}\n",
+        );
+        writer
+            .write_all(input.as_bytes())
+            .await
+            .expect("Couldn't send requests");
+
+        let mut output = String::new();
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_string(&mut output))
+            .await
+            .expect("Server didn't close the connection")
+            .expect("Couldn't read responses");
+        assert_eq!(
+            output,
+            "{\"method\":\"isPrime\",\"prime\":true}\n{\"method\":\"isPrime\",\"prime\":false}\n"
+        );
+
+        let stats = server.await.expect("Session panicked");
+        assert_eq!(stats.requests, 2);
+    }
 }
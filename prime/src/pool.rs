@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// A fixed set of worker threads for CPU-bound work, so connection tasks
+// only do I/O and at most `workers` computations run at once. The queue is
+// bounded; submitting to a full queue waits rather than letting a flood of
+// requests pile up in memory. Workers exit once every clone of the pool is
+// dropped.
+#[derive(Clone)]
+pub struct Pool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Pool {
+    pub fn new(workers: usize, queue: usize) -> Self {
+        let (jobs, queued) = mpsc::channel::<Job>(queue);
+        let queued = Arc::new(Mutex::new(queued));
+
+        for _ in 0..workers.max(1) {
+            let queued = queued.clone();
+            thread::spawn(move || {
+                loop {
+                    // The lock is only held while waiting for the next job.
+                    let job = queued
+                        .lock()
+                        .expect("Couldn't lock job queue")
+                        .blocking_recv();
+                    match job {
+                        Some(job) => job(),
+                        None => break,
+                    }
+                }
+            });
+        }
+
+        Pool { jobs }
+    }
+
+    // Queues `work` and returns where its result will arrive. The receiver
+    // reports an error if the job never ran.
+    pub async fn submit<T, F>(&self, work: F) -> oneshot::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = result.send(work());
+        });
+        let _ = self.jobs.send(job).await;
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc as std_mpsc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn a_slow_job_does_not_hold_up_the_others() {
+        let pool = Pool::new(2, 8);
+        let (release, blocked) = std_mpsc::channel::<()>();
+
+        let slow = pool.submit(move || blocked.recv().is_ok()).await;
+        let mut quick = Vec::new();
+        for i in 0..4 {
+            let result = pool.submit(move || i * 2).await;
+            quick.push(
+                timeout(Duration::from_secs(5), result)
+                    .await
+                    .expect("Quick job never ran")
+                    .expect("Quick job was dropped"),
+            );
+        }
+        assert_eq!(quick, vec![0, 2, 4, 6]);
+
+        release.send(()).expect("Slow job went away");
+        assert!(slow.await.expect("Slow job never finished"));
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+
+// Testing Miller–Rabin against the first twelve primes as witnesses is
+// deterministic for every n below 3.3 * 10^24, which covers all of u64.
+const WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+pub fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for p in WITNESSES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    // n - 1 = d * 2^s with d odd.
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+
+    WITNESSES.iter().all(|&a| passes(n, a, d, s))
+}
+
+// Whether n is a strong probable prime to base a.
+fn passes(n: u64, a: u64, d: u64, s: u32) -> bool {
+    let mut x = pow_mod(a, d, n);
+    if x == 1 || x == n - 1 {
+        return true;
+    }
+    for _ in 1..s {
+        x = mul_mod(x, x, n);
+        if x == n - 1 {
+            return true;
+        }
+    }
+    false
+}
+
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (a as u128 * b as u128 % m as u128) as u64
+}
+
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+// The smallest prime greater than n, if there's one that fits in a u64.
+pub fn next_prime(n: u64) -> Option<u64> {
+    (n.checked_add(1)?..=u64::MAX).find(|&candidate| is_prime(candidate))
+}
+
+// Which numbers up to `limit` are prime, worked out once at startup so most
+// checks are a lookup. One bit per odd number, set when it's composite;
+// anything above the limit falls back to Miller–Rabin.
+pub struct Sieve {
+    limit: u64,
+    composite: Vec<u64>,
+}
+
+// The table itself is megabytes of bits, so only the limit is shown.
+impl fmt::Debug for Sieve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sieve")
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sieve {
+    pub fn new(limit: u64) -> Self {
+        // Bit i stands for 2i + 1.
+        let odds = limit.div_ceil(2) as usize;
+        let mut sieve = Sieve {
+            limit,
+            composite: vec![0; odds.div_ceil(64)],
+        };
+        if odds > 0 {
+            sieve.mark(1);
+        }
+
+        let mut p = 3;
+        while p * p <= limit {
+            if !sieve.is_marked(p) {
+                for multiple in (p * p..=limit).step_by(2 * p as usize) {
+                    sieve.mark(multiple);
+                }
+            }
+            p += 2;
+        }
+        sieve
+    }
+
+    pub fn is_prime(&self, n: u64) -> bool {
+        if n > self.limit {
+            return is_prime(n);
+        }
+        if n.is_multiple_of(2) {
+            return n == 2;
+        }
+        !self.is_marked(n)
+    }
+
+    fn mark(&mut self, odd: u64) {
+        let i = (odd / 2) as usize;
+        self.composite[i / 64] |= 1 << (i % 64);
+    }
+
+    fn is_marked(&self, odd: u64) -> bool {
+        let i = (odd / 2) as usize;
+        self.composite[i / 64] & (1 << (i % 64)) != 0
+    }
+}
+
+// Trial division by odd numbers up to sqrt(n), as the server used to do.
+// Kept as the reference is_prime is checked and benchmarked against.
+pub fn trial_division(n: u64) -> bool {
+    match n {
+        0 | 1 => false,
+        2 => true,
+        _ if n.is_multiple_of(2) => false,
+        _ => (3..=n.isqrt()).step_by(2).all(|i| !n.is_multiple_of(i)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agrees_with_trial_division_on_small_numbers() {
+        for n in 0..200_000 {
+            assert_eq!(is_prime(n), trial_division(n), "{}", n);
+        }
+    }
+
+    #[test]
+    fn sieve_agrees_with_miller_rabin_either_side_of_its_limit() {
+        for limit in [0, 1, 2, 3, 64, 127, 128, 10_000] {
+            let sieve = Sieve::new(limit);
+            for n in 0..limit + 1_000 {
+                assert_eq!(sieve.is_prime(n), is_prime(n), "{} below {}", n, limit);
+            }
+        }
+    }
+
+    #[test]
+    fn large_primes_and_strong_pseudoprimes() {
+        let primes = [
+            1_000_000_007,
+            (1 << 31) - 1,
+            (1 << 61) - 1,
+            1_125_899_906_842_597,
+            9_223_372_036_854_775_783,
+            18_446_744_073_709_551_557,
+        ];
+        // Each fools Miller–Rabin for some of the smaller witness sets.
+        let composites = [
+            2_047,
+            3_215_031_751,
+            3_825_123_056_546_413_051,
+            1_000_000_007 * 998_244_353,
+            u64::MAX,
+        ];
+
+        for n in primes {
+            assert!(is_prime(n), "{} is prime", n);
+        }
+        for n in composites {
+            assert!(!is_prime(n), "{} is composite", n);
+        }
+    }
+
+    #[test]
+    fn next_prime_is_strictly_greater() {
+        assert_eq!(next_prime(0), Some(2));
+        assert_eq!(next_prime(2), Some(3));
+        assert_eq!(next_prime(7), Some(11));
+        assert_eq!(next_prime(1_000_000_000), Some(1_000_000_007));
+        assert_eq!(
+            next_prime(18_446_744_073_709_551_556),
+            Some(18_446_744_073_709_551_557)
+        );
+        assert_eq!(next_prime(18_446_744_073_709_551_557), None);
+    }
+}
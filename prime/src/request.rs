@@ -0,0 +1,456 @@
+use crate::primality::{self, Sieve};
+use serde::de::{self, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+// Method names are matched exactly. factorize and nextPrime parse everywhere
+// but are only answered when their extensions are enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Method {
+    #[serde(rename = "isPrime")]
+    IsPrime,
+    #[serde(rename = "factorize")]
+    Factorize,
+    #[serde(rename = "nextPrime")]
+    NextPrime,
+}
+
+// A request must be an object with exactly one `method` and one `number`,
+// where `number` is a JSON number of any shape (integer, float, exponent) and
+// never a string, bool or null. Other fields are ignored.
+#[derive(Debug, PartialEq)]
+pub struct PrimeRequest {
+    pub method: Method,
+    pub number: Number,
+}
+
+// A request's number. Integers that fit in a u64 are kept exact, since an
+// f64 would round anything past 2^53; negative integers can't be prime or
+// factorized. Fractions, exponents and integers too large for a u64 are f64s.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Number {
+    Int(u64),
+    Neg,
+    Float(f64),
+}
+
+impl Number {
+    // The number as a u64, if it's a non-negative integer that fits in one.
+    // u64::MAX rounds up to 2^64 as an f64, so a float that large doesn't.
+    pub fn whole(self) -> Option<u64> {
+        match self {
+            Number::Int(n) => Some(n),
+            Number::Neg => None,
+            Number::Float(n) => {
+                (n >= 0.0 && n.fract() == 0.0 && n < u64::MAX as f64).then_some(n as u64)
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Number {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+struct NumberVisitor;
+
+// serde_json hands integers to visit_u64 or visit_i64 when they fit, and
+// everything else numeric to visit_f64. Strings, bools and nulls fall
+// through to the default visitors, which reject them.
+impl<'de> Visitor<'de> for NumberVisitor {
+    type Value = Number;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a number")
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<Number, E> {
+        Ok(Number::Int(n))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<Number, E> {
+        Ok(u64::try_from(n).map_or(Number::Neg, Number::Int))
+    }
+
+    fn visit_f64<E: de::Error>(self, n: f64) -> Result<Number, E> {
+        Ok(Number::Float(n))
+    }
+}
+
+impl<'de> Deserialize<'de> for PrimeRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(PrimeRequestVisitor)
+    }
+}
+
+struct PrimeRequestVisitor;
+
+impl<'de> Visitor<'de> for PrimeRequestVisitor {
+    type Value = PrimeRequest;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object with a method and a number")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PrimeRequest, A::Error> {
+        let mut method = None;
+        let mut number = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "method" if method.is_some() => return Err(de::Error::duplicate_field("method")),
+                "number" if number.is_some() => return Err(de::Error::duplicate_field("number")),
+                "method" => method = Some(map.next_value()?),
+                "number" => number = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(PrimeRequest {
+            method: method.ok_or_else(|| de::Error::missing_field("method"))?,
+            number: number.ok_or_else(|| de::Error::missing_field("number"))?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrimeResponse {
+    pub method: String,
+    pub prime: bool,
+}
+
+impl PrimeResponse {
+    pub fn new(prime: bool) -> Self {
+        PrimeResponse {
+            method: "isPrime".to_string(),
+            prime,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct NextPrimeResponse {
+    pub method: String,
+    pub next: u64,
+}
+
+impl NextPrimeResponse {
+    pub fn new(next: u64) -> Self {
+        NextPrimeResponse {
+            method: "nextPrime".to_string(),
+            next,
+        }
+    }
+}
+
+// Lab-mode reply for a request that ran past its deadline. `prime` is only
+// set for isPrime so the response still parses as a (negative) answer.
+#[derive(Debug, Serialize)]
+pub struct TimeoutResponse {
+    pub method: Method,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prime: Option<bool>,
+    pub timeout: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MalformedResponse {
+    pub method: String,
+}
+
+impl MalformedResponse {
+    pub fn new() -> Self {
+        MalformedResponse {
+            method: "Malformed".to_string(),
+        }
+    }
+}
+
+impl Default for MalformedResponse {
+    fn default() -> Self {
+        MalformedResponse::new()
+    }
+}
+
+// Streamed while a factorization is in progress; `checked` is the largest
+// divisor tried so far.
+#[derive(Debug, Serialize)]
+pub struct FactorizePartial<'a> {
+    pub method: &'static str,
+    pub partial: bool,
+    pub factors: &'a [u64],
+    pub checked: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FactorizeResponse<'a> {
+    pub method: &'static str,
+    pub number: u64,
+    pub factors: &'a [u64],
+}
+
+// The largest prime a u64 holds.
+const LARGEST_PRIME: u64 = 18446744073709551557;
+
+// Negative and fractional numbers are never prime. Floats too large for a
+// u64 are all even, so they aren't either.
+pub fn is_prime(n: Number) -> bool {
+    n.whole().is_some_and(primality::is_prime)
+}
+
+// is_prime, looking n up in the sieve when it's small enough.
+pub fn is_prime_sieved(n: Number, sieve: &Sieve) -> bool {
+    n.whole().is_some_and(|n| sieve.is_prime(n))
+}
+
+// Whether next_prime has an answer for n, cheaply enough to check before
+// handing the search to the pool.
+pub fn has_next_prime(n: Number) -> bool {
+    match n {
+        Number::Int(n) => n < LARGEST_PRIME,
+        Number::Neg => true,
+        Number::Float(n) => n < u64::MAX as f64,
+    }
+}
+
+// The smallest prime greater than n, which needn't be an integer. None once
+// n is too large for the answer to fit in a u64.
+pub fn next_prime(n: Number) -> Option<u64> {
+    match n {
+        Number::Int(n) => primality::next_prime(n),
+        Number::Neg => Some(2),
+        Number::Float(n) if n < 2.0 => Some(2),
+        Number::Float(n) if n >= u64::MAX as f64 => None,
+        Number::Float(n) => primality::next_prime(n as u64),
+    }
+}
+
+// Parses the JSON value a request line holds. Strictly, nothing but
+// whitespace may follow it; leniently, the first value is taken and whatever
+// comes after ignored.
+pub fn parse<'a, T: Deserialize<'a>>(line: &'a str, lenient: bool) -> std::io::Result<T> {
+    if !lenient {
+        return serde_json::from_str(line).map_err(malformed);
+    }
+
+    serde_json::Deserializer::from_str(line)
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Empty request line"))?
+        .map_err(malformed)
+}
+
+// Whatever doesn't parse is InvalidData, including a line that stops
+// mid-value, which serde_json would report as UnexpectedEof.
+pub fn malformed(e: serde_json::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn well_formed_requests_parse() {
+        let cases = [
+            (
+                r#"{"method":"isPrime","number":7}"#,
+                Method::IsPrime,
+                Number::Int(7),
+            ),
+            (
+                r#"{"number":7,"method":"isPrime"}"#,
+                Method::IsPrime,
+                Number::Int(7),
+            ),
+            (
+                r#"{"method":"isPrime","number":-3}"#,
+                Method::IsPrime,
+                Number::Neg,
+            ),
+            (
+                r#"{"method":"isPrime","number":7.5}"#,
+                Method::IsPrime,
+                Number::Float(7.5),
+            ),
+            (
+                r#"{"method":"isPrime","number":1e3}"#,
+                Method::IsPrime,
+                Number::Float(1000.0),
+            ),
+            (
+                r#"{"method":"isPrime","number":18446744073709551557}"#,
+                Method::IsPrime,
+                Number::Int(18446744073709551557),
+            ),
+            (
+                r#"{"method":"isPrime","number":123456789012345678901234567890}"#,
+                Method::IsPrime,
+                Number::Float(1.2345678901234568e29),
+            ),
+            (
+                r#"{"method":"isPrime","extra":{"number":"x"},"number":7,"more":null}"#,
+                Method::IsPrime,
+                Number::Int(7),
+            ),
+            (
+                r#"{"method":"factorize","number":12}"#,
+                Method::Factorize,
+                Number::Int(12),
+            ),
+            (
+                r#"{"method":"nextPrime","number":12}"#,
+                Method::NextPrime,
+                Number::Int(12),
+            ),
+        ];
+
+        for (line, method, number) in cases {
+            let parsed: PrimeRequest = serde_json::from_str(line)
+                .unwrap_or_else(|e| panic!("{} should parse: {}", line, e));
+            assert_eq!(parsed, PrimeRequest { method, number }, "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let cases = [
+            "",
+            "{",
+            "not json",
+            "[]",
+            r#""isPrime""#,
+            "7",
+            "null",
+            "{}",
+            r#"{"method":"isPrime"}"#,
+            r#"{"number":7}"#,
+            r#"{"method":"isPrime","number":"7"}"#,
+            r#"{"method":"isPrime","number":true}"#,
+            r#"{"method":"isPrime","number":null}"#,
+            r#"{"method":"isPrime","number":[7]}"#,
+            r#"{"method":"isPrime","number":{"value":7}}"#,
+            r#"{"method":"isprime","number":7}"#,
+            r#"{"method":"IsPrime","number":7}"#,
+            r#"{"method":"isPrime ","number":7}"#,
+            r#"{"method":"nextprime","number":7}"#,
+            r#"{"method":7,"number":7}"#,
+            r#"{"method":null,"number":7}"#,
+            r#"{"method":"isPrime","number":7,"number":8}"#,
+            r#"{"method":"isPrime","method":"isPrime","number":7}"#,
+            r#"{"method":"isPrime","number":"#,
+        ];
+
+        for line in cases {
+            let kind = parse::<PrimeRequest>(line, false).map_err(|e| e.kind());
+            assert_eq!(
+                kind,
+                Err(ErrorKind::InvalidData),
+                "{} should be malformed",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn responses_serialize_to_the_wire_format() {
+        let line = |value: serde_json::Result<String>| value.expect("Couldn't serialize");
+
+        assert_eq!(
+            line(serde_json::to_string(&PrimeResponse::new(true))),
+            r#"{"method":"isPrime","prime":true}"#
+        );
+        assert_eq!(
+            line(serde_json::to_string(&MalformedResponse::new())),
+            r#"{"method":"Malformed"}"#
+        );
+        assert_eq!(
+            line(serde_json::to_string(&TimeoutResponse {
+                method: Method::Factorize,
+                prime: None,
+                timeout: true,
+            })),
+            r#"{"method":"factorize","timeout":true}"#
+        );
+        assert_eq!(
+            line(serde_json::to_string(&FactorizeResponse {
+                method: "factorize",
+                number: 12,
+                factors: &[2, 2, 3],
+            })),
+            r#"{"method":"factorize","number":12,"factors":[2,2,3]}"#
+        );
+    }
+
+    #[test]
+    fn only_non_negative_integers_can_be_prime() {
+        assert!(is_prime(Number::Int(7)));
+        assert!(is_prime(Number::Float(7.0)));
+        assert!(!is_prime(Number::Float(7.5)));
+        assert!(!is_prime(Number::Neg));
+        assert!(!is_prime(Number::Float(-7.0)));
+        assert!(!is_prime(Number::Float(1e308)));
+    }
+
+    #[test]
+    fn integers_past_2_53_are_checked_exactly() {
+        assert!(is_prime(Number::Int(LARGEST_PRIME)));
+        assert!(!is_prime(Number::Int(9007199254740993)));
+        assert!(!is_prime(Number::Float(18446744073709551557.0)));
+    }
+
+    #[test]
+    fn sieved_checks_match_unsieved_ones() {
+        let sieve = Sieve::new(1_000);
+        for n in [
+            Number::Neg,
+            Number::Int(0),
+            Number::Int(1),
+            Number::Int(2),
+            Number::Int(997),
+            Number::Int(1_009),
+            Number::Int(LARGEST_PRIME),
+            Number::Float(7.0),
+            Number::Float(7.5),
+            Number::Float(1e20),
+            Number::Float(f64::MAX),
+        ] {
+            assert_eq!(is_prime_sieved(n, &sieve), is_prime(n), "{:?}", n);
+        }
+    }
+
+    #[test]
+    fn next_prime_accepts_any_number_below_the_largest_prime() {
+        assert_eq!(next_prime(Number::Neg), Some(2));
+        assert_eq!(next_prime(Number::Float(-5.0)), Some(2));
+        assert_eq!(next_prime(Number::Int(2)), Some(3));
+        assert_eq!(next_prime(Number::Float(7.5)), Some(11));
+        assert_eq!(
+            next_prime(Number::Int(LARGEST_PRIME - 1)),
+            Some(LARGEST_PRIME)
+        );
+        assert!(has_next_prime(Number::Int(LARGEST_PRIME - 1)));
+        assert!(!has_next_prime(Number::Int(LARGEST_PRIME)));
+        assert!(!has_next_prime(Number::Float(1e308)));
+        assert_eq!(next_prime(Number::Float(1e308)), None);
+    }
+
+    #[test]
+    fn lenient_parsing_ignores_what_follows_the_value() {
+        let line = r#"{"method":"isPrime","number":7} junk"#;
+        assert!(parse::<PrimeRequest>(line, false).is_err());
+        assert_eq!(
+            parse::<PrimeRequest>(line, true).expect("Leniently, the request should parse"),
+            PrimeRequest {
+                method: Method::IsPrime,
+                number: Number::Int(7),
+            }
+        );
+        assert!(parse::<PrimeRequest>("", true).is_err());
+    }
+}
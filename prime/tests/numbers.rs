@@ -0,0 +1,116 @@
+// Drives a real prime server with the numbers most likely to trip up a
+// change to parsing or the primality engine, and checks the exact response
+// line for each. Anything that isn't a non-negative integer is never prime.
+// Integers are checked exactly all the way up to u64::MAX, while ones written
+// as floats, like `3.0`, are only exact below 2^53.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CASES: &[(&str, bool)] = &[
+    ("-1", false),
+    ("-7", false),
+    ("-0", false),
+    ("0", false),
+    ("1", false),
+    ("2", true),
+    ("3", true),
+    ("4", false),
+    ("3.0", true),
+    ("3.0000000001", false),
+    ("2.5", false),
+    ("1e-5", false),
+    ("7e0", true),
+    ("1.7e1", true),
+    ("1e3", false),
+    ("1E308", false),
+    ("1e308", false),
+    ("-1e308", false),
+    ("123456789012345678901234567890", false),
+    ("2147483647", true),
+    ("2147483649", false),
+    ("4294967291", true),
+    ("4294967297", false),
+    ("1000000007", true),
+    // The largest prime below 2^53, the last one an f64 holds exactly.
+    ("9007199254740881", true),
+    ("9007199254740881.0", true),
+    // The smallest prime above 2^53, which an f64 can't hold.
+    ("9007199254740997", true),
+    ("9007199254740997.0", false),
+    // The largest prime below 2^63, and 2^63 + 1.
+    ("9223372036854775783", true),
+    ("9223372036854775809", false),
+    // The largest prime below 2^64, and u64::MAX.
+    ("18446744073709551557", true),
+    ("18446744073709551615", false),
+    ("18446744073709551616", false),
+];
+
+// A prime server on a free local port, killed when dropped.
+struct Server {
+    child: Child,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Self {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .expect("Couldn't find a free port")
+            .local_addr()
+            .expect("Couldn't read local address")
+            .to_string();
+        let child = Command::new(env!("CARGO_BIN_EXE_prime"))
+            .args(["--bind", &addr])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Couldn't start prime");
+
+        let started = Instant::now();
+        while TcpStream::connect(&addr).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "prime never started listening");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        Server { child, addr }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn numeric_edge_cases_get_exact_responses() {
+    let server = Server::start();
+    let mut stream = TcpStream::connect(&server.addr).expect("Couldn't connect to prime");
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .expect("Couldn't set read timeout");
+    let mut reader = BufReader::new(stream.try_clone().expect("Couldn't clone stream"));
+
+    for (number, prime) in CASES {
+        writeln!(stream, r#"{{"method":"isPrime","number":{}}}"#, number)
+            .expect("Couldn't send request");
+
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .unwrap_or_else(|e| panic!("No response for {}: {}", number, e));
+        assert_eq!(
+            line,
+            format!("{{\"method\":\"isPrime\",\"prime\":{}}}\n", prime),
+            "Wrong response for {}",
+            number
+        );
+    }
+}
@@ -0,0 +1,61 @@
+// Checks the primality engine against the primal crate's (primal-check's)
+// Miller–Rabin over random inputs from the whole u64 range and from the
+// ranges the checker tends to send.
+
+use prime::primality;
+use prime::request::{self, Number};
+use proptest::prelude::*;
+
+proptest! {
+    // There's no lib.rs beside this file to keep regressions next to.
+    #![proptest_config(ProptestConfig {
+        cases: 10_000,
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn agrees_with_primal_over_all_of_u64(n in any::<u64>()) {
+        prop_assert_eq!(primality::is_prime(n), primal_check::miller_rabin(n));
+    }
+
+    #[test]
+    fn agrees_with_primal_below_a_million(n in 0u64..1_000_000) {
+        prop_assert_eq!(primality::is_prime(n), primal_check::miller_rabin(n));
+    }
+
+    // Odd numbers near 2^63, where trial division used to be slowest.
+    #[test]
+    fn agrees_with_primal_near_2_63(offset in 0u64..1 << 32) {
+        let n = (1 << 63) - (offset | 1);
+        prop_assert_eq!(primality::is_prime(n), primal_check::miller_rabin(n));
+    }
+
+    // Products of two odd numbers are never prime, however large.
+    #[test]
+    fn products_are_composite(a in 3u64..u32::MAX as u64, b in 3u64..u32::MAX as u64) {
+        prop_assert!(!primality::is_prime((a | 1) * (b | 1)));
+    }
+
+    // Integers in requests are kept exact all the way up to u64::MAX.
+    #[test]
+    fn requested_integers_are_checked_exactly(n in any::<u64>()) {
+        prop_assert_eq!(request::is_prime(Number::Int(n)), primal_check::miller_rabin(n));
+    }
+
+    // Written as floats, like `7.0`, they're exact below 2^53.
+    #[test]
+    fn exact_floats_are_checked_as_integers(n in 0u64..1 << 53) {
+        prop_assert_eq!(request::is_prime(Number::Float(n as f64)), primal_check::miller_rabin(n));
+    }
+
+    #[test]
+    fn negatives_are_never_prime(n in -1e18f64..0.0) {
+        prop_assert!(!request::is_prime(Number::Float(n)));
+    }
+
+    #[test]
+    fn fractions_are_never_prime(whole in 0u64..1 << 40, fraction in 0.001f64..0.999) {
+        prop_assert!(!request::is_prime(Number::Float(whole as f64 + fraction)));
+    }
+}
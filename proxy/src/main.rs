@@ -1,14 +1,321 @@
+use common::quota::Quotas;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const LOCAL_ADDR: &str = "0.0.0.0:8080";
 const UPSTREAM_ADDR: &str = "206.189.113.124:16963";
 const TONYS_ACCOUNT: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+const REUSE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+// How many event lines can wait for the sink before new ones are dropped.
+const EVENT_QUEUE: usize = 1024;
+// What budgetchat opens every connection with. With --reuse-upstream the
+// proxy says it itself, since which upstream a client gets isn't known until
+// it has given its name.
+const GREETING: &str = "Welcome to budgetchat! What shall I call you?\n";
 
-fn intercept_message(message: &str) -> String {
+#[derive(Debug, Clone)]
+enum EventTarget {
+    File(PathBuf),
+    Socket(PathBuf),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Config {
+    upstream: String,
+    reuse_upstream: bool,
+    events: Option<EventTarget>,
+}
+
+impl Config {
+    fn from_args() -> Result<Self, String> {
+        let mut config = Config {
+            upstream: UPSTREAM_ADDR.to_string(),
+            ..Config::default()
+        };
+        let mut args = std::env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Expected a value after {}", arg))
+            };
+
+            match arg.as_str() {
+                "--upstream" => config.upstream = value()?,
+                // Lab-only: see Pool for who can pick up a parked session.
+                "--reuse-upstream" => config.reuse_upstream = true,
+                "--events-file" => config.events = Some(EventTarget::File(value()?.into())),
+                "--events-socket" => config.events = Some(EventTarget::Socket(value()?.into())),
+                other => return Err(format!("Unknown argument '{}'", other)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+// One JSON line per rewritten address. `session` identifies the upstream
+// connection, so it stays the same when --reuse-upstream hands it to a
+// reconnecting client.
+#[derive(Debug, Serialize)]
+struct RewriteEvent<'a> {
+    session: u64,
+    direction: Direction,
+    original: &'a str,
+    replacement: &'a str,
+}
+
+// Lines are written to the sink by a thread of its own, so a slow or stalled
+// consumer never holds up forwarding. Once it falls EVENT_QUEUE lines behind,
+// new events are dropped.
+struct Events {
+    lines: SyncSender<Vec<u8>>,
+}
+
+impl Events {
+    fn open(target: &EventTarget) -> std::io::Result<Self> {
+        let sink: Box<dyn Write + Send> = match target {
+            EventTarget::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            EventTarget::Socket(path) => Box::new(UnixStream::connect(path)?),
+        };
+
+        Ok(Events::new(sink))
+    }
+
+    fn new(mut sink: Box<dyn Write + Send>) -> Self {
+        let (lines, queued) = mpsc::sync_channel::<Vec<u8>>(EVENT_QUEUE);
+
+        thread::spawn(move || {
+            for line in queued {
+                if let Err(e) = sink.write_all(&line).and_then(|_| sink.flush()) {
+                    eprintln!("Couldn't write rewrite event: {}", e);
+                }
+            }
+        });
+
+        Events { lines }
+    }
+
+    fn emit(&self, event: &RewriteEvent) {
+        let mut line = serde_json::to_vec(event).expect("Couldn't serialize event to JSON");
+        line.push(b'\n');
+
+        if let Err(TrySendError::Full(_)) = self.lines.try_send(line) {
+            eprintln!("Dropping rewrite event: the event sink is falling behind");
+        }
+    }
+}
+
+fn rewrite(
+    message: &str,
+    session: u64,
+    direction: Direction,
+    events: &Option<Arc<Events>>,
+) -> String {
+    let (rewritten, originals) = intercept_message(message);
+
+    if let Some(events) = events {
+        for original in originals {
+            events.emit(&RewriteEvent {
+                session,
+                direction,
+                original,
+                replacement: TONYS_ACCOUNT,
+            });
+        }
+    }
+
+    rewritten
+}
+
+// Who's in the room as this upstream's user last saw it, kept up to date
+// from the server's presence notices so a client that picks up a parked
+// upstream can be told, the way a fresh join would be.
+#[derive(Debug, Default)]
+struct Room {
+    members: Vec<String>,
+}
+
+impl Room {
+    fn observe(&mut self, line: &str) {
+        let line = line.trim_end();
+
+        if let Some(names) = line.strip_prefix("* The room contains:") {
+            self.members = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect();
+        } else if let Some(name) = line
+            .strip_prefix("* ")
+            .and_then(|line| line.strip_suffix(" has entered the room"))
+        {
+            self.members.push(name.to_string());
+        } else if let Some(name) = line
+            .strip_prefix("* ")
+            .and_then(|line| line.strip_suffix(" has left the room"))
+        {
+            self.members.retain(|member| member != name);
+        }
+    }
+
+    fn presence(&self) -> String {
+        format!("* The room contains: {}\n", self.members.join(", "))
+    }
+}
+
+// One upstream chat connection. A dedicated thread forwards everything the
+// server says to whichever client is currently attached; while no client is
+// attached (parked in the pool) server lines are dropped.
+struct Upstream {
+    session: u64,
+    writer: TcpStream,
+    client: Arc<Mutex<Option<TcpStream>>>,
+    room: Arc<Mutex<Room>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Upstream {
+    // With `skip_greeting` the server's welcome line is dropped, for a
+    // client the proxy has already greeted itself.
+    fn connect(
+        addr: &str,
+        session: u64,
+        client_writer: TcpStream,
+        events: Option<Arc<Events>>,
+        skip_greeting: bool,
+    ) -> std::io::Result<Self> {
+        let server_stream = TcpStream::connect(addr)?;
+        let mut server_reader = BufReader::new(server_stream.try_clone()?);
+
+        let client = Arc::new(Mutex::new(Some(client_writer)));
+        let room = Arc::new(Mutex::new(Room::default()));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader_client = client.clone();
+        let reader_room = room.clone();
+        let reader_closed = closed.clone();
+        thread::spawn(move || {
+            let mut buf = String::new();
+            let mut skip = skip_greeting;
+            loop {
+                buf.clear();
+                match server_reader.read_line(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) if std::mem::take(&mut skip) => {}
+                    Ok(_) => {
+                        println!("[server] {}", &buf);
+                        reader_room
+                            .lock()
+                            .expect("Couldn't obtain lock on room")
+                            .observe(&buf);
+                        let new_msg = rewrite(&buf, session, Direction::ServerToClient, &events);
+                        if let Some(client_writer) = reader_client
+                            .lock()
+                            .expect("Couldn't obtain lock on client")
+                            .as_mut()
+                        {
+                            let _ = client_writer.write_all(new_msg.as_bytes());
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            reader_closed.store(true, Ordering::SeqCst);
+            if let Some(client_writer) = reader_client
+                .lock()
+                .expect("Couldn't obtain lock on client")
+                .take()
+            {
+                let _ = client_writer.shutdown(Shutdown::Both);
+            }
+        });
+
+        Ok(Upstream {
+            session,
+            writer: server_stream,
+            client,
+            room,
+            closed,
+        })
+    }
+
+    fn attach(&self, client_writer: Option<TcpStream>) -> Option<TcpStream> {
+        std::mem::replace(
+            &mut *self.client.lock().expect("Couldn't obtain lock on client"),
+            client_writer,
+        )
+    }
+
+    // Hands a parked upstream to a reconnecting client, telling it who's in
+    // the room in place of the server's join response it won't get.
+    fn reattach(&self, mut client_writer: TcpStream) -> std::io::Result<()> {
+        let mut client = self.client.lock().expect("Couldn't obtain lock on client");
+        let presence = self
+            .room
+            .lock()
+            .expect("Couldn't obtain lock on room")
+            .presence();
+        client_writer.write_all(presence.as_bytes())?;
+        *client = Some(client_writer);
+        Ok(())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        let _ = self.writer.shutdown(Shutdown::Both);
+    }
+}
+
+// Upstream connections whose client went away, kept open for
+// REUSE_GRACE_PERIOD so a reconnect under the same name picks up where it left
+// off instead of leaving and re-joining the room. They're keyed by the
+// client's address as well as its name, so another host can't take over a
+// session just by typing the same name. Anyone behind the same address still
+// can, so --reuse-upstream is only meant for lab setups.
+type Pool = Arc<Mutex<HashMap<(IpAddr, String), (Instant, Upstream)>>>;
+
+fn expire_pool(pool: &Pool) {
+    pool.lock()
+        .expect("Couldn't obtain lock on pool")
+        .retain(|_, (parked_at, upstream)| {
+            let keep = parked_at.elapsed() < REUSE_GRACE_PERIOD && !upstream.is_closed();
+            if !keep {
+                upstream.close();
+            }
+            keep
+        });
+}
+
+// Returns the rewritten message along with every address it replaced.
+fn intercept_message(message: &str) -> (String, Vec<&str>) {
     let has_newline = message.ends_with('\n');
     let trimmed = message.trim_end();
+    let mut replaced = Vec::new();
 
     let words: Vec<&str> = trimmed
         .split(' ')
@@ -18,6 +325,7 @@ fn intercept_message(message: &str) -> String {
                 && word.len() <= 35
                 && word.chars().all(char::is_alphanumeric)
             {
+                replaced.push(word);
                 TONYS_ACCOUNT
             } else {
                 word
@@ -29,36 +337,55 @@ fn intercept_message(message: &str) -> String {
     if has_newline {
         result.push('\n');
     }
-    result
+    (result, replaced)
 }
 
-fn handle_client(client_stream: TcpStream) {
-    let server_stream = TcpStream::connect(UPSTREAM_ADDR).expect("Couldn't connect to upstream");
-
-    let mut server_reader = BufReader::new(server_stream.try_clone().unwrap());
-    let mut client_writer = client_stream.try_clone().unwrap();
-
-    thread::spawn(move || {
-        let mut buf = String::new();
-        loop {
-            buf.clear();
-            match server_reader.read_line(&mut buf) {
-                Ok(0) => break,
-                Ok(_) => {
-                    println!("[server] {}", &buf);
-                    let new_msg = intercept_message(&buf);
-                    client_writer.write_all(new_msg.as_bytes()).unwrap();
-                    buf.clear();
-                }
-                Err(e) => break,
-            }
+// Without a pool the upstream is connected straight away and the client talks
+// to it from the start. With one, the proxy greets the client itself and
+// waits for its name: a parked upstream for that name is picked up where it
+// left off, and only otherwise is a new one connected.
+fn handle_client(
+    client_stream: TcpStream,
+    session: u64,
+    config: Arc<Config>,
+    pool: Option<Pool>,
+    events: Option<Arc<Events>>,
+    quotas: Arc<Quotas>,
+) {
+    let _permit = match quotas.open_session() {
+        Ok(permit) => permit,
+        Err(e) => {
+            eprintln!("Rejecting client: {}", e);
+            return;
         }
+    };
 
-        let _ = client_writer.shutdown(Shutdown::Both);
-    });
+    let peer = match client_stream.peer_addr() {
+        Ok(peer) => peer.ip(),
+        Err(e) => {
+            eprintln!("Couldn't get client address: {}", e);
+            return;
+        }
+    };
+    let mut client_writer = client_stream.try_clone().unwrap();
+    let mut upstream = None;
+    if pool.is_none() {
+        upstream = Some(
+            Upstream::connect(
+                &config.upstream,
+                session,
+                client_writer,
+                events.clone(),
+                false,
+            )
+            .expect("Couldn't connect to upstream"),
+        );
+    } else if client_writer.write_all(GREETING.as_bytes()).is_err() {
+        return;
+    }
 
-    let mut client_reader = BufReader::new(client_stream);
-    let mut server_writer = server_stream;
+    let mut client_reader = BufReader::new(quotas.wrap(client_stream));
+    let mut identity: Option<(IpAddr, String)> = None;
 
     let mut buf = String::new();
     loop {
@@ -67,26 +394,418 @@ fn handle_client(client_stream: TcpStream) {
             Ok(0) => break,
             Ok(_) => {
                 println!("[client] {}", &buf);
-                let new_msg = intercept_message(&buf);
-                server_writer.write_all(new_msg.as_bytes()).unwrap();
-                buf.clear();
+
+                let upstream = match (&upstream, &pool) {
+                    (Some(upstream), _) => upstream,
+                    (None, Some(pool)) => {
+                        let name = buf.trim().to_string();
+                        let key = (peer, name.clone());
+                        let writer = client_reader.get_ref().get_ref().try_clone().unwrap();
+
+                        let parked = pool
+                            .lock()
+                            .expect("Couldn't obtain lock on pool")
+                            .remove(&key);
+                        identity = Some(key);
+                        if let Some((_, parked)) = parked
+                            && !parked.is_closed()
+                        {
+                            println!("[proxy] Reusing upstream for {}", name);
+                            let reattached = parked.reattach(writer);
+                            upstream = Some(parked);
+                            if reattached.is_err() {
+                                break;
+                            }
+                            continue;
+                        }
+
+                        match Upstream::connect(
+                            &config.upstream,
+                            session,
+                            writer,
+                            events.clone(),
+                            true,
+                        ) {
+                            Ok(connected) => upstream.insert(connected),
+                            Err(e) => {
+                                eprintln!("Couldn't connect to upstream: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    (None, None) => unreachable!("Upstream is connected up front without a pool"),
+                };
+
+                let new_msg = rewrite(&buf, upstream.session, Direction::ClientToServer, &events);
+                if (&upstream.writer).write_all(new_msg.as_bytes()).is_err() {
+                    break;
+                }
             }
-            Err(e) => break,
+            Err(_) => break,
         }
     }
 
-    let _ = server_writer.shutdown(Shutdown::Both);
+    let Some(upstream) = upstream else {
+        return;
+    };
+
+    if let Some(client_writer) = upstream.attach(None) {
+        let _ = client_writer.shutdown(Shutdown::Both);
+    }
+
+    match (pool, identity) {
+        (Some(pool), Some(key)) if !upstream.is_closed() => {
+            println!("[proxy] Parking upstream for {} from {}", key.1, key.0);
+            pool.lock()
+                .expect("Couldn't obtain lock on pool")
+                .insert(key, (Instant::now(), upstream));
+        }
+        _ => upstream.close(),
+    }
 }
 
 fn main() {
+    let config = Arc::new(Config::from_args().expect("Couldn't parse arguments"));
+    let quotas = Quotas::from_env().expect("Couldn't parse QUOTA");
     let listener = TcpListener::bind(LOCAL_ADDR).expect("Couldn't bind to local network");
 
+    let events = config
+        .events
+        .as_ref()
+        .map(|target| Events::open(target).map(Arc::new))
+        .transpose()
+        .expect("Couldn't open event sink");
+    let sessions = AtomicU64::new(0);
+
+    let pool: Option<Pool> = config
+        .reuse_upstream
+        .then(|| Arc::new(Mutex::new(HashMap::new())));
+
+    if let Some(pool) = pool.clone() {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                expire_pool(&pool);
+            }
+        });
+    }
+
     for client_stream in listener.incoming() {
         match client_stream {
             Ok(client_stream) => {
-                thread::spawn(move || handle_client(client_stream));
+                let session = sessions.fetch_add(1, Ordering::Relaxed);
+                let config = config.clone();
+                let pool = pool.clone();
+                let events = events.clone();
+                let quotas = quotas.clone();
+                thread::spawn(move || {
+                    handle_client(client_stream, session, config, pool, events, quotas)
+                });
+            }
+            Err(e) => eprintln!("Failed to accept client: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::quota::Quota;
+    use std::sync::mpsc::Receiver;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // A stand-in for the chat server that hands every connection it accepts
+    // to the test, already greeted.
+    fn fake_upstream() -> (String, Receiver<BufReader<TcpStream>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind fake upstream");
+        let addr = listener.local_addr().unwrap().to_string();
+        let (accepted, connections) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("Couldn't accept proxy connection");
+                stream.write_all(GREETING.as_bytes()).unwrap();
+                if accepted.send(BufReader::new(stream)).is_err() {
+                    return;
+                }
             }
-            Err(e) => {}
+        });
+
+        (addr, connections)
+    }
+
+    // Both ends of a local connection: (client, proxy).
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind proxy listener");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (client, stream)
+    }
+
+    fn start_client(config: &Arc<Config>, pool: &Pool) -> BufReader<TcpStream> {
+        let (client, stream) = connected_pair();
+
+        let config = config.clone();
+        let pool = Some(pool.clone());
+        let quotas = Quotas::new(Quota::default());
+        thread::spawn(move || handle_client(stream, 0, config, pool, None, quotas));
+
+        BufReader::new(client)
+    }
+
+    // The pool key for a test client, which always connects from localhost.
+    fn local(name: &str) -> (IpAddr, String) {
+        (IpAddr::from([127, 0, 0, 1]), name.to_string())
+    }
+
+    fn read_line<R: BufRead>(reader: &mut R) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("Couldn't read line");
+        line
+    }
+
+    fn wait_for(condition: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < TIMEOUT, "Timed out waiting");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn a_reconnecting_client_picks_up_its_parked_upstream() {
+        let (upstream_addr, connections) = fake_upstream();
+        let config = Arc::new(Config {
+            upstream: upstream_addr,
+            reuse_upstream: true,
+            events: None,
+        });
+        let pool: Pool = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut first = start_client(&config, &pool);
+        assert_eq!(read_line(&mut first), GREETING);
+        first.get_mut().write_all(b"bob\n").unwrap();
+
+        // Only now, with the name known, is the upstream connected. Its own
+        // greeting isn't passed on, since the proxy already sent one.
+        let mut server = connections.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(read_line(&mut server), "bob\n");
+        server
+            .get_mut()
+            .write_all(b"* The room contains: alice\n* carol has entered the room\n")
+            .unwrap();
+        assert_eq!(read_line(&mut first), "* The room contains: alice\n");
+        assert_eq!(read_line(&mut first), "* carol has entered the room\n");
+
+        first.get_ref().shutdown(Shutdown::Both).unwrap();
+        wait_for(|| pool.lock().unwrap().contains_key(&local("bob")));
+        server
+            .get_mut()
+            .write_all(b"* alice has left the room\n")
+            .unwrap();
+
+        // The second client gets the room as it stands now instead of a
+        // fresh join, over the same upstream connection.
+        let mut second = start_client(&config, &pool);
+        assert_eq!(read_line(&mut second), GREETING);
+        wait_for(|| {
+            let pool = pool.lock().unwrap();
+            pool[&local("bob")].1.room.lock().unwrap().members == ["carol"]
+        });
+        second.get_mut().write_all(b"bob\nhi\n").unwrap();
+        assert_eq!(read_line(&mut second), "* The room contains: carol\n");
+        assert_eq!(read_line(&mut server), "hi\n");
+        assert!(connections.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_new_name_gets_its_own_upstream() {
+        let (upstream_addr, connections) = fake_upstream();
+        let config = Arc::new(Config {
+            upstream: upstream_addr,
+            reuse_upstream: true,
+            events: None,
+        });
+        let pool: Pool = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut first = start_client(&config, &pool);
+        first.get_mut().write_all(b"bob\n").unwrap();
+        let _bob = connections.recv_timeout(TIMEOUT).unwrap();
+        first.get_ref().shutdown(Shutdown::Both).unwrap();
+        wait_for(|| pool.lock().unwrap().contains_key(&local("bob")));
+
+        let mut second = start_client(&config, &pool);
+        second.get_mut().write_all(b"carol\n").unwrap();
+        let mut carol = connections.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(read_line(&mut carol), "carol\n");
+        assert!(pool.lock().unwrap().contains_key(&local("bob")));
+    }
+
+    #[test]
+    fn a_name_parked_from_another_address_isnt_picked_up() {
+        let (upstream_addr, connections) = fake_upstream();
+        let config = Arc::new(Config {
+            upstream: upstream_addr.clone(),
+            reuse_upstream: true,
+            events: None,
+        });
+        let pool: Pool = Arc::new(Mutex::new(HashMap::new()));
+
+        let elsewhere = (IpAddr::from([192, 0, 2, 1]), "bob".to_string());
+        let (_client, client_writer) = connected_pair();
+        let parked = Upstream::connect(&upstream_addr, 0, client_writer, None, true)
+            .expect("Couldn't connect to fake upstream");
+        parked.attach(None);
+        pool.lock()
+            .unwrap()
+            .insert(elsewhere.clone(), (Instant::now(), parked));
+        let _parked_server = connections.recv_timeout(TIMEOUT).unwrap();
+
+        let mut client = start_client(&config, &pool);
+        assert_eq!(read_line(&mut client), GREETING);
+        client.get_mut().write_all(b"bob\n").unwrap();
+        let mut server = connections.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(read_line(&mut server), "bob\n");
+        assert!(pool.lock().unwrap().contains_key(&elsewhere));
+    }
+
+    #[test]
+    fn parked_upstreams_expire_after_the_grace_period_or_when_closed() {
+        let (upstream_addr, connections) = fake_upstream();
+        let pool: Pool = Arc::new(Mutex::new(HashMap::new()));
+        let park = |name: &str, parked_at: Instant| {
+            let (_client, client_writer) = connected_pair();
+            let upstream = Upstream::connect(&upstream_addr, 0, client_writer, None, true)
+                .expect("Couldn't connect to fake upstream");
+            upstream.attach(None);
+            pool.lock()
+                .unwrap()
+                .insert(local(name), (parked_at, upstream));
+        };
+
+        let expired = Instant::now()
+            .checked_sub(REUSE_GRACE_PERIOD)
+            .expect("Clock too close to its epoch");
+        park("fresh", Instant::now());
+        park("stale", expired);
+        park("hung-up", Instant::now());
+
+        let mut servers: Vec<_> = (0..3)
+            .map(|_| connections.recv_timeout(TIMEOUT).unwrap())
+            .collect();
+        let hung_up = servers.pop().unwrap();
+        hung_up.get_ref().shutdown(Shutdown::Both).unwrap();
+        wait_for(|| pool.lock().unwrap()[&local("hung-up")].1.is_closed());
+
+        expire_pool(&pool);
+        let pool = pool.lock().unwrap();
+        assert_eq!(pool.keys().collect::<Vec<_>>(), [&local("fresh")]);
+    }
+
+    #[test]
+    fn the_room_follows_presence_notices() {
+        let mut room = Room::default();
+        room.observe("* The room contains: alice, bob\n");
+        room.observe("* carol has entered the room\n");
+        room.observe("* alice has left the room\n");
+        room.observe("[bob] * dave has entered the room\n");
+        assert_eq!(room.presence(), "* The room contains: bob, carol\n");
+
+        room.observe("* The room contains: \n");
+        assert_eq!(room.presence(), "* The room contains: \n");
+    }
+
+    // Collects everything written to it, for checking what Events sent.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Never finishes a write, like a consumer that has stopped reading.
+    struct Stalled;
+
+    impl Write for Stalled {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            loop {
+                thread::park();
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_replaced_address_is_one_event() {
+        let captured = Captured::default();
+        let events = Some(Arc::new(Events::new(Box::new(captured.clone()))));
+
+        let message =
+            "Send to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX or 7LOrwbDlS8NujgjddyogWgIM93MV5N2VR\n";
+        let rewritten = rewrite(message, 7, Direction::ClientToServer, &events);
+        assert_eq!(
+            rewritten,
+            format!("Send to {} or {}\n", TONYS_ACCOUNT, TONYS_ACCOUNT)
+        );
+
+        wait_for(|| {
+            captured
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|&&b| b == b'\n')
+                .count()
+                == 2
+        });
+        let lines: Vec<serde_json::Value> = captured
+            .0
+            .lock()
+            .unwrap()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({
+                    "session": 7,
+                    "direction": "client_to_server",
+                    "original": "7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX",
+                    "replacement": TONYS_ACCOUNT,
+                }),
+                serde_json::json!({
+                    "session": 7,
+                    "direction": "client_to_server",
+                    "original": "7LOrwbDlS8NujgjddyogWgIM93MV5N2VR",
+                    "replacement": TONYS_ACCOUNT,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stalled_sink_doesnt_hold_up_forwarding() {
+        let events = Some(Arc::new(Events::new(Box::new(Stalled))));
+        let message = "7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX\n";
+
+        let started = Instant::now();
+        for _ in 0..EVENT_QUEUE * 2 {
+            rewrite(message, 0, Direction::ServerToClient, &events);
         }
+        assert!(started.elapsed() < TIMEOUT);
     }
 }